@@ -8,11 +8,11 @@ elseif exists("b:current_syntax")
 	finish
 endif
 
-syn keyword QuailKeyword fun def let match with in import as
+syn keyword QuailKeyword fun def let match with in import as inductive coinductive forall
 syn match QuailIdentifier "\<[A-Za-z_][A-Za-z0-9_\$]*\>"
 syn match QuailLiteral "\<[0-9][0-9]*\>"
-syn keyword QuailStdLib succ zero false true nil cons top pair left right println
-syn keyword QuailStdLibType Nat Bool Top Bot List Pair
+syn keyword QuailStdLib succ zero false true nil cons none some top pair left right println
+syn keyword QuailStdLibType Nat Bool Top Bot List Option Pair
 syn region QuailCommentLine start="#" end="$"
 syn region QuailHole start="{" end="}"
 syn match QuailOperator display "="
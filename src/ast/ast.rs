@@ -1,8 +1,11 @@
 use std::collections::HashSet;
 use std::rc;
 
+use num_bigint::BigUint;
+
 use crate::parser;
 use crate::tokenizer::Loc;
+use crate::tokenizer::Span;
 
 use std::convert::TryFrom;
 
@@ -10,16 +13,43 @@ use std::convert::TryFrom;
 pub struct Module {
     pub definitions: Vec<Def>,
     pub imports: Vec<Import>,
+    pub type_decls: Vec<TypeDecl>,
 }
 
 #[derive(Clone, Debug)]
-pub struct Def(pub String, pub Type, pub Term);
+pub struct Def(pub String, pub Type, pub Term, pub Span);
 
 #[derive(Clone, Debug)]
-pub struct Import(pub String);
+pub struct Import(pub String, pub Span);
+
+/// A declaration of a new inductive or coinductive type, such as:
+///
+/// ```text
+/// inductive List
+///     nil
+///     cons Nat List
+/// ```
+///
+/// Each constructor is written on its own line, followed by the types of its arguments.
+/// A type may take type parameters, written after its name, as in `inductive List a`.
+/// The span covers the first line of the declaration, up to the constructors.
+#[derive(Clone, Debug)]
+pub struct TypeDecl(pub Flavor, pub String, pub Vec<String>, pub Vec<CtorDecl>, pub Span);
 
-#[derive(Clone, Debug, PartialEq, Eq)]
-pub struct Term(Box<TermNode>);
+#[derive(Clone, Debug)]
+pub struct CtorDecl(pub Tag, pub Vec<Type>, pub Span);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Flavor {
+    Inductive,
+    Coinductive,
+}
+
+/// A term, together with the span of source code it was parsed from.
+/// Terms built by the implementation rather than parsed from source have no span.
+/// Spans are ignored when comparing terms.
+#[derive(Clone, Debug)]
+pub struct Term(rc::Rc<TermNode>, Option<rc::Rc<Span>>);
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Variable {
@@ -37,6 +67,8 @@ pub enum TermNode {
     Hole(HoleInfo),
     As(Term, Type),
     StrLit(String),
+    /// A number, which stands for that many `succ`s applied to `zero`.
+    NatLit(BigUint),
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -49,29 +81,39 @@ pub struct HoleInfo {
 
 pub type HoleId = usize;
 
-#[derive(Clone, Debug, PartialEq, Eq)]
-pub struct MatchArm(pub Pattern, pub Term);
+/// A match arm. The span covers the arm's pattern.
+#[derive(Clone, Debug)]
+pub struct MatchArm(pub Pattern, pub Term, pub Span);
 
 pub type Pattern = Vec<String>;
 
 pub type Tag = String;
 
-#[derive(Clone, Debug, PartialEq, Eq)]
-pub struct Type(rc::Rc<TypeNode>);
+/// A type, together with the span of source code it was parsed from, if any.
+/// Like with terms, spans are ignored when comparing types.
+#[derive(Clone, Debug)]
+pub struct Type(rc::Rc<TypeNode>, Option<Span>);
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum TypeNode {
     Atom(String),
+    App(String, Vec<Type>),
+    Var(String),
     Arrow(Type, Type),
     Forall(String, Type),
+    Meta(MetaId),
 }
 
+/// Metavariables stand for types which are yet to be determined by the typechecker.
+/// They never appear in source code.
+pub type MetaId = usize;
+
 impl std::ops::Deref for Term {
     type Target = TermNode;
 
     fn deref(&self) -> &TermNode {
         use std::borrow::Borrow;
-        let Term(rc_tn) = self;
+        let Term(rc_tn, _span) = self;
         rc_tn.borrow()
     }
 }
@@ -79,25 +121,43 @@ impl std::ops::Deref for Term {
 impl AsRef<TermNode> for Term {
     fn as_ref(&self) -> &TermNode {
         use std::borrow::Borrow;
-        let Term(rc_tn) = self;
+        let Term(rc_tn, _span) = self;
         rc_tn.borrow()
     }
 }
 
 impl From<TermNode> for Term {
     fn from(tn: TermNode) -> Self {
-        Term(Box::new(tn))
+        Term(rc::Rc::new(tn), None)
+    }
+}
+
+impl PartialEq for Term {
+    fn eq(&self, other: &Term) -> bool {
+        self.as_node() == other.as_node()
     }
 }
 
+impl Eq for Term {}
+
+impl PartialEq for MatchArm {
+    fn eq(&self, other: &MatchArm) -> bool {
+        let MatchArm(pat, body, _span) = self;
+        let MatchArm(other_pat, other_body, _other_span) = other;
+        pat == other_pat && body == other_body
+    }
+}
+
+impl Eq for MatchArm {}
+
 impl Module {
-    pub fn new(definitions: Vec<Def>, imports: Vec<Import>) -> Self {
-        Module { definitions, imports }
+    pub fn new(definitions: Vec<Def>, imports: Vec<Import>, type_decls: Vec<TypeDecl>) -> Self {
+        Module { definitions, imports, type_decls }
     }
 
     pub fn definition(&self, name: &str) -> Option<Def> {
         for d in &self.definitions {
-            let Def(definition_name, _typ, _body) = d;
+            let Def(definition_name, _typ, _body, _span) = d;
             if definition_name == name {
                 return Some(d.clone())
             }
@@ -137,22 +197,76 @@ impl std::fmt::Display for Variable {
 
 }
 
-pub fn find_matching_arm(tag: &Tag, match_arms: &[MatchArm]) -> MatchArm {
-    for match_arm in match_arms {
-        let MatchArm(pat, _body) = match_arm;
-        if pat[0] == *tag {
-            return match_arm.clone();
+impl std::fmt::Display for TermNode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TermNode::Var(v) => write!(f, "{}", v),
+            TermNode::Lam(x, body) => write!(f, "fun {} => {}", x, **body),
+            TermNode::App(func, args) => {
+                write_term_part(f, func)?;
+                for arg in args {
+                    write!(f, " ")?;
+                    write_term_part(f, arg)?;
+                }
+                Ok(())
+            },
+            TermNode::Let(x, v, body) => write!(f, "let {} = {} in {}", x, **v, **body),
+            TermNode::Match(t, match_arms) => {
+                write!(f, "match {}", **t)?;
+                for MatchArm(pat, body, _span) in match_arms {
+                    write!(f, " with {} => ", pat.join(" "))?;
+                    write_term_part(f, body)?;
+                }
+                Ok(())
+            },
+            TermNode::Hole(hole_info) => {
+                write!(f, "?")?;
+                if let Some(name) = &hole_info.name {
+                    write!(f, "{}", name)?;
+                }
+                if let Some(contents) = &hole_info.contents {
+                    write!(f, "{{{}}}", contents)?;
+                }
+                Ok(())
+            },
+            TermNode::As(t, typ) => {
+                write_term_part(f, t)?;
+                write!(f, " as {}", **typ)
+            },
+            TermNode::StrLit(contents) => write!(f, "{:?}", contents),
+            TermNode::NatLit(n) => write!(f, "{}", n),
         }
     }
-    panic!(format!("No matching arm found for tag {:?}", tag))
+}
+
+/// Writes a term, parenthesizing it unless it is a variable, hole, or literal.
+fn write_term_part(f: &mut std::fmt::Formatter, t: &Term) -> std::fmt::Result {
+    match t.as_ref() {
+        TermNode::Var(_) | TermNode::Hole(_) | TermNode::StrLit(_) | TermNode::NatLit(_) => write!(f, "{}", **t),
+        _ => write!(f, "({})", **t),
+    }
+}
+
+/// Finds the arm of a match whose pattern has the given tag, if there is one.
+pub fn find_matching_arm<'a>(tag: &Tag, match_arms: &'a [MatchArm]) -> Option<&'a MatchArm> {
+    match_arms.iter()
+        .find(|MatchArm(pat, _body, _span)| pat[0] == *tag)
 }
 
 impl From<TypeNode> for Type {
     fn from(tn: TypeNode) -> Self {
-        Type(rc::Rc::new(tn))
+        Type(rc::Rc::new(tn), None)
     }
 }
 
+impl PartialEq for Type {
+    fn eq(&self, other: &Type) -> bool {
+        self.as_ref() == other.as_ref()
+    }
+}
+
+impl Eq for Type {}
+
 impl TryFrom<&str> for Type {
     type Error = parser::ParseErr;
 
@@ -166,7 +280,7 @@ impl std::ops::Deref for Type {
 
     fn deref(&self) -> &TypeNode {
         use std::borrow::Borrow;
-        let Type(rc_tn) = self;
+        let Type(rc_tn, _span) = self;
         rc_tn.borrow()
     }
 }
@@ -174,7 +288,7 @@ impl std::ops::Deref for Type {
 impl AsRef<TypeNode> for Type {
     fn as_ref(&self) -> &TypeNode {
          use std::borrow::Borrow;
-         let Type(rc_tn) = self;
+         let Type(rc_tn, _span) = self;
          rc_tn.borrow()
     }
 }
@@ -183,16 +297,116 @@ impl std::fmt::Display for TypeNode {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             TypeNode::Atom(atom) => write!(f, "{}", atom),
+            TypeNode::App(name, args) => {
+                write!(f, "{}", name)?;
+                for arg in args {
+                    if arg.is_simple() {
+                        write!(f, " {}", **arg)?;
+                    } else {
+                        write!(f, " ({})", **arg)?;
+                    }
+                }
+                Ok(())
+            },
+            TypeNode::Var(name) => write!(f, "{}", name),
             TypeNode::Arrow(dom, cod) => {
-                if let TypeNode::Atom(_) = **dom {
-                    write!(f, "{}", **dom)?;
-                } else {
-                    write!(f, "({})",**dom)?;
+                match **dom {
+                    TypeNode::Arrow(_, _) | TypeNode::Forall(_, _) => write!(f, "({})", **dom)?,
+                    _ => write!(f, "{}", **dom)?,
                 }
                 write!(f, " -> ")?;
                 write!(f, "{}", **cod)
             }
-            TypeNode::Forall(atom, typ) => write!(f, "[{}] -> {}", atom, **typ),
+            TypeNode::Forall(name, typ) => write!(f, "forall {}. {}", name, **typ),
+            TypeNode::Meta(meta_id) => write!(f, "?{}", meta_id),
+        }
+    }
+}
+
+impl Type {
+    pub fn span(&self) -> Option<&Span> {
+        let Type(_rc_tn, span) = self;
+        span.as_ref()
+    }
+
+    pub fn with_span(self, span: Span) -> Type {
+        let Type(rc_tn, _span) = self;
+        Type(rc_tn, Some(span))
+    }
+
+    /// Simple types can be printed without parentheses wherever they appear.
+    fn is_simple(&self) -> bool {
+        match self.as_ref() {
+            TypeNode::Atom(_) | TypeNode::Var(_) | TypeNode::Meta(_) => true,
+            TypeNode::App(_, _) | TypeNode::Arrow(_, _) | TypeNode::Forall(_, _) => false,
+        }
+    }
+
+    /// Returns the type variables which are not bound by any forall, in order of appearance.
+    pub fn free_type_vars(&self) -> Vec<String> {
+        let mut free_vars = Vec::new();
+        self.free_type_vars_in_ctx(&[], &mut free_vars);
+        free_vars
+    }
+
+    fn free_type_vars_in_ctx(&self, bound: &[String], free_vars: &mut Vec<String>) {
+        match self.as_ref() {
+            TypeNode::Atom(_) | TypeNode::Meta(_) => (),
+            TypeNode::App(_, args) => {
+                for arg in args {
+                    arg.free_type_vars_in_ctx(bound, free_vars);
+                }
+            },
+            TypeNode::Var(name) => {
+                if !bound.contains(name) && !free_vars.contains(name) {
+                    free_vars.push(name.clone());
+                }
+            },
+            TypeNode::Arrow(dom, cod) => {
+                dom.free_type_vars_in_ctx(bound, free_vars);
+                cod.free_type_vars_in_ctx(bound, free_vars);
+            },
+            TypeNode::Forall(name, typ) => {
+                let mut new_bound = bound.to_owned();
+                new_bound.push(name.clone());
+                typ.free_type_vars_in_ctx(&new_bound, free_vars);
+            },
+        }
+    }
+
+    /// Quantifies over all of the free type variables of this type.
+    /// For example, `a -> List a` becomes `forall a. a -> List a`.
+    pub fn generalize(&self) -> Type {
+        let mut typ = self.clone();
+        for name in self.free_type_vars().into_iter().rev() {
+            typ = TypeNode::Forall(name, typ).into();
+        }
+        typ
+    }
+
+    /// Replaces the free occurrences of the type variable `name` with `replacement`.
+    pub fn subst(&self, name: &str, replacement: &Type) -> Type {
+        match self.as_ref() {
+            TypeNode::Atom(_) | TypeNode::Meta(_) => self.clone(),
+            TypeNode::App(head, args) => {
+                let args = args.iter().map(|arg| arg.subst(name, replacement)).collect();
+                TypeNode::App(head.clone(), args).into()
+            },
+            TypeNode::Var(var) => {
+                if var == name {
+                    replacement.clone()
+                } else {
+                    self.clone()
+                }
+            },
+            TypeNode::Arrow(dom, cod) => TypeNode::Arrow(dom.subst(name, replacement), cod.subst(name, replacement)).into(),
+            TypeNode::Forall(var, typ) => {
+                if var == name {
+                    self.clone()
+                } else {
+                    TypeNode::Forall(var.clone(), typ.subst(name, replacement)).into()
+                }
+            },
         }
     }
 }
@@ -264,7 +478,7 @@ impl Term {
                     free_vars.insert(fv);
                 }
 
-                for MatchArm(pat, body) in match_arms {
+                for MatchArm(pat, body, _span) in match_arms {
                     let mut new_ctx = ctx.to_owned();
                     new_ctx.extend(pat[1..].iter().cloned());
 
@@ -278,11 +492,22 @@ impl Term {
             Hole(_) => HashSet::new(),
             As(t, _ty) => t.free_vars_in_ctx(ctx),
             StrLit(_s) => HashSet::new(),
+            NatLit(_n) => HashSet::new(),
         }
     }
 
     pub fn as_node(&self) -> &TermNode {
-        let Term(node) = self;
+        let Term(node, _span) = self;
         node
     }
+
+    pub fn span(&self) -> Option<&Span> {
+        let Term(_node, span) = self;
+        span.as_deref()
+    }
+
+    pub fn with_span(self, span: Span) -> Term {
+        let Term(node, _span) = self;
+        Term(node, Some(rc::Rc::new(span)))
+    }
 }
@@ -11,7 +11,7 @@ fn free_vars_examples() {
     let mut import_resolver = FileImportResolver::new("examples");
     let text = import_resolver.resolve("hello").unwrap().text();
     let module = parse_module(None, &text).unwrap();
-    let ast::Def(_name, _typ, body) = module.definition("main").unwrap();
+    let ast::Def(_name, _typ, body, _span) = module.definition("main").unwrap();
     assert_eq!(body.free_vars(), vec!["println".try_into().unwrap()].into_iter().collect());
 }
 
@@ -64,3 +64,26 @@ fn free_vars() {
                                 with Succ z => f z
                               ", &["x", "y", "f"]);
 }
+
+#[test]
+fn type_display() {
+    macro_rules! assert_type_roundtrips {
+        ($text:expr) => {
+            let typ = parse_type(None, &$text).unwrap();
+            assert_eq!(format!("{}", *typ), $text);
+        }
+    }
+
+    assert_type_roundtrips!("Nat");
+    assert_type_roundtrips!("a -> a");
+    assert_type_roundtrips!("List Nat -> Str");
+    assert_type_roundtrips!("List (List a) -> List a");
+    assert_type_roundtrips!("Pair (Nat -> Nat) b");
+    assert_type_roundtrips!("(a -> b) -> List a -> List b");
+    assert_type_roundtrips!("forall a. a -> a");
+    assert_type_roundtrips!("(forall a. a -> a) -> Nat");
+
+    let typ = parse_type(None, "(a -> b) -> List a -> c").unwrap();
+    assert_eq!(typ.free_type_vars(), vec!["a".to_string(), "b".to_string(), "c".to_string()]);
+    assert_eq!(format!("{}", *typ.generalize()), "forall a. forall b. forall c. (a -> b) -> List a -> c");
+}
@@ -1,6 +1,10 @@
+use std::time::Duration;
+
 use quail::runtime;
 use quail::interpreter;
 use quail::resolver;
+use quail::stg;
+use quail::diagnostic::Diagnostic;
 
 use structopt::StructOpt;
 
@@ -9,26 +13,127 @@ use structopt::StructOpt;
 struct Opt {
         #[structopt(help = "Input file")]
         filename: Option<String>,
+
+        #[structopt(
+            long = "message-format",
+            help = "How errors are reported",
+            default_value = "human",
+            possible_values = &["human", "json"],
+        )]
+        message_format: String,
+
+        #[structopt(
+            long = "backend",
+            help = "How the program is run: by the tree-walking evaluator, or compiled to and run on the STG machine",
+            default_value = "tree",
+            possible_values = &["tree", "stg"],
+        )]
+        backend: String,
+
+        #[structopt(
+            long = "trace",
+            help = "Writes each step the STG machine takes to stderr, for people to read or as lines of JSON",
+            default_value = "none",
+            possible_values = &["none", "human", "json"],
+        )]
+        trace: String,
+
+        #[structopt(long = "max-steps", help = "The most reduction steps evaluation may take")]
+        max_steps: Option<u64>,
+
+        #[structopt(
+            long = "timeout",
+            help = "The most seconds evaluation may take",
+            parse(try_from_str = runtime::parse_seconds),
+        )]
+        timeout: Option<Duration>,
+
+        #[structopt(long = "max-values", help = "The most values evaluation may allocate")]
+        max_values: Option<u64>,
 }
 
-fn main() -> Result<(), runtime::RuntimeError> {
+fn main() {
     let opt = Opt::from_args();
+    let has_budget = opt.max_steps.is_some() || opt.timeout.is_some() || opt.max_values.is_some();
+    if opt.backend == "stg" && has_budget {
+        // The STG machine doesn't meter what it does, so it can't keep to a budget.
+        structopt::clap::Error::with_description(
+            "--max-steps, --timeout and --max-values can't be used with --backend=stg",
+            structopt::clap::ErrorKind::ArgumentConflict,
+        ).exit();
+    }
     let filename = opt.filename;
+    let json = opt.message_format == "json";
+    let budget = runtime::Budget {
+        steps: opt.max_steps,
+        time: opt.timeout,
+        values: opt.max_values,
+    };
+
+    if json {
+        // Errors during evaluation still abort the process, so report them as they happen.
+        std::panic::set_hook(Box::new(|panic_info| {
+            let message = match panic_info.payload().downcast_ref::<&str>() {
+                Some(message) => message.to_string(),
+                None => match panic_info.payload().downcast_ref::<String>() {
+                    Some(message) => message.to_string(),
+                    None => "The program panicked".to_string(),
+                },
+            };
+            eprintln!("{}", Diagnostic::new("runtime", message, None).to_json());
+        }));
+    }
+
     match filename {
         None => {
             println!("{}", include_str!("../../assets/quail.txt"));
             let mut interpreter = interpreter::Interpreter::new();
+            interpreter.runtime.budget = budget;
             interpreter::repl(&mut interpreter);
         },
         Some(filename) => {
-            let mut runtime = runtime::Runtime::new();
+            // The interpreter is only needed for its hole handler, which fills holes as the program runs.
+            let mut interpreter = interpreter::Interpreter::new();
+            let runtime = &mut interpreter.runtime;
+            runtime.budget = budget;
             let mut import_resolver = resolver::ChainedImportResolver::new(
                 Box::new(resolver::FilePathImportResolver),
                 Box::new(resolver::FileImportResolver::new("examples")),
             );
-            runtime.import(&filename, &mut import_resolver, true)?;
-            runtime.exec();
+            let result = match opt.backend.as_str() {
+                "stg" => exec_stg(runtime, &filename, &mut import_resolver, &opt.trace),
+                _ => runtime.import(&filename, &mut import_resolver, true).and_then(|()| runtime.exec()),
+            };
+            if let Err(error) = result {
+                if json {
+                    eprintln!("{}", error.diagnostic().to_json());
+                } else {
+                    eprintln!("{}", runtime.render_error(&error));
+                }
+                std::process::exit(1);
+            }
         },
     }
+}
+
+/// Loads and typechecks the program, then runs it on the STG machine rather than the Runtime.
+fn exec_stg(
+    runtime: &mut runtime::Runtime,
+    filename: &str,
+    import_resolver: &mut dyn resolver::ImportResolver,
+    trace: &str,
+) -> Result<(), runtime::RuntimeError> {
+    let definitions = runtime.load(filename, import_resolver, true)?;
+    runtime.check_main()?;
+
+    let program = stg::transform::transform(&definitions, &runtime.inductive_typedefs);
+    let mut machine = stg::StgMachine::new(&program, None);
+    machine.primops.extend(stg::builtins::primops(&runtime.inductive_typedefs, runtime.show_depth));
+    match trace {
+        "human" => machine.tracer = Box::new(stg::trace::HumanTracer(std::io::stderr())),
+        "json" => machine.tracer = Box::new(stg::trace::JsonTracer(std::io::stderr())),
+        _ => (),
+    }
+    stg::builtins::exec(&mut machine, "main")?;
     Ok(())
 }
@@ -0,0 +1,32 @@
+use std::io;
+use std::path::PathBuf;
+
+use quail::lsp;
+use quail::resolver;
+
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "quail-lsp", about = "A language server for Quail, speaking LSP over stdio")]
+struct Opt {
+        #[structopt(long = "import-dir", help = "Directory to resolve imports from", default_value = "examples")]
+        import_dir: PathBuf,
+}
+
+fn main() -> io::Result<()> {
+    let opt = Opt::from_args();
+    if !opt.import_dir.is_dir() {
+        eprintln!("Import directory {:?} does not exist", opt.import_dir);
+        std::process::exit(1);
+    }
+
+    let import_resolver = resolver::ChainedImportResolver::new(
+        Box::new(resolver::FilePathImportResolver),
+        Box::new(resolver::FileImportResolver::new(&opt.import_dir)),
+    );
+    let mut server = lsp::Server::new(Box::new(import_resolver));
+
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    server.run(&mut stdin.lock(), &mut stdout.lock())
+}
@@ -1,37 +1,46 @@
 use std::rc;
 
-#[derive(Debug)]
-struct ContextNode<T>(Vec<(String, T)>);
-
+///
+/// A Context is a persistent list of bindings of names to values. Extending a context never
+/// changes it. Instead, a new context is made which shares everything but the new binding,
+/// so extending is cheap no matter how many bindings are in scope.
+///
 #[derive(Debug, Clone)]
-pub struct Context<T>(rc::Rc<ContextNode<T>>);
+pub struct Context<T>(Option<rc::Rc<ContextNode<T>>>);
+
+#[derive(Debug)]
+struct ContextNode<T> {
+    name: String,
+    value: T,
+    rest: Context<T>,
+}
 
 impl<T: Clone> Context<T> {
     pub fn empty() -> Self {
-        Context(rc::Rc::new(ContextNode(Vec::new())))
+        Context(None)
     }
 
     pub fn lookup(&self, x: &str, k: usize) -> Option<T> {
-        let Context(rc_ctx_node) = self;
-        let ContextNode(var_typ_list) = rc_ctx_node.as_ref();
-        for (y, typ) in var_typ_list.iter().rev() {
-            if x == y {
+        let mut ctx = self;
+        while let Context(Some(node)) = ctx {
+            if x == node.name {
                 if k == 0 {
-                    return Some(typ.clone());
+                    return Some(node.value.clone());
                 } else {
-                    return self.lookup(x, k - 1);
+                    return node.rest.lookup(x, k - 1);
                 }
             }
+            ctx = &node.rest;
         }
         None
     }
 
     pub fn extend(&self, x: &str, t: T) -> Context<T> {
-        let Context(rc_ctx_node) = self;
-        let ContextNode(var_val_list) = rc_ctx_node.as_ref();
-        let mut extended_var_val_list = var_val_list.clone();
-        extended_var_val_list.push((x.to_string(), t.clone()));
-        Context(rc::Rc::new(ContextNode(extended_var_val_list)))
+        Context(Some(rc::Rc::new(ContextNode {
+            name: x.to_string(),
+            value: t,
+            rest: self.clone(),
+        })))
     }
 
     pub fn extend_many(&self, bindings: &[(String, T)]) -> Context<T> {
@@ -50,9 +59,31 @@ impl<T: Clone> Context<T> {
         result_ctx
     }
 
+    /// Moves the values out of the context, leaving it empty, so long as no other context shares them.
+    pub fn take_unshared(&mut self) -> Vec<T> {
+        let mut values = vec![];
+        let mut next = self.0.take();
+        while let Some(node) = next {
+            match rc::Rc::try_unwrap(node) {
+                Ok(ContextNode { name: _, value, rest }) => {
+                    values.push(value);
+                    next = rest.0;
+                },
+                Err(_shared) => break,
+            }
+        }
+        values
+    }
+
+    /// The bindings of the context, with the outermost first.
     pub fn bindings(&self) -> Vec<(String, T)> {
-        let Context(context_node_rc) = self;
-        let ContextNode(bindings) = context_node_rc.as_ref();
-        bindings.clone()
+        let mut bindings = vec![];
+        let mut ctx = self;
+        while let Context(Some(node)) = ctx {
+            bindings.push((node.name.to_string(), node.value.clone()));
+            ctx = &node.rest;
+        }
+        bindings.reverse();
+        bindings
     }
 }
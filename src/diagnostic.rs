@@ -0,0 +1,106 @@
+use serde_json::json;
+
+use crate::tokenizer::Loc;
+use crate::tokenizer::Span;
+
+///
+/// A Diagnostic is an error made ready to be shown to the user: a message, the region of
+/// source code it is about (if any), and any secondary notes.
+///
+/// Parse errors, type errors and runtime errors are all turned into Diagnostics, so that
+/// they are rendered the same way.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// A short, stable name for the kind of error, such as `mismatch` or `coverage`.
+    pub code: &'static str,
+    pub message: String,
+    pub span: Option<Span>,
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn new(code: &'static str, message: String, span: Option<Span>) -> Self {
+        Diagnostic {
+            code,
+            message,
+            span,
+            notes: vec![],
+        }
+    }
+
+    pub fn with_notes(mut self, notes: Vec<String>) -> Self {
+        self.notes.extend(notes);
+        self
+    }
+
+    /// The path of the source file the diagnostic points into.
+    pub fn path(&self) -> Option<&str> {
+        self.span.as_ref().and_then(|span| span.start.path.as_deref())
+    }
+
+    ///
+    /// Renders the diagnostic compiler-style. When the source text is given, the offending
+    /// line is quoted and the span is underlined with carets:
+    ///
+    /// ```text
+    /// error: Expected Bool but found Nat
+    ///  --> examples/bad.ql:1:32
+    ///   |
+    /// 1 | def f : Nat -> Bool = fun n => n
+    ///   |                                ^
+    /// ```
+    ///
+    pub fn render(&self, source: Option<&str>) -> String {
+        let mut lines = vec![format!("error: {}", self.message)];
+        let mut gutter = String::new();
+
+        if let Some(span) = &self.span {
+            let start = &span.start;
+            let path = start.path.as_deref().unwrap_or("<input>");
+            let line_number = (start.line + 1).to_string();
+            gutter = " ".repeat(line_number.len());
+            lines.push(format!("{}--> {}:{}:{}", gutter, path, start.line + 1, start.col + 1));
+
+            if let Some(line) = source.and_then(|source| source.lines().nth(start.line)) {
+                // Tabs are shown as single spaces so that the carets line up with the columns.
+                let line: String = line.chars().map(|ch| if ch == '\t' { ' ' } else { ch }).collect();
+                let width = line.chars().count();
+                let end_col = if span.end.line > start.line {
+                    width
+                } else {
+                    span.end.col.min(width)
+                };
+                let underline_width = end_col.saturating_sub(start.col).max(1);
+
+                lines.push(format!("{} |", gutter));
+                lines.push(format!("{} | {}", line_number, line));
+                lines.push(format!("{} | {}{}", gutter, " ".repeat(start.col), "^".repeat(underline_width)));
+            }
+        }
+
+        for note in &self.notes {
+            lines.push(format!("{} = note: {}", gutter, note));
+        }
+        lines.join("\n")
+    }
+
+    ///
+    /// The diagnostic as a JSON object, for tools which consume errors without scraping text.
+    /// Unlike `Loc`, the lines and columns in the range are one-based.
+    ///
+    pub fn to_json(&self) -> serde_json::Value {
+        let position = |loc: &Loc| json!({ "line": loc.line + 1, "col": loc.col + 1 });
+        json!({
+            "severity": "error",
+            "code": self.code,
+            "message": self.message,
+            "file": self.path(),
+            "range": self.span.as_ref().map(|span| json!({
+                "start": position(&span.start),
+                "end": position(&span.end),
+            })),
+            "notes": self.notes,
+        })
+    }
+}
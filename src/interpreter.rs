@@ -1,3 +1,7 @@
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::rc;
+
 use dirs;
 use rustyline::error::ReadlineError;
 
@@ -10,7 +14,16 @@ use crate::context::Context;
 
 use ast::Import;
 use ast::Def;
+use ast::HoleInfo;
+use ast::Term;
+use ast::Type;
+use ast::TypeNode;
+use check::HoleTyping;
 use runtime::Runtime;
+use runtime::RuntimeError;
+use runtime::Cause;
+use runtime::Value;
+use runtime::Resource;
 
 pub struct Interpreter {
     /// The REPL and hole-filling mode both use rustyline, which is
@@ -23,31 +36,27 @@ pub struct Interpreter {
     pub readline_file: String,
 
     /// The rustyline Editor. This is a handle to interact with the readline library.
-    pub editor: rustyline::Editor<()>,
+    /// It is shared with the hole handler, so that the REPL and hole-filling mode have
+    /// one history between them.
+    pub editor: rc::Rc<RefCell<rustyline::Editor<()>>>,
 
     pub runtime: Runtime,
 }
 
 impl Interpreter {
     pub fn new() -> Self {
-        let readline_file = dirs::config_dir()
-            .expect("User does not have a home directory??")
-            .join("quail").join("history");
-
-        if !readline_file.exists() {
-            std::fs::create_dir_all(&readline_file.parent().unwrap()).unwrap();
-            std::fs::File::create(&readline_file).expect("Could not create readline file");
-        }
+        let readline_file = readline_file().to_string_lossy().to_string();
+        let editor = rc::Rc::new(RefCell::new(readline_editor(Path::new(&readline_file))));
 
-        let mut editor = rustyline::Editor::new();
-        if editor.load_history(&readline_file).is_err() {
-            eprintln!("Could not read from {:?} for readline history.", &readline_file);
-        }
-
-        let runtime = Runtime::new();
+        let mut runtime = Runtime::new();
+        let hole_editor = editor.clone();
+        let hole_readline_file = readline_file.clone();
+        runtime.set_hole_handler(rc::Rc::new(move |runtime, hole_info, ctx| {
+            hole_mode(&mut hole_editor.borrow_mut(), &hole_readline_file, runtime, hole_info, ctx)
+        }));
 
         Interpreter {
-            readline_file: readline_file.to_string_lossy().to_string(),
+            readline_file,
             editor,
             runtime,
         }
@@ -55,12 +64,39 @@ impl Interpreter {
 
     /// Reads a line using the rustyline readline library and saves it to the user's history file.
     pub fn readline(&mut self) -> Result<String, ReadlineError> {
-        let line = self.editor.readline("> ")?;
-        self.editor.add_history_entry(line.as_str());
-        self.editor.save_history(&self.readline_file)?;
-        Ok(line)
+        readline(&mut self.editor.borrow_mut(), &self.readline_file, "> ")
+    }
+
+}
+
+/// Finds the readline history file, creating it if it doesn't exist.
+fn readline_file() -> PathBuf {
+    let readline_file = dirs::config_dir()
+        .expect("User does not have a home directory??")
+        .join("quail").join("history");
+
+    if !readline_file.exists() {
+        std::fs::create_dir_all(readline_file.parent().unwrap()).unwrap();
+        std::fs::File::create(&readline_file).expect("Could not create readline file");
     }
+    readline_file
+}
+
+fn readline(editor: &mut rustyline::Editor<()>, readline_file: &str, prompt: &str) -> Result<String, ReadlineError> {
+    let line = editor.readline(prompt)?;
+    editor.add_history_entry(line.as_str());
+    if let Err(err) = editor.save_history(readline_file) {
+        println!("Error: {:?}", err);
+    }
+    Ok(line)
+}
 
+fn readline_editor(readline_file: &Path) -> rustyline::Editor<()> {
+    let mut editor = rustyline::Editor::new();
+    if editor.load_history(readline_file).is_err() {
+        eprintln!("Could not read from {:?} for readline history.", readline_file);
+    }
+    editor
 }
 
 pub fn repl(interpreter: &mut Interpreter) {
@@ -83,6 +119,8 @@ fn repl_line(runtime: &mut Runtime, line: &str) {
 
     if line.is_empty() {
         ()
+    } else if let Some(settings) = line.strip_prefix(":set") {
+        repl_line_set(runtime, settings.trim());
     } else if line.starts_with("import") {
         repl_line_import(runtime, line);
     } else if line.starts_with("def") {
@@ -92,17 +130,51 @@ fn repl_line(runtime: &mut Runtime, line: &str) {
     }
 }
 
+///
+/// Changes the settings of the REPL. `:set <resource> <limit>` limits the steps, time or values
+/// each evaluation may spend, with `none` meaning no limit. `:set depth <n>` is how deep results
+/// are shown into coinductive values. `:set` alone shows the current settings.
+///
+fn repl_line_set(runtime: &mut Runtime, line: &str) {
+    let resources = [Resource::Steps, Resource::Time, Resource::Values];
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match words.as_slice() {
+        [] => {
+            for resource in resources.iter() {
+                println!("{} = {}", resource, runtime.budget.describe(*resource));
+            }
+            println!("depth = {}", runtime.show_depth);
+        },
+        ["depth", depth] => match depth.parse::<usize>() {
+            Ok(depth) => {
+                runtime.show_depth = depth;
+                println!("depth = {}", depth);
+            },
+            Err(_) => println!("Expected a whole number for the depth, but found {:?}", depth),
+        },
+        [resource, limit] => {
+            let result = resource.parse::<Resource>()
+                .and_then(|resource| runtime.budget.set(resource, limit).map(|()| resource));
+            match result {
+                Ok(resource) => println!("{} = {}", resource, runtime.budget.describe(resource)),
+                Err(message) => println!("{}", message),
+            }
+        },
+        _ => println!("Usage: :set [steps|time|values|depth] [<limit>|none]"),
+    }
+}
+
 fn repl_line_import(runtime: &mut Runtime, line: &str) {
     let mut import_resolver = resolver::FileImportResolver::new("examples");
 
     match parser::parse_import(None, line) {
-        Ok(Import(module_name)) => {
+        Ok(Import(module_name, _span)) => {
             match runtime.import(&module_name, &mut import_resolver, false) {
                 Ok(()) => println!("import successful"),
-                Err(msg) => println!("{:?}", msg),
+                Err(err) => println!("{}", runtime.render_error(&err)),
             }
         },
-        Err(e) => println!("There was an error {:?}", e),
+        Err(e) => println!("{}", e.diagnostic().render(Some(line))),
     }
 }
 
@@ -111,13 +183,13 @@ fn repl_line_def(runtime: &mut Runtime, line: &str) {
         Ok(definition) => {
             match runtime.define(&definition) {
                 Ok(()) => {
-                    let Def(name, typ, _body) = definition;
+                    let Def(name, typ, _body, _span) = definition;
                     println!("=> {} : {}", name, *typ);
                 },
-                Err(err) => println!("Error: {:?}", err),
+                Err(err) => println!("{}", err.diagnostic().render(Some(line))),
             }
         },
-        Err(e) => println!("There was an error {:?}", e),
+        Err(e) => println!("{}", e.diagnostic().render(Some(line))),
     }
 }
 
@@ -129,14 +201,146 @@ fn repl_line_term(runtime: &mut Runtime, line: &str) {
                     &term,
                     type_context,
                     &runtime.inductive_typedefs,
+                    &mut runtime.hole_typings,
                 ) {
                 Ok(typ) => {
-                    let value = runtime.eval(&term, Context::empty());
-                    println!("=> {:?} : {}", &value, *typ);
+                    // Effects typed at the prompt are carried out, and their results shown.
+                    let result = runtime.eval(&term, Context::empty())
+                        .and_then(|value| runtime.force(&value))
+                        .and_then(|value| match typ.as_ref() {
+                            TypeNode::App(name, args) if name == "IO" => Ok((runtime.perform(&value)?, args[0].clone())),
+                            _ => Ok((value, typ.clone())),
+                        })
+                        .and_then(|(value, typ)| Ok((runtime.show(value)?, typ)));
+                    match result {
+                        Ok((shown, typ)) => println!("=> {} : {}", shown, *typ),
+                        Err(error) => println!("{}", runtime.render_error(&error)),
+                    }
                 },
-                Err(type_error) => println!("Type Error: {:?}", &type_error),
+                Err(type_error) => println!("{}", type_error.diagnostic().render(Some(line))),
             }
         },
-        Err(e) => println!("There was an error {:?}", e),
+        Err(e) => println!("{}", e.diagnostic().render(Some(line))),
+    }
+}
+
+///
+/// Hole-filling mode. This is installed as the hole handler of the Runtime, and so it is
+/// entered whenever evaluation reaches a hole which hasn't been filled yet. The user is
+/// given a prompt where they may fill the hole, evaluate expressions in the hole's scope,
+/// or abort the program. See assets/help/hole.txt for details.
+///
+/// Aborting, or reaching the end of input, gives an `Aborted` error, which ends the evaluation.
+///
+pub fn hole_mode(
+    editor: &mut rustyline::Editor<()>,
+    readline_file: &str,
+    runtime: &mut Runtime,
+    hole_info: &HoleInfo,
+    ctx: Context<Value>,
+) -> Result<Term, RuntimeError> {
+    let hole_typing = runtime.hole_typings.get(&hole_info.hole_id).cloned();
+    print_hole_info(hole_info, hole_typing.as_ref(), &ctx);
+
+    loop {
+        let line = match readline(editor, readline_file, "?> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => return Err(runtime.error(Cause::Aborted(hole_info.clone()))),
+            Err(err) => {
+                println!("Error: {:?}", err);
+                continue;
+            },
+        };
+
+        let line = line.trim();
+        let (command, rest) = match line.find(char::is_whitespace) {
+            Some(i) => (&line[..i], line[i..].trim()),
+            None => (line, ""),
+        };
+
+        match command {
+            "" => (),
+            "fill" => match hole_mode_term(runtime, rest, hole_typing.as_ref(), true) {
+                Ok((term, _typ)) => return Ok(term),
+                Err(err) => println!("{}", err),
+            },
+            "eval" => match hole_mode_term(runtime, rest, hole_typing.as_ref(), false) {
+                Ok((term, typ)) => {
+                    let result = runtime.eval(&term, ctx.clone())
+                        .and_then(|value| runtime.show(value));
+                    match result {
+                        Ok(shown) => println!("=> {} : {}", shown, *typ),
+                        Err(error) => println!("{}", runtime.render_error(&error)),
+                    }
+                },
+                Err(err) => println!("{}", err),
+            },
+            "abort" => return Err(runtime.error(Cause::Aborted(hole_info.clone()))),
+            "help" => println!("{}", include_str!("../assets/help/hole.txt")),
+            _ => println!("Unknown command {:?}. Try fill, eval, abort, or help.", command),
+        }
+    }
+}
+
+fn print_hole_info(hole_info: &HoleInfo, hole_typing: Option<&HoleTyping>, ctx: &Context<Value>) {
+    match &hole_info.name {
+        Some(name) => println!("Reached hole ?{} at {}", name, hole_info.loc),
+        None => println!("Reached hole at {}", hole_info.loc),
+    }
+
+    if let Some(contents) = &hole_info.contents {
+        println!("    contents: {{{}}}", contents);
+    }
+
+    if let Some(hole_typing) = hole_typing {
+        println!("    expected type: {}", *hole_typing.typ);
+        for (name, _value) in ctx.bindings() {
+            if let Some(typ) = hole_typing.ctx.lookup(&name, 0) {
+                println!("    {} : {}", name, *typ);
+            }
+        }
+    }
+
+    println!("Type help for a list of commands.");
+}
+
+/// Parses and typechecks a term typed into hole-filling mode. Terms are checked in the
+/// typing context of the hole. When `check_hole_type` is set, the term must also have
+/// the type expected by the hole.
+fn hole_mode_term(
+    runtime: &mut Runtime,
+    line: &str,
+    hole_typing: Option<&HoleTyping>,
+    check_hole_type: bool,
+) -> Result<(Term, Type), String> {
+    let term = parser::parse_term(None, line).map_err(|e| e.diagnostic().render(Some(line)))?;
+
+    let type_context = match hole_typing {
+        Some(hole_typing) => hole_typing.ctx.clone(),
+        None => runtime.builtin_type_ctx.append(runtime.definition_type_ctx.clone()),
+    };
+
+    let typ = match hole_typing {
+        Some(hole_typing) if check_hole_type => {
+            check::check_type(
+                &term,
+                type_context,
+                &runtime.inductive_typedefs,
+                &mut runtime.hole_typings,
+                hole_typing.typ.clone(),
+            ).map(|()| hole_typing.typ.clone())
+        },
+        _ => check::infer_type(
+            &term,
+            type_context,
+            &runtime.inductive_typedefs,
+            &mut runtime.hole_typings,
+        ),
+    };
+
+    match typ {
+        Ok(typ) => Ok((term, typ)),
+        Err(type_error) => Err(type_error.diagnostic().render(Some(line))),
     }
 }
@@ -1,5 +1,7 @@
 mod ast;
+pub mod diagnostic;
 pub mod interpreter;
+pub mod lsp;
 pub mod parser;
 mod tests;
 pub mod tokenizer;
@@ -0,0 +1,452 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::BufRead;
+use std::io::Write;
+
+use serde_json::json;
+use serde_json::Value as Json;
+
+use crate::ast::Def;
+use crate::ast::Import;
+use crate::ast::HoleInfo;
+use crate::ast::Term;
+use crate::ast::TermNode;
+use crate::ast::MatchArm;
+use crate::ast::Type;
+use crate::diagnostic::Diagnostic;
+use crate::parser;
+use crate::resolver::ImportResolver;
+use crate::runtime::Runtime;
+use crate::tokenizer::Loc;
+use crate::tokenizer::Span;
+use crate::tokenizer::Token;
+use crate::tokenizer::Tokenizer;
+use crate::types::check;
+
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+const SEVERITY_ERROR: u64 = 1;
+const SEVERITY_INFORMATION: u64 = 3;
+
+///
+/// A language server for Quail. It speaks the Language Server Protocol over any reader and
+/// writer, so that it may be driven by an editor over stdio or by a scripted client in tests.
+///
+/// Whenever a document is opened or changed, it is parsed and typechecked, but never evaluated.
+/// The server publishes the errors found as diagnostics, along with one diagnostic for each
+/// hole giving its expected type. It also answers hover requests with the types of top-level
+/// definitions and holes, and go-to-definition requests for top-level definitions, including
+/// those brought in by imports.
+///
+pub struct Server {
+    resolver: Box<dyn ImportResolver>,
+    documents: HashMap<String, Document>,
+}
+
+/// An open document, along with what was learned by checking it.
+struct Document {
+    path: String,
+    text: String,
+    analysis: Analysis,
+}
+
+struct Analysis {
+    diagnostics: Vec<Diagnostic>,
+    /// Holds the types of every top-level definition in scope.
+    runtime: Runtime,
+    /// Where each top-level definition in scope was defined.
+    definition_spans: HashMap<String, Span>,
+    holes: Vec<(Span, HoleInfo, Option<Type>)>,
+}
+
+impl Server {
+    pub fn new(resolver: Box<dyn ImportResolver>) -> Self {
+        Server {
+            resolver,
+            documents: HashMap::new(),
+        }
+    }
+
+    /// Handles messages until the client sends `exit` or closes the connection.
+    pub fn run(&mut self, reader: &mut dyn BufRead, writer: &mut dyn Write) -> io::Result<()> {
+        while let Some(message) = read_message(reader)? {
+            let method = message["method"].as_str().unwrap_or("");
+            let params = &message["params"];
+
+            match message.get("id") {
+                Some(id) if !method.is_empty() => {
+                    let response = match self.handle_request(method, params) {
+                        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+                        Err((code, message)) => json!({
+                            "jsonrpc": "2.0",
+                            "id": id,
+                            "error": { "code": code, "message": message },
+                        }),
+                    };
+                    write_message(writer, &response)?;
+                },
+                Some(_id) => (),
+                None if method == "exit" => return Ok(()),
+                None => {
+                    for notification in self.handle_notification(method, params) {
+                        write_message(writer, &notification)?;
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_request(&mut self, method: &str, params: &Json) -> Result<Json, (i64, String)> {
+        match method {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "hoverProvider": true,
+                    "definitionProvider": true,
+                },
+                "serverInfo": { "name": "quail-lsp" },
+            })),
+            "shutdown" => Ok(Json::Null),
+            "textDocument/hover" => {
+                let (document, loc) = self.document_position(params)?;
+                Ok(document.hover(&loc))
+            },
+            "textDocument/definition" => {
+                let (document, loc) = self.document_position(params)?;
+                Ok(document.definition(&loc))
+            },
+            _ => Err((METHOD_NOT_FOUND, format!("Unknown method {}", method))),
+        }
+    }
+
+    /// Handles a notification from the client, returning the notifications to send in reply.
+    fn handle_notification(&mut self, method: &str, params: &Json) -> Vec<Json> {
+        let uri = match params["textDocument"]["uri"].as_str() {
+            Some(uri) => uri.to_string(),
+            None => return vec![],
+        };
+
+        match method {
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or("");
+                vec![self.update_document(&uri, text)]
+            },
+            "textDocument/didChange" => {
+                // The server asks for full syncs, so the last change holds the whole text.
+                match params["contentChanges"].as_array().and_then(|changes| changes.last()) {
+                    Some(change) => {
+                        let text = change["text"].as_str().unwrap_or("");
+                        vec![self.update_document(&uri, text)]
+                    },
+                    None => vec![],
+                }
+            },
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                vec![publish_diagnostics(&uri, vec![])]
+            },
+            _ => vec![],
+        }
+    }
+
+    fn update_document(&mut self, uri: &str, text: &str) -> Json {
+        let path = uri_to_path(uri);
+        let analysis = analyze(&path, text, self.resolver.as_mut());
+
+        let mut diagnostics: Vec<Json> = analysis.diagnostics.iter()
+            .map(|diagnostic| lsp_diagnostic(diagnostic, SEVERITY_ERROR))
+            .collect();
+        for (span, hole_info, typ) in analysis.holes.iter() {
+            let message = match typ {
+                Some(typ) => format!("Hole {} has type {}", hole_name(hole_info), **typ),
+                None => format!("Hole {} could not be given a type", hole_name(hole_info)),
+            };
+            let diagnostic = Diagnostic::new("hole", message, Some(span.clone()));
+            diagnostics.push(lsp_diagnostic(&diagnostic, SEVERITY_INFORMATION));
+        }
+
+        self.documents.insert(uri.to_string(), Document {
+            path,
+            text: text.to_string(),
+            analysis,
+        });
+        publish_diagnostics(uri, diagnostics)
+    }
+
+    fn document_position(&self, params: &Json) -> Result<(&Document, Loc), (i64, String)> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or("");
+        let document = self.documents.get(uri)
+            .ok_or_else(|| (INVALID_PARAMS, format!("Document {} is not open", uri)))?;
+        let line = params["position"]["line"].as_u64();
+        let col = params["position"]["character"].as_u64();
+        match (line, col) {
+            (Some(line), Some(col)) => {
+                let loc = Loc {
+                    path: Some(document.path.clone()),
+                    line: line as usize,
+                    col: col as usize,
+                };
+                Ok((document, loc))
+            },
+            _ => Err((INVALID_PARAMS, "Expected a position".to_string())),
+        }
+    }
+}
+
+impl Document {
+    fn hover(&self, loc: &Loc) -> Json {
+        if let Some((span, hole_info, typ)) = self.analysis.holes.iter().find(|(span, _, _)| contains(span, loc)) {
+            let contents = match typ {
+                Some(typ) => format!("{} : {}", hole_name(hole_info), **typ),
+                None => hole_name(hole_info),
+            };
+            return json!({ "contents": contents, "range": lsp_range(span) });
+        }
+
+        match self.identifier_at(loc) {
+            Some((name, span)) => {
+                let runtime = &self.analysis.runtime;
+                let typ = runtime.definition_type_ctx.lookup(&name, 0)
+                    .or_else(|| runtime.builtin_type_ctx.lookup(&name, 0));
+                match typ {
+                    Some(typ) => json!({ "contents": format!("{} : {}", name, *typ), "range": lsp_range(&span) }),
+                    None => Json::Null,
+                }
+            },
+            None => Json::Null,
+        }
+    }
+
+    fn definition(&self, loc: &Loc) -> Json {
+        let span = self.identifier_at(loc)
+            .and_then(|(name, _span)| self.analysis.definition_spans.get(&name));
+
+        match span {
+            Some(span) => {
+                let uri = match &span.start.path {
+                    Some(path) => path_to_uri(path),
+                    None => return Json::Null,
+                };
+                json!({ "uri": uri, "range": lsp_range(span) })
+            },
+            None => Json::Null,
+        }
+    }
+
+    /// The identifier under the cursor, if there is one.
+    fn identifier_at(&self, loc: &Loc) -> Option<(String, Span)> {
+        let mut tokenizer = Tokenizer::new(Some(self.path.clone()), &self.text);
+        let tokens = tokenizer.tokenize_spans().ok()?;
+        tokens.into_iter().find_map(|(token, span)| match token {
+            Token::Ident(_loc, name) if contains(&span, loc) => Some((name, span)),
+            _ => None,
+        })
+    }
+}
+
+///
+/// Parses and typechecks a document. Unlike `Runtime::import`, checking carries on past the first
+/// error, so that one mistake doesn't hide the rest. A definition which fails to typecheck is still
+/// assumed to have its declared type while checking those which follow it.
+///
+fn analyze(path: &str, text: &str, resolver: &mut dyn ImportResolver) -> Analysis {
+    let mut analysis = Analysis {
+        diagnostics: vec![],
+        runtime: Runtime::new(),
+        definition_spans: HashMap::new(),
+        holes: vec![],
+    };
+
+    let module = match parser::parse_module(Some(path.to_string()), text) {
+        Ok(module) => module,
+        Err(error) => {
+            analysis.diagnostics.push(error.diagnostic());
+            return analysis;
+        },
+    };
+
+    for Import(name, import_span) in module.imports.iter() {
+        match analysis.runtime.load(name, resolver, false) {
+            Ok(definitions) => {
+                for Def(name, _typ, _body, span) in definitions {
+                    analysis.definition_spans.insert(name, span);
+                }
+            },
+            Err(error) => {
+                // Errors in other modules are reported at the import which brought them in.
+                let mut diagnostic = error.diagnostic();
+                if diagnostic.path() != Some(path) {
+                    if let Some(span) = &diagnostic.span {
+                        diagnostic.notes.insert(0, format!("in the module {} at {}", name, span));
+                    }
+                    diagnostic.span = Some(import_span.clone());
+                }
+                analysis.diagnostics.push(diagnostic);
+            },
+        }
+    }
+
+    for type_decl in module.type_decls.iter() {
+        if let Err(error) = analysis.runtime.declare_type(type_decl) {
+            analysis.diagnostics.push(error.diagnostic());
+        }
+    }
+
+    for definition in module.definitions.iter() {
+        let Def(name, typ, body, span) = definition;
+        analysis.definition_spans.insert(name.to_string(), span.clone());
+
+        if let Err(error) = analysis.runtime.check_definition(definition) {
+            analysis.diagnostics.push(error.diagnostic());
+            let runtime = &mut analysis.runtime;
+            if check::check_type_wellformed(typ, &runtime.inductive_typedefs).is_ok() {
+                runtime.definition_type_ctx = runtime.definition_type_ctx.extend(name, typ.clone());
+            }
+        }
+
+        collect_holes(body, &mut |span, hole_info| {
+            let typ = analysis.runtime.hole_typings.get(&hole_info.hole_id).map(|hole_typing| hole_typing.typ.clone());
+            analysis.holes.push((span.clone(), hole_info.clone(), typ));
+        });
+    }
+
+    analysis
+}
+
+fn collect_holes(t: &Term, f: &mut dyn FnMut(&Span, &HoleInfo)) {
+    match t.as_ref() {
+        TermNode::Var(_v) => (),
+        TermNode::Lam(_x, body) => collect_holes(body, f),
+        TermNode::App(func, args) => {
+            collect_holes(func, f);
+            for arg in args {
+                collect_holes(arg, f);
+            }
+        },
+        TermNode::Let(_x, v, body) => {
+            collect_holes(v, f);
+            collect_holes(body, f);
+        },
+        TermNode::Match(discriminee, match_arms) => {
+            collect_holes(discriminee, f);
+            for MatchArm(_pat, body, _span) in match_arms {
+                collect_holes(body, f);
+            }
+        },
+        TermNode::Hole(hole_info) => {
+            if let Some(span) = t.span() {
+                f(span, hole_info);
+            }
+        },
+        TermNode::As(term, _typ) => collect_holes(term, f),
+        TermNode::StrLit(_contents) => (),
+        TermNode::NatLit(_n) => (),
+    }
+}
+
+fn hole_name(hole_info: &HoleInfo) -> String {
+    match &hole_info.name {
+        Some(name) => format!("?{}", name),
+        None => "?".to_string(),
+    }
+}
+
+/// Whether a location lies within a span. A cursor just past the end of a token still counts.
+fn contains(span: &Span, loc: &Loc) -> bool {
+    let position = (loc.line, loc.col);
+    (span.start.line, span.start.col) <= position && position <= (span.end.line, span.end.col)
+}
+
+fn lsp_range(span: &Span) -> Json {
+    json!({
+        "start": { "line": span.start.line, "character": span.start.col },
+        "end": { "line": span.end.line, "character": span.end.col },
+    })
+}
+
+fn lsp_diagnostic(diagnostic: &Diagnostic, severity: u64) -> Json {
+    let range = match &diagnostic.span {
+        Some(span) => lsp_range(span),
+        None => json!({
+            "start": { "line": 0, "character": 0 },
+            "end": { "line": 0, "character": 0 },
+        }),
+    };
+    let mut message = diagnostic.message.clone();
+    for note in diagnostic.notes.iter() {
+        message.push_str(&format!("\nnote: {}", note));
+    }
+    json!({
+        "range": range,
+        "severity": severity,
+        "code": diagnostic.code,
+        "source": "quail",
+        "message": message,
+    })
+}
+
+fn publish_diagnostics(uri: &str, diagnostics: Vec<Json>) -> Json {
+    json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/publishDiagnostics",
+        "params": { "uri": uri, "diagnostics": diagnostics },
+    })
+}
+
+fn uri_to_path(uri: &str) -> String {
+    let path = uri.strip_prefix("file://").unwrap_or(uri);
+    let mut bytes = vec![];
+    let mut chars = path.bytes();
+    while let Some(byte) = chars.next() {
+        if byte == b'%' {
+            let hex: Vec<u8> = chars.by_ref().take(2).collect();
+            match std::str::from_utf8(&hex).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                Some(decoded) => bytes.push(decoded),
+                None => {
+                    bytes.push(byte);
+                    bytes.extend(hex);
+                },
+            }
+        } else {
+            bytes.push(byte);
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+fn path_to_uri(path: &str) -> String {
+    format!("file://{}", path.replace('%', "%25").replace(' ', "%20"))
+}
+
+/// Reads one JSON-RPC message framed by a Content-Length header. Returns None at the end of input.
+pub fn read_message(reader: &mut dyn BufRead) -> io::Result<Option<Json>> {
+    let mut content_length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            content_length = value.trim().parse::<usize>().ok();
+        }
+    }
+
+    let content_length = content_length
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Missing Content-Length header"))?;
+    let mut content = vec![0; content_length];
+    reader.read_exact(&mut content)?;
+    let message = serde_json::from_slice(&content).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+    Ok(Some(message))
+}
+
+pub fn write_message(writer: &mut dyn Write, message: &Json) -> io::Result<()> {
+    let content = message.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{}", content.len(), content)?;
+    writer.flush()
+}
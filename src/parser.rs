@@ -1,6 +1,16 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use std::fmt;
+
+use num_traits::ToPrimitive;
+
+use crate::tokenizer::Loc;
+use crate::tokenizer::Span;
 use crate::tokenizer::Token;
 use crate::tokenizer::Tokenizer;
+use crate::tokenizer::TokenizeErr;
 use crate::ast;
+use crate::diagnostic::Diagnostic;
 
 use ast::HoleId;
 use ast::HoleInfo;
@@ -10,17 +20,35 @@ use ast::Module;
 use ast::MatchArm;
 use ast::Def;
 use ast::Import;
+use ast::TypeDecl;
+use ast::CtorDecl;
+use ast::Flavor;
 use ast::Pattern;
 use ast::Variable;
 use ast::Type;
 use ast::TypeNode;
 
-pub type ParseErr = String;
+#[derive(Debug, Clone)]
+pub enum ParseErr {
+    /// The input couldn't be broken up into tokens.
+    Tokenize { span: Span, message: String },
+    /// The parser found a token other than the one it expected.
+    UnexpectedToken { span: Span, expected: String, found: Box<Token> },
+    /// The input ended while the parser still expected something.
+    UnexpectedEnd { span: Span, expected: String },
+    /// A type variable was written with a name which doesn't begin with a lowercase letter.
+    BadTypeVariable { span: Span, name: String },
+}
+
+/// Hole ids are handed out from a single counter so that holes remain distinct
+/// across every module and REPL line parsed during the lifetime of the process.
+static NEXT_HOLE_ID: AtomicUsize = AtomicUsize::new(0);
 
 struct Parser {
     tokens: Vec<Token>,
+    spans: Vec<Span>,
+    source: Option<String>,
     cur: usize,
-    next_hole_id: HoleId,
     hole_count: u64,
 }
 
@@ -31,22 +59,63 @@ macro_rules! consume_expected_token {
             Some(Token::$ctor(_)) => {
                 $tokenizer.consume();
             },
-            Some(peek_token) => return Err(format!("Expected {:?} but found {:?}.", expected_token, peek_token)),
-            None => return Err(format!("Expected {:?} but found end of input.", expected_token)),
+            _ => return Err($tokenizer.unexpected(&format!("{:?}", expected_token))),
         }
     }
 }
 
 impl Parser {
-    pub fn new(tokens: Vec<Token>) -> Self {
+    pub fn new(source: Option<String>, tokens: Vec<(Token, Span)>) -> Self {
+        let (tokens, spans) = tokens.into_iter().unzip();
         Parser {
             tokens,
+            spans,
+            source,
             cur: 0,
-            next_hole_id: 0,
             hole_count: 0,
         }
     }
 
+    /// The location of the next token, or else the end of the input.
+    fn next_loc(&self) -> Loc {
+        match self.spans.get(self.cur) {
+            Some(span) => span.start.clone(),
+            None => self.end_loc(),
+        }
+    }
+
+    fn end_loc(&self) -> Loc {
+        match self.spans.last() {
+            Some(span) => span.end.clone(),
+            None => Loc::new(self.source.clone()),
+        }
+    }
+
+    /// The span from the given location up to the end of the last token consumed.
+    fn span_from(&self, start: Loc) -> Span {
+        let end = if self.cur == 0 {
+            start.clone()
+        } else {
+            self.spans[self.cur - 1].end.clone()
+        };
+        Span::new(start, end)
+    }
+
+    /// Builds an error for when the next token isn't what was expected.
+    fn unexpected(&self, expected: &str) -> ParseErr {
+        match self.tokens.get(self.cur) {
+            Some(token) => ParseErr::UnexpectedToken {
+                span: self.spans[self.cur].clone(),
+                expected: expected.to_string(),
+                found: Box::new(token.clone()),
+            },
+            None => ParseErr::UnexpectedEnd {
+                span: Span::new(self.end_loc(), self.end_loc()),
+                expected: expected.to_string(),
+            },
+        }
+    }
+
     fn peek(&mut self) -> Option<Token> {
         self.peek_ahead(0)
     }
@@ -69,10 +138,12 @@ impl Parser {
     }
 
     fn consume_identifier(&mut self) -> Result<String, ParseErr> {
-        match self.consume() {
-            Some(Token::Ident(_, name)) => Ok(name),
-            Some(token) => Err(format!("Expected identifier but found {:?}.", token)),
-            None => Err("Expected identifier but found end of input.".into()),
+        match self.peek() {
+            Some(Token::Ident(_, name)) => {
+                self.consume();
+                Ok(name)
+            },
+            _ => Err(self.unexpected("identifier")),
         }
     }
 
@@ -87,49 +158,55 @@ impl Parser {
             }
         }
         if idents.is_empty() {
-            Err("Expected identifier".into())
+            Err(self.unexpected("identifier"))
         } else {
             Ok(idents)
         }
     }
 
     fn parse_lambda(&mut self) -> Result<Term, ParseErr> {
+        let start = self.next_loc();
         consume_expected_token!(self, Lambda, "fun");
         let bind_vars = self.consume_identifier_plus()?;
         consume_expected_token!(self, FatArrow, "=>");
         let body = self.parse_term()?;
+        let span = self.span_from(start);
 
         let mut term = body;
         for bind_var in bind_vars.into_iter().rev() {
-            term = TermNode::Lam(bind_var, term).into();
+            term = Term::from(TermNode::Lam(bind_var, term)).with_span(span.clone());
         }
         Ok(term)
     }
 
     fn parse_variable(&mut self) -> Result<Term, ParseErr> {
+        let start = self.next_loc();
         let name = self.consume_identifier()?;
         if let Some(Token::Dollar(_)) = self.peek() {
             if let Some(Token::Nat(_, k)) = self.peek_ahead(1) {
                 self.consume();
+                let k = k.to_usize().ok_or_else(|| self.unexpected("a smaller number after $"))?;
                 self.consume();
                 let variable = Variable {
                     name,
                     layer: k,
                 };
-                Ok(TermNode::Var(variable).into())
+                Ok(Term::from(TermNode::Var(variable)).with_span(self.span_from(start)))
             } else {
-                Err("Expected a number after $.".to_string())
+                self.consume();
+                Err(self.unexpected("a number after $"))
             }
         } else {
             let variable = Variable {
                 name,
                 layer: 0,
             };
-            Ok(TermNode::Var(variable).into())
+            Ok(Term::from(TermNode::Var(variable)).with_span(self.span_from(start)))
         }
     }
 
     fn parse_term_part(&mut self) -> Result<Option<Term>, ParseErr> {
+        let start = self.next_loc();
         match self.peek() {
             Some(token) => match token {
                 Token::Ident(_, _name) => {
@@ -150,19 +227,23 @@ impl Parser {
                     let value = self.parse_term()?;
                     consume_expected_token!(self, In, "in");
                     let body = self.parse_term()?;
-                    Ok(Some(TermNode::Let(bind_var, value, body).into()))
+                    let term = Term::from(TermNode::Let(bind_var, value, body));
+                    Ok(Some(term.with_span(self.span_from(start))))
                 },
                 Token::Hole(loc, name, contents) => {
                     self.consume();
-                    Ok(Some(TermNode::Hole(HoleInfo::new(self.generate_hole_id(), name, contents, loc)).into()))
+                    let term = Term::from(TermNode::Hole(HoleInfo::new(self.generate_hole_id(), name, contents, loc)));
+                    Ok(Some(term.with_span(self.span_from(start))))
                 },
                 Token::Str(_loc, contents) => {
                     self.consume();
-                    Ok(Some(TermNode::StrLit(contents).into()))
+                    let term = Term::from(TermNode::StrLit(contents));
+                    Ok(Some(term.with_span(self.span_from(start))))
                 },
-                Token::Nat(_loc, contents) => {
+                Token::Nat(_loc, n) => {
                     self.consume();
-                    Ok(Some(usize_to_nat_term(contents).into()))
+                    let term = Term::from(TermNode::NatLit(n));
+                    Ok(Some(term.with_span(self.span_from(start))))
                 },
                 _ => Ok(None),
             },
@@ -171,8 +252,7 @@ impl Parser {
     }
 
     fn generate_hole_id(&mut self) -> HoleId {
-        let hole_id = self.next_hole_id;
-        self.next_hole_id += 1;
+        let hole_id = NEXT_HOLE_ID.fetch_add(1, Ordering::SeqCst);
         self.hole_count += 1;
         hole_id
     }
@@ -183,10 +263,12 @@ impl Parser {
 
     fn parse_match_arm(&mut self) -> Result<MatchArm, ParseErr> {
         consume_expected_token!(self, With, "with");
+        let start = self.next_loc();
         let idents = self.parse_pattern()?;
+        let pattern_span = self.span_from(start);
         consume_expected_token!(self, FatArrow, "=>");
         let body = self.parse_term()?;
-        Ok(MatchArm(idents, body))
+        Ok(MatchArm(idents, body, pattern_span))
     }
 
     fn parse_match_arm_star(&mut self) -> Result<Vec<MatchArm>, ParseErr> {
@@ -198,13 +280,15 @@ impl Parser {
     }
 
     fn parse_match(&mut self) -> Result<Term, ParseErr> {
+        let start = self.next_loc();
         consume_expected_token!(self, Match, "match");
         let discriminee = self.parse_term()?;
         let match_arms = self.parse_match_arm_star()?;
-        Ok(TermNode::Match(discriminee, match_arms).into())
+        Ok(Term::from(TermNode::Match(discriminee, match_arms)).with_span(self.span_from(start)))
     }
 
     fn parse_type_part(&mut self) -> Result<Type, ParseErr> {
+        let start = self.next_loc();
         match self.peek() {
             Some(Token::LeftParen(_)) => {
                 consume_expected_token!(self, LeftParen, "(");
@@ -214,37 +298,93 @@ impl Parser {
             },
             Some(Token::Ident(_, _name)) => {
                 let ident = self.consume_identifier()?;
-                Ok(TypeNode::Atom(ident).into())
+                let typ = if is_type_variable_name(&ident) {
+                    Type::from(TypeNode::Var(ident))
+                } else {
+                    Type::from(TypeNode::Atom(ident))
+                };
+                Ok(typ.with_span(self.span_from(start)))
             },
-            None => Err("Expected '(' or identifier, but found end of input".to_string()),
-            _ => Err(format!("Expected '(' or identifier, but found {:?}", self.peek().unwrap())),
+            _ => Err(self.unexpected("'(' or identifier")),
+        }
+    }
+
+    /// Parses a type constructor applied to its arguments, such as `List Nat`.
+    fn parse_type_app(&mut self) -> Result<Type, ParseErr> {
+        let start = self.next_loc();
+        let head = self.parse_type_part()?;
+        if let TypeNode::Atom(name) = head.as_ref() {
+            let mut args = Vec::new();
+            while let Some(Token::Ident(_, _)) | Some(Token::LeftParen(_)) = self.peek() {
+                args.push(self.parse_type_part()?);
+            }
+
+            if !args.is_empty() {
+                return Ok(Type::from(TypeNode::App(name.clone(), args)).with_span(self.span_from(start)));
+            }
+        }
+        Ok(head)
+    }
+
+    fn parse_forall(&mut self) -> Result<Type, ParseErr> {
+        let start = self.next_loc();
+        consume_expected_token!(self, Forall, "forall");
+        let vars_start = self.next_loc();
+        let type_vars = self.consume_identifier_plus()?;
+        let vars_span = self.span_from(vars_start);
+        consume_expected_token!(self, Dot, ".");
+        let body = self.parse_type()?;
+        let span = self.span_from(start);
+
+        let mut typ = body;
+        for type_var in type_vars.into_iter().rev() {
+            if !is_type_variable_name(&type_var) {
+                return Err(ParseErr::BadTypeVariable { span: vars_span, name: type_var });
+            }
+            typ = Type::from(TypeNode::Forall(type_var, typ)).with_span(span.clone());
         }
+        Ok(typ)
     }
 
     fn parse_type(&mut self) -> Result<Type, ParseErr> {
-        let mut type_parts = vec![self.parse_type_part()?];
+        if let Some(Token::Forall(_)) = self.peek() {
+            return self.parse_forall();
+        }
+
+        let mut type_parts = vec![self.parse_type_app()?];
         while let Some(Token::Arrow(_)) = self.peek() {
             consume_expected_token!(self, Arrow, "->");
-            type_parts.push(self.parse_type_part()?);
+            if let Some(Token::Forall(_)) = self.peek() {
+                type_parts.push(self.parse_forall()?);
+                break;
+            }
+            type_parts.push(self.parse_type_app()?);
         }
 
         type_parts.reverse();
 
         let (first, rest) = type_parts.split_first().unwrap();
-        let term: Type = rest.to_vec().iter().fold(first.clone(), |acc, cod| TypeNode::Arrow(cod.clone(), acc.clone()).into());
-        Ok(term)
+        let typ: Type = rest.iter().fold(first.clone(), |acc, dom| {
+            let typ = Type::from(TypeNode::Arrow(dom.clone(), acc.clone()));
+            match (dom.span(), acc.span()) {
+                (Some(dom_span), Some(acc_span)) => typ.with_span(dom_span.to(acc_span)),
+                _ => typ,
+            }
+        });
+        Ok(typ)
     }
 
     fn parse_term(&mut self) -> Result<Term, ParseErr> {
         if let Some(Token::Match(_)) = self.peek() {
             self.parse_match()
         } else {
+            let start = self.next_loc();
             let func;
             let mut args = Vec::new();
 
             match self.parse_term_part()? {
                 None => {
-                    return Err("Empty input".to_string());
+                    return Err(self.unexpected("a term"));
                 },
                 Some(term_part) => {
                     func = term_part;
@@ -258,13 +398,13 @@ impl Parser {
             let mut term = if args.is_empty() {
                 func
             } else {
-                TermNode::App(func, args).into()
+                Term::from(TermNode::App(func, args)).with_span(self.span_from(start.clone()))
             };
 
             if let Some(Token::As(_)) = self.peek() {
                 consume_expected_token!(self, As, "as");
                 let typ = self.parse_type()?;
-                term = TermNode::As(term, typ).into();
+                term = Term::from(TermNode::As(term, typ)).with_span(self.span_from(start));
             }
 
             Ok(term)
@@ -272,24 +412,78 @@ impl Parser {
     }
 
     fn parse_def(&mut self) -> Result<Def, ParseErr> {
+        let start = self.next_loc();
         consume_expected_token!(self, Def, "def");
         let binding_name = self.consume_identifier()?;
         consume_expected_token!(self, Colon, ":");
+        // Type variables which are not bound by a forall are quantified over the whole signature.
         let typ = self.parse_type()?;
+        let typ = match typ.span() {
+            Some(span) => typ.generalize().with_span(span.clone()),
+            None => typ.generalize(),
+        };
         consume_expected_token!(self, Equals, "=");
         let body = self.parse_term()?;
-        Ok(Def(binding_name.to_string(), typ, body))
+        Ok(Def(binding_name.to_string(), typ, body, self.span_from(start)))
     }
 
     fn parse_import(&mut self) -> Result<Import, ParseErr> {
+        let start = self.next_loc();
         consume_expected_token!(self, Import, "import");
         let import_name = self.consume_identifier()?;
-        Ok(Import(import_name))
+        Ok(Import(import_name, self.span_from(start)))
+    }
+
+    fn parse_type_decl(&mut self) -> Result<TypeDecl, ParseErr> {
+        let start = self.next_loc();
+        let (flavor, line) = match self.peek() {
+            Some(Token::Inductive(loc)) => (Flavor::Inductive, loc.line),
+            Some(Token::Coinductive(loc)) => (Flavor::Coinductive, loc.line),
+            _ => return Err(self.unexpected("\"inductive\" or \"coinductive\"")),
+        };
+        self.consume();
+        let type_name = self.consume_identifier()?;
+
+        let mut params = Vec::new();
+        while let Some(Token::Ident(loc, name)) = self.peek() {
+            if loc.line != line {
+                break;
+            } else if !is_type_variable_name(&name) {
+                return Err(ParseErr::BadTypeVariable { span: self.spans[self.cur].clone(), name });
+            }
+            self.consume();
+            params.push(name);
+        }
+        let span = self.span_from(start);
+
+        let mut ctor_decls = Vec::new();
+        while let Some(Token::Ident(loc, _name)) = self.peek() {
+            ctor_decls.push(self.parse_ctor_decl(loc.line)?);
+        }
+        Ok(TypeDecl(flavor, type_name, params, ctor_decls, span))
+    }
+
+    /// Parses a constructor declaration. The argument types of a constructor extend
+    /// to the end of the line the constructor's name appears on.
+    fn parse_ctor_decl(&mut self, line: usize) -> Result<CtorDecl, ParseErr> {
+        let start = self.next_loc();
+        let tag = self.consume_identifier()?;
+        let mut arg_types = Vec::new();
+        while let Some(token) = self.peek() {
+            match token {
+                Token::Ident(_, _) | Token::LeftParen(_) if token.loc().line == line => {
+                    arg_types.push(self.parse_type_part()?);
+                },
+                _ => break,
+            }
+        }
+        Ok(CtorDecl(tag, arg_types, self.span_from(start)))
     }
 
     fn parse_module(&mut self) -> Result<Module, ParseErr> {
         let mut definitions = Vec::new();
         let mut imports = Vec::new();
+        let mut type_decls = Vec::new();
 
         while let Some(token) = self.peek() {
             match token {
@@ -301,20 +495,24 @@ impl Parser {
                     let import = self.parse_import()?;
                     imports.push(import);
                 },
+                Token::Inductive(_) | Token::Coinductive(_) => {
+                    let type_decl = self.parse_type_decl()?;
+                    type_decls.push(type_decl);
+                },
                 _ => {
-                    return Err(format!("Expected an item declaration, found {:?}", token));
+                    return Err(self.unexpected("an item declaration"));
                 },
             }
         }
-        Ok(Module::new(definitions, imports))
+        Ok(Module::new(definitions, imports, type_decls))
     }
 }
 
 pub fn parse_variable(source: Option<String>, input: &str) -> Result<Variable, ParseErr> {
-    let mut toker = Tokenizer::new(source, input);
-    let tokens = toker.tokenize()?;
+    let mut toker = Tokenizer::new(source.clone(), input);
+    let tokens = toker.tokenize_spans()?;
 
-    let mut parser = Parser::new(tokens);
+    let mut parser = Parser::new(source, tokens);
 
     if let TermNode::Var(var) = parser.parse_variable()?.as_node() {
         Ok(var.clone())
@@ -324,60 +522,97 @@ pub fn parse_variable(source: Option<String>, input: &str) -> Result<Variable, P
 }
 
 pub fn parse_term(source: Option<String>, input: &str) -> Result<Term, ParseErr> {
-    let mut toker = Tokenizer::new(source, input);
-    let tokens = toker.tokenize()?;
+    let mut toker = Tokenizer::new(source.clone(), input);
+    let tokens = toker.tokenize_spans()?;
 
-    let mut parser = Parser::new(tokens);
+    let mut parser = Parser::new(source, tokens);
 
     let term = parser.parse_term()?;
     Ok(term)
 }
 
 pub fn parse_module(source: Option<String>, input: &str) -> Result<Module, ParseErr> {
-    let mut toker = Tokenizer::new(source, input);
-    let tokens = toker.tokenize()?;
+    let mut toker = Tokenizer::new(source.clone(), input);
+    let tokens = toker.tokenize_spans()?;
 
-    let mut parser = Parser::new(tokens);
+    let mut parser = Parser::new(source, tokens);
 
     let module = parser.parse_module()?;
     Ok(module)
 }
 
 pub fn parse_import(source: Option<String>, input: &str) -> Result<Import, ParseErr> {
-    let mut toker = Tokenizer::new(source, input);
-    let tokens = toker.tokenize()?;
+    let mut toker = Tokenizer::new(source.clone(), input);
+    let tokens = toker.tokenize_spans()?;
 
-    let mut parser = Parser::new(tokens);
+    let mut parser = Parser::new(source, tokens);
     parser.parse_import()
 }
 
 pub fn parse_def(source: Option<String>, input: &str) -> Result<Def, ParseErr> {
-    let mut toker = Tokenizer::new(source, input);
-    let tokens = toker.tokenize()?;
+    let mut toker = Tokenizer::new(source.clone(), input);
+    let tokens = toker.tokenize_spans()?;
 
-    let mut parser = Parser::new(tokens);
+    let mut parser = Parser::new(source, tokens);
     parser.parse_def()
 }
 
 pub fn parse_type(source: Option<String>, input: &str) -> Result<Type, ParseErr> {
-    let mut toker = Tokenizer::new(source, input);
-    let tokens = toker.tokenize()?;
+    let mut toker = Tokenizer::new(source.clone(), input);
+    let tokens = toker.tokenize_spans()?;
 
-    let mut parser = Parser::new(tokens);
+    let mut parser = Parser::new(source, tokens);
 
     let term = parser.parse_type()?;
     Ok(term)
 }
 
-fn usize_to_nat_term(v: usize) -> Term {
-    let mut result: Term = TermNode::Var(Variable { name: "zero".to_owned(), layer: 0 }).into();
+impl ParseErr {
+    pub fn span(&self) -> &Span {
+        match self {
+            ParseErr::Tokenize { span, .. } => span,
+            ParseErr::UnexpectedToken { span, .. } => span,
+            ParseErr::UnexpectedEnd { span, .. } => span,
+            ParseErr::BadTypeVariable { span, .. } => span,
+        }
+    }
 
-    for _ in 0..v {
-        result = TermNode::App(
-            TermNode::Var(Variable { name: "succ".to_owned(), layer: 0 }).into(),
-            vec![result.clone()],
-        ).into();
+    /// A short name for the kind of error, used to identify it in machine-readable output.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ParseErr::Tokenize { .. } => "tokenize",
+            ParseErr::UnexpectedToken { .. } => "unexpected-token",
+            ParseErr::UnexpectedEnd { .. } => "unexpected-end",
+            ParseErr::BadTypeVariable { .. } => "bad-type-variable",
+        }
+    }
+
+    pub fn diagnostic(&self) -> Diagnostic {
+        Diagnostic::new(self.code(), self.to_string(), Some(self.span().clone()))
     }
+}
+
+impl fmt::Display for ParseErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseErr::Tokenize { span: _, message } => write!(f, "{}", message),
+            ParseErr::UnexpectedToken { span: _, expected, found } => write!(f, "Expected {} but found {}.", expected, found.show()),
+            ParseErr::UnexpectedEnd { span: _, expected } => write!(f, "Expected {} but found end of input.", expected),
+            ParseErr::BadTypeVariable { span: _, name } => write!(f, "Type variables must begin with a lowercase letter: {}", name),
+        }
+    }
+}
+
+impl From<TokenizeErr> for ParseErr {
+    fn from(error: TokenizeErr) -> Self {
+        ParseErr::Tokenize {
+            span: Span::new(error.loc.clone(), error.loc),
+            message: error.message,
+        }
+    }
+}
 
-    result
+/// Type variables are distinguished from the names of types by beginning with a lowercase letter.
+fn is_type_variable_name(name: &str) -> bool {
+    name.chars().next().is_some_and(|ch| ch.is_lowercase())
 }
@@ -42,7 +42,7 @@ impl ImportResolver for FileImportResolver {
 
         Ok(ResolvedImport {
             reader: Box::new(io::Cursor::new(module_text)),
-            source: filename,
+            source: filepath.to_string_lossy().into_owned(),
         })
     }
 }
@@ -0,0 +1,129 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+use std::time::Instant;
+
+/// How often, in steps, the clock is consulted. Reading it on every step would be needlessly slow.
+const STEPS_PER_CLOCK_CHECK: u64 = 1024;
+
+///
+/// Limits on how much work evaluation may do before it is cut short. A limit of None is no limit
+/// at all, which is the default. The budget is spent afresh by each top-level evaluation, such as
+/// running `main`, or evaluating a term at the REPL.
+///
+#[derive(Debug, Clone, Default)]
+pub struct Budget {
+    /// The most reduction steps evaluation may take.
+    pub steps: Option<u64>,
+    /// The longest evaluation may take, as measured by the wall clock.
+    pub time: Option<Duration>,
+    /// The most values, such as thunks, closures and constructors, evaluation may allocate.
+    /// The Nats and Strs made by prims count as a value for every eight bytes they take up.
+    pub values: Option<u64>,
+}
+
+/// The resources a Budget limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resource {
+    Steps,
+    Time,
+    Values,
+}
+
+/// Keeps track of what has been spent during the evaluation in progress.
+#[derive(Debug)]
+pub(crate) struct Meter {
+    steps: u64,
+    values: u64,
+    started: Instant,
+}
+
+impl Meter {
+    pub(crate) fn new() -> Self {
+        Meter {
+            steps: 0,
+            values: 0,
+            started: Instant::now(),
+        }
+    }
+
+    /// Spends a reduction step, and checks that neither steps nor time have run out.
+    pub(crate) fn step(&mut self, budget: &Budget) -> Result<(), Resource> {
+        self.steps += 1;
+        if budget.steps.is_some_and(|steps| self.steps > steps) {
+            return Err(Resource::Steps);
+        }
+        if self.steps.is_multiple_of(STEPS_PER_CLOCK_CHECK) {
+            if let Some(time) = budget.time {
+                if self.started.elapsed() > time {
+                    return Err(Resource::Time);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Spends `count` newly allocated values.
+    pub(crate) fn allocate(&mut self, count: usize, budget: &Budget) -> Result<(), Resource> {
+        self.values += count as u64;
+        if budget.values.is_some_and(|values| self.values > values) {
+            return Err(Resource::Values);
+        }
+        Ok(())
+    }
+}
+
+impl Budget {
+    /// The limit on the given resource, written out for people to read.
+    pub fn describe(&self, resource: Resource) -> String {
+        match resource {
+            Resource::Steps => self.steps.map_or("unlimited".to_string(), |steps| format!("{} steps", steps)),
+            Resource::Time => self.time.map_or("unlimited".to_string(), |time| format!("{:?}", time)),
+            Resource::Values => self.values.map_or("unlimited".to_string(), |values| format!("{} values", values)),
+        }
+    }
+
+    /// Sets the limit on the given resource from its text, which is either a number or `none`.
+    /// Time is given in seconds.
+    pub fn set(&mut self, resource: Resource, limit: &str) -> Result<(), String> {
+        let limit = if limit == "none" { None } else { Some(limit) };
+        let count = |limit: &str| limit.parse::<u64>().map_err(|_| format!("Expected a whole number of {}, but found {:?}", resource, limit));
+        match resource {
+            Resource::Steps => self.steps = limit.map(count).transpose()?,
+            Resource::Time => self.time = limit.map(parse_seconds).transpose()?,
+            Resource::Values => self.values = limit.map(count).transpose()?,
+        }
+        Ok(())
+    }
+}
+
+/// Parses a number of seconds, such as `2` or `0.5`, into a Duration.
+pub fn parse_seconds(text: &str) -> Result<Duration, String> {
+    match text.parse::<f64>() {
+        Ok(seconds) if seconds.is_finite() && seconds >= 0.0 => Ok(Duration::from_secs_f64(seconds)),
+        _ => Err(format!("Expected a number of seconds, but found {:?}", text)),
+    }
+}
+
+impl FromStr for Resource {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "steps" => Ok(Resource::Steps),
+            "time" => Ok(Resource::Time),
+            "values" => Ok(Resource::Values),
+            _ => Err(format!("Unknown resource {:?}. Try steps, time, or values.", name)),
+        }
+    }
+}
+
+impl fmt::Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Resource::Steps => write!(f, "steps"),
+            Resource::Time => write!(f, "time"),
+            Resource::Values => write!(f, "values"),
+        }
+    }
+}
@@ -0,0 +1,52 @@
+use crate::parser;
+use crate::types::check;
+
+use super::Runtime;
+use super::RuntimeError;
+use super::Value;
+use super::builtins::PrimDef;
+use super::builtins::PrimCode;
+
+///
+/// Builds a Runtime for a program which embeds Quail. Besides the usual builtins, the Runtime
+/// has the prims given by the host, each with its own Quail type, as in
+/// `Runtime::builder().prim("double", "Nat -> Nat", double).build()`.
+///
+#[derive(Default)]
+pub struct RuntimeBuilder {
+    /// The name, type and code of each prim, in the order they were added.
+    prims: Vec<(String, String, PrimCode)>,
+}
+
+impl RuntimeBuilder {
+    pub fn new() -> Self {
+        RuntimeBuilder::default()
+    }
+
+    ///
+    /// Adds a prim with the given name and type, written in Quail syntax. Like the builtin prims,
+    /// the code is called with its arguments completely evaluated. The type is only checked
+    /// when the Runtime is built.
+    ///
+    pub fn prim<F>(mut self, name: &str, type_str: &str, code: F) -> Self
+    where
+        F: Fn(&mut Runtime, Vec<Value>) -> Result<Value, RuntimeError> + 'static,
+    {
+        self.prims.push((name.to_string(), type_str.to_string(), Box::new(code)));
+        self
+    }
+
+    /// Makes the Runtime, checking that the type of each prim is well-formed and that no two builtins share a name.
+    pub fn build(self) -> Result<Runtime, RuntimeError> {
+        let mut runtime = Runtime::new();
+        for (name, type_str, code) in self.prims {
+            if runtime.builtin_type_ctx.lookup(&name, 0).is_some() {
+                return Err(RuntimeError::from(format!("There is already a builtin named {}", name)));
+            }
+            let typ = parser::parse_type(Some(format!("<prim {}>", name)), &type_str)?;
+            check::check_type_wellformed(&typ, &runtime.inductive_typedefs)?;
+            runtime.add_primdef(PrimDef::new(name, typ, code));
+        }
+        Ok(runtime)
+    }
+}
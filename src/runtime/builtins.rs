@@ -6,23 +6,24 @@ use crate::context::Context;
 use crate::runtime::Value;
 use crate::ast::Tag;
 use crate::runtime::Runtime;
+use crate::runtime::RuntimeError;
 use crate::ast::Type;
 use crate::ast::TypeNode;
+use crate::ast::TypeDecl;
+use crate::ast::CtorDecl;
+use crate::parser;
 
-#[derive(Debug, Clone)]
-pub enum Flavor {
-    Inductive,
-    Coinductive,
-}
+pub use crate::ast::Flavor;
 
 #[derive(Debug, Clone)]
 pub struct TypeDef {
     pub name: String,
+    pub params: Vec<String>,
     pub flavor: Flavor,
     pub ctor_types: HashMap<Tag, Type>,
 }
 
-type PrimCode = Box<dyn Fn(&mut Runtime, Vec<Value>) -> Value>;
+pub type PrimCode = Box<dyn Fn(&mut Runtime, Vec<Value>) -> Result<Value, RuntimeError>>;
 
 pub struct PrimDef {
     pub name: String,
@@ -46,21 +47,38 @@ impl TypeDef {
     /// Creates a new InductiveTypeDef from a name and a list of pairs (tagname, signature).
     /// A signature means the types arguments to the constructor strung together (omitting
     /// the return value, which is inferred to be the inductive type itself).. For example,
-    /// if our constructor is `cons`, we would include ("cons", ["Nat", "List"]).
+    /// if our constructor is `cons`, we would include ("cons", ["a", "List a"]).
     ///
-    pub fn new(name: &str, flavor: Flavor, ctor_signatures: &[(Tag, Vec<Type>)]) -> Self {
+    /// A parameterized type, such as `List a`, lists its type parameters in `params`.
+    /// Each constructor is then polymorphic in those parameters, so that `cons` has the type
+    /// `forall a. a -> List a -> List a`.
+    ///
+    pub fn new(name: &str, params: &[String], flavor: Flavor, ctor_signatures: &[(Tag, Vec<Type>)]) -> Self {
         let mut ctor_types = HashMap::new();
-        for (tag, typ) in ctor_signatures.into_iter().map(|(tag, sig)| (tag, ctor_type_from_signature(&name, &sig))) {
+        for (tag, typ) in ctor_signatures.iter().map(|(tag, sig)| (tag, ctor_type_from_signature(name, params, sig))) {
             ctor_types.insert(tag.to_string(), typ);
         }
 
         TypeDef {
             name: name.to_string(),
+            params: params.to_vec(),
             flavor,
             ctor_types,
         }
     }
 
+    ///
+    /// The type of values of this type, with the given types substituted for its parameters.
+    ///
+    pub fn applied_to(&self, args: Vec<Type>) -> Type {
+        assert_eq!(self.params.len(), args.len());
+        if args.is_empty() {
+            TypeNode::Atom(self.name.to_string()).into()
+        } else {
+            TypeNode::App(self.name.to_string(), args).into()
+        }
+    }
+
     ///
     /// Create a value-level context containing the constructors for this inductive type.
     ///
@@ -94,8 +112,26 @@ impl TypeDef {
     pub fn ctor_tags(&self) -> Vec<Tag> {
         self.ctor_types.keys().cloned().collect()
     }
+
+    ///
+    /// Return the number of arguments the constructor with the given tag takes,
+    /// or None if it isn't one of this type's constructors.
+    ///
+    pub fn ctor_arity(&self, tag: &str) -> Option<usize> {
+        self.ctor_types.get(tag).map(arity)
+    }
 }
 
+impl From<&TypeDecl> for TypeDef {
+    fn from(type_decl: &TypeDecl) -> Self {
+        let TypeDecl(flavor, name, params, ctor_decls, _span) = type_decl;
+        let ctor_signatures: Vec<(Tag, Vec<Type>)> = ctor_decls
+            .iter()
+            .map(|CtorDecl(tag, arg_types, _span)| (tag.to_string(), arg_types.clone()))
+            .collect();
+        TypeDef::new(name, params, flavor.clone(), &ctor_signatures)
+    }
+}
 
 impl PrimDef {
     pub fn new(name: String, typ: Type, code: PrimCode) -> Self {
@@ -107,58 +143,51 @@ impl PrimDef {
     }
 }
 
-fn ctor_type_from_signature(name: &str, ctor_signature: &[Type]) -> Type {
-    let mut typ: Type = TypeNode::Atom(name.to_string()).into();
-    for sig_typ in ctor_signature.iter().rev() {
-        typ = TypeNode::Arrow(sig_typ.clone(), typ).into();
+///
+/// The number of arguments a value of the given type takes before it gives back something
+/// which isn't a function.
+///
+pub fn arity(typ: &Type) -> usize {
+    let mut typ = typ;
+    while let TypeNode::Forall(_var, body) = typ.as_ref() {
+        typ = body;
     }
-    typ
+
+    let mut arity = 0;
+    while let TypeNode::Arrow(_dom, cod) = typ.as_ref() {
+        arity += 1;
+        typ = cod;
+    }
+    arity
 }
 
-pub fn parse_typedef(types_text_lines: &mut dyn Iterator<Item=&str>) -> Option<TypeDef> {
-    let first_line = types_text_lines.next()?;
-    let flavor = match first_line.split(" ").next()? {
-        "inductive" => Flavor::Inductive,
-        "coinductive" => Flavor::Coinductive,
-        _ => panic!("Illegal flavor in type declaration: {}", first_line),
+fn ctor_type_from_signature(name: &str, params: &[String], ctor_signature: &[Type]) -> Type {
+    let mut typ: Type = if params.is_empty() {
+        TypeNode::Atom(name.to_string()).into()
+    } else {
+        let args = params.iter().map(|param| TypeNode::Var(param.to_string()).into()).collect();
+        TypeNode::App(name.to_string(), args).into()
     };
 
-    let mut ctors: Vec<(String, Vec<Type>)> = Vec::new();
-
-    while let Some(line) = types_text_lines.next() {
-        let line = line.trim();
-        if line == "" {
-            break;
-        }
-
-        let parts: Vec<&str> = line.split(" ").collect();
-        let (ctor_name, arg_type_names) = parts.split_first()?;
-
-        let mut arg_types = Vec::new();
-        for arg_type_name in arg_type_names {
-            arg_types.push(TypeNode::Atom(arg_type_name.to_string()).into());
-        }
-
-        let ctor = (ctor_name.to_string(), arg_types);
-        ctors.push(ctor);
+    for sig_typ in ctor_signature.iter().rev() {
+        typ = TypeNode::Arrow(sig_typ.clone(), typ).into();
     }
 
-    let name = first_line.split(" ").last()?.trim();
-    let typedef = TypeDef::new(name, flavor, ctors.as_slice());
-    Some(typedef)
+    for param in params.iter().rev() {
+        typ = TypeNode::Forall(param.to_string(), typ).into();
+    }
+    typ
 }
 
 ///
 /// Returns a list of inductive typedefs which are considered "built-in" in Quail.
 ///
 pub fn builtin_inductive_typedefs() -> Vec<TypeDef> {
-    let mut typedefs = vec![];
-    let mut types_text_lines = include_str!("../../assets/types.txt").lines();
-    while let Some(typedef) = parse_typedef(&mut types_text_lines) {
-        typedefs.push(typedef);
-    }
+    let types_text = include_str!("../../assets/types.txt");
+    let module = parser::parse_module(Some("types.txt".to_string()), types_text)
+        .expect("Builtin types should parse");
 
-    typedefs
+    module.type_decls.iter().map(TypeDef::from).collect()
 }
 
 pub fn builtin_primdefs() -> Vec<PrimDef> {
@@ -174,10 +203,24 @@ pub fn builtin_primdefs() -> Vec<PrimDef> {
         }
     }
 
-    primdef!(println, "Str -> Top");
-    primdef!(show, "Nat -> Str");
-    primdef!(show_list, "List -> Str");
+    primdef!(println, "Str -> IO Top");
+    primdef!(pure, "forall a. a -> IO a");
+    primdef!(bind, "forall a. forall b. IO a -> (a -> IO b) -> IO b");
+    primdef!(then, "forall a. forall b. IO a -> IO b -> IO b");
+    primdef!(show, "forall a. a -> Str");
     primdef!(cat, "Str -> Str -> Str");
+    primdef!(add, "Nat -> Nat -> Nat");
+    primdef!(mul, "Nat -> Nat -> Nat");
+    primdef!(sub, "Nat -> Nat -> Nat");
+    primdef!(eq, "Nat -> Nat -> Bool");
+    primdef!(str_length, "Str -> Nat");
+    primdef!(str_eq, "Str -> Str -> Bool");
+    primdef!(str_lt, "Str -> Str -> Bool");
+    primdef!(substr, "Str -> Nat -> Nat -> Str");
+    primdef!(chars, "Str -> List Str");
+    primdef!(split, "Str -> Str -> List Str");
+    primdef!(parse_nat, "Str -> Option Nat");
+    primdef!(show_nat, "Nat -> Str");
 
     primdefs
 }
@@ -188,7 +231,7 @@ pub fn builtins_ctx() -> Context<Value> {
     for primdef in builtin_primdefs() {
         ctx = ctx.extend(
             &primdef.name.to_string(),
-            Value::Prim(rc::Rc::new(primdef.code)),
+            Value::Prim(rc::Rc::new(primdef.code), arity(&primdef.typ), vec![]),
         );
     }
     ctx
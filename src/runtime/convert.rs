@@ -0,0 +1,199 @@
+use std::mem;
+
+use num_bigint::BigUint;
+use num_traits::ToPrimitive;
+
+use crate::ast::Type;
+use crate::ast::TypeNode;
+
+use super::Cause;
+use super::RuntimeError;
+use super::Value;
+
+/// A Rust type which stands for a Quail type.
+pub trait QuailType {
+    fn quail_type() -> Type;
+}
+
+/// A Rust type whose values can be passed to Quail.
+pub trait IntoValue: QuailType {
+    fn into_value(self) -> Value;
+}
+
+///
+/// A Rust type whose values can be read back from Quail. The value given must have been
+/// completely evaluated, as by `Runtime::force_deep`, so that it holds no thunks.
+///
+pub trait FromValue: QuailType + Sized {
+    fn from_value(value: Value) -> Result<Self, RuntimeError>;
+}
+
+/// An argument for `Runtime::call`, together with its Quail type.
+pub struct Arg {
+    pub value: Value,
+    pub typ: Type,
+}
+
+impl<T: IntoValue> From<T> for Arg {
+    fn from(x: T) -> Self {
+        Arg {
+            typ: T::quail_type(),
+            value: x.into_value(),
+        }
+    }
+}
+
+fn conversion_error<T: QuailType>(value: &Value) -> RuntimeError {
+    Cause::Conversion(T::quail_type().to_string(), format!("{:?}", value)).into()
+}
+
+fn atom(name: &str) -> Type {
+    TypeNode::Atom(name.to_string()).into()
+}
+
+fn ctor(tag: &str, contents: Vec<Value>) -> Value {
+    Value::Ctor(tag.to_string(), contents)
+}
+
+impl QuailType for u64 {
+    fn quail_type() -> Type {
+        atom("Nat")
+    }
+}
+
+impl IntoValue for u64 {
+    fn into_value(self) -> Value {
+        Value::Nat(BigUint::from(self))
+    }
+}
+
+impl FromValue for u64 {
+    /// Reads a Nat which may be packed, spelled out with `succ` and `zero`, or a mix of the two.
+    fn from_value(value: Value) -> Result<Self, RuntimeError> {
+        let mut val = value;
+        let mut succs: u64 = 0;
+
+        loop {
+            match &mut val {
+                Value::Nat(n) => return n.to_u64().and_then(|n| n.checked_add(succs)).ok_or_else(|| conversion_error::<u64>(&val)),
+                Value::Ctor(tag, _contents) if tag == "zero" => return Ok(succs),
+                Value::Ctor(tag, contents) if tag == "succ" && contents.len() == 1 => {
+                    val = mem::take(contents).remove(0);
+                    succs += 1;
+                },
+                _ => return Err(conversion_error::<u64>(&val)),
+            }
+        }
+    }
+}
+
+impl QuailType for bool {
+    fn quail_type() -> Type {
+        atom("Bool")
+    }
+}
+
+impl IntoValue for bool {
+    fn into_value(self) -> Value {
+        ctor(if self { "true" } else { "false" }, vec![])
+    }
+}
+
+impl FromValue for bool {
+    fn from_value(value: Value) -> Result<Self, RuntimeError> {
+        match &value {
+            Value::Ctor(tag, _contents) if tag == "true" => Ok(true),
+            Value::Ctor(tag, _contents) if tag == "false" => Ok(false),
+            _ => Err(conversion_error::<bool>(&value)),
+        }
+    }
+}
+
+impl QuailType for String {
+    fn quail_type() -> Type {
+        atom("Str")
+    }
+}
+
+impl IntoValue for String {
+    fn into_value(self) -> Value {
+        Value::Str(self)
+    }
+}
+
+impl FromValue for String {
+    fn from_value(mut value: Value) -> Result<Self, RuntimeError> {
+        match &mut value {
+            Value::Str(s) => Ok(mem::take(s)),
+            _ => Err(conversion_error::<String>(&value)),
+        }
+    }
+}
+
+impl QuailType for &str {
+    fn quail_type() -> Type {
+        atom("Str")
+    }
+}
+
+impl IntoValue for &str {
+    fn into_value(self) -> Value {
+        Value::Str(self.to_string())
+    }
+}
+
+impl QuailType for () {
+    fn quail_type() -> Type {
+        atom("Top")
+    }
+}
+
+impl IntoValue for () {
+    fn into_value(self) -> Value {
+        ctor("top", vec![])
+    }
+}
+
+impl FromValue for () {
+    fn from_value(value: Value) -> Result<Self, RuntimeError> {
+        match &value {
+            Value::Ctor(tag, _contents) if tag == "top" => Ok(()),
+            _ => Err(conversion_error::<()>(&value)),
+        }
+    }
+}
+
+impl<T: QuailType> QuailType for Vec<T> {
+    fn quail_type() -> Type {
+        TypeNode::App("List".to_string(), vec![T::quail_type()]).into()
+    }
+}
+
+impl<T: IntoValue> IntoValue for Vec<T> {
+    fn into_value(self) -> Value {
+        let mut list = ctor("nil", vec![]);
+        for x in self.into_iter().rev() {
+            list = ctor("cons", vec![x.into_value(), list]);
+        }
+        list
+    }
+}
+
+impl<T: FromValue> FromValue for Vec<T> {
+    fn from_value(value: Value) -> Result<Self, RuntimeError> {
+        let mut result = Vec::new();
+        let mut val = value;
+
+        loop {
+            match &mut val {
+                Value::Ctor(tag, _contents) if tag == "nil" => return Ok(result),
+                Value::Ctor(tag, contents) if tag == "cons" && contents.len() == 2 => {
+                    let mut contents = mem::take(contents).into_iter();
+                    result.push(T::from_value(contents.next().unwrap())?);
+                    val = contents.next().unwrap();
+                },
+                _ => return Err(conversion_error::<Vec<T>>(&val)),
+            }
+        }
+    }
+}
@@ -2,10 +2,29 @@ mod value;
 mod runtime;
 mod builtins;
 mod prims;
+mod budget;
+mod builder;
+mod convert;
+mod show;
 
 pub use builtins::TypeDef;
 pub use value::Value;
+pub use value::Thunk;
+pub use value::Effect;
+pub use budget::Budget;
+pub use builder::RuntimeBuilder;
+pub use convert::{
+    QuailType,
+    IntoValue,
+    FromValue,
+    Arg,
+};
+pub use budget::Resource;
+pub use budget::parse_seconds;
 pub use runtime::{
     Runtime,
     RuntimeError,
+    Cause,
+    Frame,
+    HoleFn,
 };
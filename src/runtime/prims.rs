@@ -1,104 +1,206 @@
-use super::{Runtime, Value};
-
-pub(super) fn cat(_runtime: &mut Runtime, vs: Vec<Value>) -> Value {
-    assert_eq!(vs.len(), 2, "show_list must have exactly two arguments");
-    let v1 = vs[0].clone();
-    let v2 = vs[1].clone();
-    match (&v1, &v2) {
-        (Value::Str(s1), Value::Str(s2)) => Value::Str(format!("{}{}", s1, s2)),
-        _ => panic!("Arguments to cat must both be Str: {:?} {:?}", &v1, &v2),
+use std::rc;
+
+use num_bigint::BigUint;
+use num_traits::ToPrimitive;
+use num_traits::Zero;
+
+use super::{Runtime, RuntimeError, Cause, Value, Effect, IntoValue};
+
+/// Reports that the named prim was given arguments it can't handle.
+fn prim_error(runtime: &Runtime, name: &str, message: String) -> RuntimeError {
+    runtime.error(Cause::Prim(name.to_string(), message))
+}
+
+/// Checks that the named prim was given exactly as many arguments as it takes.
+fn expect_args(runtime: &Runtime, name: &str, vs: &[Value], count: usize) -> Result<(), RuntimeError> {
+    if vs.len() == count {
+        Ok(())
+    } else {
+        Err(prim_error(runtime, name, format!("expected {} arguments, but was given {}", count, vs.len())))
     }
 }
 
-pub(super) fn println(_runtime: &mut Runtime, vs: Vec<Value>) -> Value {
-    assert_eq!(vs.len(), 1, "println must have exactly one argument");
-    let v = vs[0].clone();
-    println!("{:?}", v);
-    Value::Ctor("unit".into(), Vec::new())
-}
-
-pub(super) fn show(runtime: &mut Runtime, vs: Vec<Value>) -> Value {
-    assert_eq!(vs.len(), 1, "show must have exactly one argument");
-    let v = vs[0].clone();
-    match &v {
-        Value::Ctor(tag, _) => {
-            if tag == "zero" || tag == "succ" {
-                Value::Str(format!("{}", nat_to_u64(v)))
-            } else if tag == "nil" || tag == "cons" {
-                let val_vec = list_to_vec(v.clone());
-                let str_value_vec: Vec<Value> = val_vec.into_iter()
-                    .map(|v| show(runtime, vec![v]))
-                    .collect();
-                let s: String = format!("{:?}", str_value_vec);
-                Value::Str(s)
-            } else {
-                Value::Str(format!("{:?}", v))
-            }
-        },
-        _ => panic!("Can't show this {:?}", &v),
+pub(super) fn cat(runtime: &mut Runtime, vs: Vec<Value>) -> Result<Value, RuntimeError> {
+    expect_args(runtime, "cat", &vs, 2)?;
+    let (v1, v2) = (&vs[0], &vs[1]);
+    match (v1, v2) {
+        (Value::Str(s1), Value::Str(s2)) => Ok(Value::Str(format!("{}{}", s1, s2))),
+        _ => Err(prim_error(runtime, "cat", format!("arguments must both be Str, but found {:?} and {:?}", v1, v2))),
+    }
+}
+
+pub(super) fn println(runtime: &mut Runtime, vs: Vec<Value>) -> Result<Value, RuntimeError> {
+    expect_args(runtime, "println", &vs, 1)?;
+    let v = vs.into_iter().next().unwrap();
+    Ok(Value::Effect(rc::Rc::new(Effect::Println(v))))
+}
+
+pub(super) fn pure(runtime: &mut Runtime, vs: Vec<Value>) -> Result<Value, RuntimeError> {
+    expect_args(runtime, "pure", &vs, 1)?;
+    let v = vs.into_iter().next().unwrap();
+    Ok(Value::Effect(rc::Rc::new(Effect::Pure(v))))
+}
+
+pub(super) fn bind(runtime: &mut Runtime, vs: Vec<Value>) -> Result<Value, RuntimeError> {
+    expect_args(runtime, "bind", &vs, 2)?;
+    let mut vs = vs.into_iter();
+    let io = vs.next().unwrap();
+    let f = vs.next().unwrap();
+    Ok(Value::Effect(rc::Rc::new(Effect::Bind(io, f))))
+}
+
+pub(super) fn then(runtime: &mut Runtime, vs: Vec<Value>) -> Result<Value, RuntimeError> {
+    expect_args(runtime, "then", &vs, 2)?;
+    let mut vs = vs.into_iter();
+    let io = vs.next().unwrap();
+    let next = vs.next().unwrap();
+    let f = Value::Prim(rc::Rc::new(move |_runtime: &mut Runtime, _vs: Vec<Value>| Ok(next.clone())), 1, vec![]);
+    Ok(Value::Effect(rc::Rc::new(Effect::Bind(io, f))))
+}
+
+pub(super) fn show(runtime: &mut Runtime, vs: Vec<Value>) -> Result<Value, RuntimeError> {
+    expect_args(runtime, "show", &vs, 1)?;
+    Ok(Value::Str(runtime.show(vs.into_iter().next().unwrap())?))
+}
+
+pub(super) fn add(runtime: &mut Runtime, vs: Vec<Value>) -> Result<Value, RuntimeError> {
+    let (n, m) = nat_args(runtime, "add", vs)?;
+    Ok(Value::Nat(n + m))
+}
+
+pub(super) fn mul(runtime: &mut Runtime, vs: Vec<Value>) -> Result<Value, RuntimeError> {
+    let (n, m) = nat_args(runtime, "mul", vs)?;
+    Ok(Value::Nat(n * m))
+}
+
+/// Subtraction on Nat stops at zero.
+pub(super) fn sub(runtime: &mut Runtime, vs: Vec<Value>) -> Result<Value, RuntimeError> {
+    let (n, m) = nat_args(runtime, "sub", vs)?;
+    if n > m {
+        Ok(Value::Nat(n - m))
+    } else {
+        Ok(Value::Nat(BigUint::zero()))
     }
 }
 
-pub(super) fn show_list(runtime: &mut Runtime, vs: Vec<Value>) -> Value {
-    assert_eq!(vs.len(), 1, "show_list must have exactly one argument");
-    let v = vs[0].clone();
-    match &v {
-        Value::Ctor(tag, _) => {
-            if tag == "zero" || tag == "succ" {
-                Value::Str(format!("{}", nat_to_u64(v)))
-            } else if tag == "nil" || tag == "cons" {
-                let val_vec = list_to_vec(v.clone());
-                let str_value_vec: Vec<Value> = val_vec.into_iter()
-                    .map(|v| show(runtime, vec![v]))
-                    .collect();
-                let s: String = format!("{:?}", str_value_vec);
-                Value::Str(s)
-            } else {
-                Value::Str(format!("{:?}", v))
-            }
-        },
-        _ => panic!("Can't show this {:?}", &v),
+pub(super) fn eq(runtime: &mut Runtime, vs: Vec<Value>) -> Result<Value, RuntimeError> {
+    let (n, m) = nat_args(runtime, "eq", vs)?;
+    let tag = if n == m { "true" } else { "false" };
+    Ok(Value::Ctor(tag.to_string(), vec![]))
+}
+
+/// The number of characters in a string.
+pub(super) fn str_length(runtime: &mut Runtime, vs: Vec<Value>) -> Result<Value, RuntimeError> {
+    expect_args(runtime, "str_length", &vs, 1)?;
+    let s = str_arg(runtime, "str_length", &vs[0])?;
+    Ok(Value::Nat(BigUint::from(s.chars().count())))
+}
+
+pub(super) fn str_eq(runtime: &mut Runtime, vs: Vec<Value>) -> Result<Value, RuntimeError> {
+    expect_args(runtime, "str_eq", &vs, 2)?;
+    let (s1, s2) = (str_arg(runtime, "str_eq", &vs[0])?, str_arg(runtime, "str_eq", &vs[1])?);
+    Ok((s1 == s2).into_value())
+}
+
+/// Whether the first string comes before the second, comparing them character by character.
+pub(super) fn str_lt(runtime: &mut Runtime, vs: Vec<Value>) -> Result<Value, RuntimeError> {
+    expect_args(runtime, "str_lt", &vs, 2)?;
+    let (s1, s2) = (str_arg(runtime, "str_lt", &vs[0])?, str_arg(runtime, "str_lt", &vs[1])?);
+    Ok((s1 < s2).into_value())
+}
+
+///
+/// `substr s start count` is the part of `s` which is `count` characters long, starting at
+/// the character numbered `start`, counting from zero. It is cut short at the end of `s`.
+///
+pub(super) fn substr(runtime: &mut Runtime, vs: Vec<Value>) -> Result<Value, RuntimeError> {
+    expect_args(runtime, "substr", &vs, 3)?;
+    let mut vs = vs.into_iter();
+    let s = vs.next().unwrap();
+    let s = str_arg(runtime, "substr", &s)?;
+    // Neither can be larger than the length of a string in memory, so anything bigger is as good as usize::MAX.
+    let start = to_nat(runtime, "substr", vs.next().unwrap())?.to_usize().unwrap_or(usize::MAX);
+    let count = to_nat(runtime, "substr", vs.next().unwrap())?.to_usize().unwrap_or(usize::MAX);
+    Ok(Value::Str(s.chars().skip(start).take(count).collect()))
+}
+
+/// The characters of a string, each as a string of its own.
+pub(super) fn chars(runtime: &mut Runtime, vs: Vec<Value>) -> Result<Value, RuntimeError> {
+    expect_args(runtime, "chars", &vs, 1)?;
+    let s = str_arg(runtime, "chars", &vs[0])?;
+    let chars: Vec<String> = s.chars().map(String::from).collect();
+    Ok(chars.into_value())
+}
+
+///
+/// `split separator s` is the list of the parts of `s` between occurrences of `separator`.
+/// An empty separator splits `s` into its characters.
+///
+pub(super) fn split(runtime: &mut Runtime, vs: Vec<Value>) -> Result<Value, RuntimeError> {
+    expect_args(runtime, "split", &vs, 2)?;
+    let separator = str_arg(runtime, "split", &vs[0])?;
+    let s = str_arg(runtime, "split", &vs[1])?;
+    let parts: Vec<String> = if separator.is_empty() {
+        s.chars().map(String::from).collect()
+    } else {
+        s.split(separator).map(String::from).collect()
+    };
+    Ok(parts.into_value())
+}
+
+/// Reads a string of decimal digits as a Nat. Anything else gives `none`.
+pub(super) fn parse_nat(runtime: &mut Runtime, vs: Vec<Value>) -> Result<Value, RuntimeError> {
+    expect_args(runtime, "parse_nat", &vs, 1)?;
+    let s = str_arg(runtime, "parse_nat", &vs[0])?;
+    if !s.is_empty() && s.chars().all(|ch| ch.is_ascii_digit()) {
+        let n = s.parse::<BigUint>().expect("Digits should parse as a Nat");
+        Ok(Value::Ctor("some".to_string(), vec![Value::Nat(n)]))
+    } else {
+        Ok(Value::Ctor("none".to_string(), vec![]))
     }
 }
 
-fn list_to_vec(v: Value) -> Vec<Value> {
+/// Writes a Nat in decimal, the way `parse_nat` reads it.
+pub(super) fn show_nat(runtime: &mut Runtime, vs: Vec<Value>) -> Result<Value, RuntimeError> {
+    expect_args(runtime, "show_nat", &vs, 1)?;
+    let n = to_nat(runtime, "show_nat", vs.into_iter().next().unwrap())?;
+    Ok(Value::Str(n.to_string()))
+}
+
+fn str_arg<'a>(runtime: &Runtime, name: &str, v: &'a Value) -> Result<&'a str, RuntimeError> {
     match v {
-        Value::Ctor(tag, contents) => {
-            if tag == "nil" {
-                Vec::new()
-            } else if tag == "cons" {
-                let head = &contents[0];
-                let tail = &contents[1];
-                let mut result = list_to_vec(tail.clone());
-                result.insert(0, head.clone());
-                result
-            } else {
-                panic!("This isn't a list.")
-            }
-        },
-        _ => panic!("This isn't a list."),
+        Value::Str(s) => Ok(s),
+        _ => Err(prim_error(runtime, name, format!("expected a Str, but found {:?}", v))),
     }
 }
 
-fn nat_to_u64(v: Value) -> u64 {
+fn nat_args(runtime: &Runtime, name: &str, vs: Vec<Value>) -> Result<(BigUint, BigUint), RuntimeError> {
+    expect_args(runtime, name, &vs, 2)?;
+    let mut vs = vs.into_iter();
+    let n = to_nat(runtime, name, vs.next().unwrap())?;
+    let m = to_nat(runtime, name, vs.next().unwrap())?;
+    Ok((n, m))
+}
+
+/// Reads a Nat which may be packed, spelled out with `succ` and `zero`, or a mix of the two.
+fn to_nat(runtime: &Runtime, name: &str, v: Value) -> Result<BigUint, RuntimeError> {
     let mut val = v;
-    let mut result = 0;
+    let mut succs: u64 = 0;
 
     loop {
-        match val {
+        match &mut val {
+            Value::Nat(n) => return Ok(&*n + succs),
             Value::Ctor(tag, contents) => {
                 if tag == "zero" {
-                    break
+                    return Ok(BigUint::from(succs));
                 } else if tag == "succ" {
-                    val = contents[0].clone();
-                    result += 1;
+                    val = std::mem::take(contents).remove(0);
+                    succs += 1;
                 } else {
-                    panic!("This isn't a nat.")
+                    return Err(prim_error(runtime, name, format!("expected a Nat, but found {}", tag)));
                 }
             },
-            _ => panic!("This isn't a nat."),
+            _ => return Err(prim_error(runtime, name, format!("expected a Nat, but found {:?}", val))),
         }
     }
-
-    result
-}
\ No newline at end of file
+}
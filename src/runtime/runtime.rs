@@ -1,34 +1,61 @@
 use std::collections::HashMap;
+use std::fmt;
 use std::io::Read;
+use std::cell;
+use std::mem;
+use std::rc;
+
+use num_traits::Zero;
 
 use crate::parser;
 use crate::ast;
 use crate::types::check;
 use crate::resolver::ImportResolver;
 use crate::context::Context;
+use crate::diagnostic::Diagnostic;
+use crate::tokenizer::Loc;
+use crate::tokenizer::Span;
 
 use ast::TermNode;
 use ast::Def;
 use ast::Import;
+use ast::TypeDecl;
 use ast::MatchArm;
 use ast::Variable;
 use ast::Term;
 use ast::Type;
+use ast::TypeNode;
+use ast::HoleId;
+use ast::HoleInfo;
 use builtins::TypeDef;
+use builtins::PrimDef;
+use check::HoleTypings;
 
 use super::builtins;
 use super::value::Value;
+use super::value::Thunk;
+use super::value::Effect;
+use super::value::PrimFn;
+use super::budget::Budget;
+use super::budget::Meter;
+use super::budget::Resource;
+use super::builder::RuntimeBuilder;
+use super::convert::Arg;
+use super::convert::FromValue;
 
 ///
 /// Runtime is the global store for all of the information loaded into the program.
 ///
 pub struct Runtime {
     /// Keeps track of which modules have been loaded into the Runtime
-    /// already. This is currently being used to break cyclic imports.
+    /// already, or are being loaded. This is currently being used to break cyclic imports.
+    /// A module which fails to load is taken off again, so that it can be imported once fixed.
     pub imports: Vec<String>,
+    /// The text of each module loaded, keyed by its source, so that errors can quote it.
+    pub sources: HashMap<String, String>,
 
-    /// This keeps track of the builtin typedef data for inductive
-    /// types, such as Nat and Bool.
+    /// This keeps track of the typedef data for inductive types, both the builtin
+    /// ones, such as Nat and Bool, and those declared by imported modules.
     pub inductive_typedefs: HashMap<String, TypeDef>,
 
     /// Tracks the value and types of all of the
@@ -42,36 +69,82 @@ pub struct Runtime {
     pub builtin_ctx: Context<Value>,
     /// Tracks the types of the builtins, like println and show.
     pub builtin_type_ctx: Context<Type>,
+
+    /// Records the expected type and typing context of every hole seen by the typechecker.
+    pub hole_typings: HoleTypings,
+    /// Remembers the terms which holes have been filled with. A filled hole evaluates
+    /// its term in the hole's own scope rather than asking for a value again.
+    pub hole_fills: HashMap<HoleId, Term>,
+    /// Called when evaluation reaches a hole which has not yet been filled.
+    /// It must produce the term to fill the hole with.
+    pub hole_handler: Option<rc::Rc<HoleFn>>,
+
+    /// How many constructors deep `show` goes into a coinductive value before it writes `...` instead.
+    pub show_depth: usize,
+
+    /// Limits how much work evaluation may do, so that a program which loops forever is cut short.
+    pub budget: Budget,
+    /// What has been spent of the budget, while an evaluation is in progress.
+    meter: Option<Meter>,
+
+    /// The Quail call stack of the evaluation in progress, outermost frame first.
+    pub(crate) call_stack: Vec<CallSite>,
 }
 
+pub type HoleFn = dyn Fn(&mut Runtime, &HoleInfo, Context<Value>) -> Result<Term, RuntimeError>;
+
 impl Runtime {
     /// Creates a new Runtime with no modules loaded. The inductive typedefs are defined, as well as the builtins.
     /// Sets up the history file, creating it if it doesn't exist.
     pub fn new() -> Self {
-        let inductive_typedefs: HashMap<String, TypeDef> = builtins::builtin_inductive_typedefs()
-            .iter()
-            .map(|itd| (itd.name.to_string(), itd.clone()))
-            .collect();
-
-        let mut builtin_ctx = builtins::builtins_ctx();
-        let mut builtin_type_ctx = builtins::builtins_type_ctx();
-
-        for inductive_typedef in inductive_typedefs.values() {
-            builtin_ctx = builtin_ctx.append(inductive_typedef.ctor_context());
-            builtin_type_ctx = builtin_type_ctx.append(inductive_typedef.ctor_type_context());
-        }
-
-        Runtime {
+        let mut runtime = Runtime {
             imports: vec![],
+            sources: HashMap::new(),
 
-            inductive_typedefs,
+            inductive_typedefs: HashMap::new(),
 
             definition_ctx: Context::empty(),
-            builtin_ctx,
+            builtin_ctx: builtins::builtins_ctx(),
 
             definition_type_ctx: Context::empty(),
-            builtin_type_ctx,
+            builtin_type_ctx: builtins::builtins_type_ctx(),
+
+            hole_typings: HashMap::new(),
+            hole_fills: HashMap::new(),
+            hole_handler: None,
+
+            show_depth: 8,
+
+            budget: Budget::default(),
+            meter: None,
+
+            call_stack: vec![],
+        };
+
+        for inductive_typedef in builtins::builtin_inductive_typedefs() {
+            runtime.add_typedef(inductive_typedef);
         }
+
+        runtime
+    }
+
+    /// Starts building a Runtime with prims of the host program's own.
+    pub fn builder() -> RuntimeBuilder {
+        RuntimeBuilder::new()
+    }
+
+    /// Brings a prim into scope as a builtin.
+    pub(super) fn add_primdef(&mut self, primdef: PrimDef) {
+        let PrimDef { name, typ, code } = primdef;
+        self.builtin_ctx = self.builtin_ctx.extend(&name, Value::Prim(rc::Rc::new(code), builtins::arity(&typ), vec![]));
+        self.builtin_type_ctx = self.builtin_type_ctx.extend(&name, typ);
+    }
+
+    /// Registers an inductive typedef and brings its constructors into scope.
+    fn add_typedef(&mut self, inductive_typedef: TypeDef) {
+        self.builtin_ctx = self.builtin_ctx.append(inductive_typedef.ctor_context());
+        self.builtin_type_ctx = self.builtin_type_ctx.append(inductive_typedef.ctor_type_context());
+        self.inductive_typedefs.insert(inductive_typedef.name.to_string(), inductive_typedef);
     }
 
     pub fn import(
@@ -80,82 +153,323 @@ impl Runtime {
         resolver: &mut dyn ImportResolver,
         is_main: bool,
     ) -> Result<(), RuntimeError> {
+        for definition in self.load(import_name, resolver, is_main)? {
+            let Def(name, _typ, body, _span) = definition;
+            self.definition_ctx = self.definition_ctx.extend(&name, Value::thunk(body, Context::empty()));
+        }
+        Ok(())
+    }
+
+    ///
+    /// Loads a module, along with any modules it imports, and typechecks it. Nothing is evaluated.
+    /// Returns the definitions which were loaded, with those of imported modules coming first.
+    ///
+    pub fn load(
+        &mut self,
+        import_name: &str,
+        resolver: &mut dyn ImportResolver,
+        is_main: bool,
+    ) -> Result<Vec<Def>, RuntimeError> {
+        // If loading fails, the Runtime is put back the way it was, so that the import can be tried again.
+        let imports_len = self.imports.len();
+        let inductive_typedefs = self.inductive_typedefs.clone();
+        let definition_type_ctx = self.definition_type_ctx.clone();
+        let builtin_ctx = self.builtin_ctx.clone();
+        let builtin_type_ctx = self.builtin_type_ctx.clone();
+
+        let result = self.load_module(import_name, resolver, is_main);
+        if result.is_err() {
+            self.imports.truncate(imports_len);
+            self.inductive_typedefs = inductive_typedefs;
+            self.definition_type_ctx = definition_type_ctx;
+            self.builtin_ctx = builtin_ctx;
+            self.builtin_type_ctx = builtin_type_ctx;
+        }
+        result
+    }
+
+    fn load_module(
+        &mut self,
+        import_name: &str,
+        resolver: &mut dyn ImportResolver,
+        is_main: bool,
+    ) -> Result<Vec<Def>, RuntimeError> {
+        if self.imports.iter().any(|name| name == import_name) {
+            return Ok(vec![]);
+        }
+        self.imports.push(import_name.to_string());
+
         let mut module_text = String::new();
 
-        let mut resolved_import = resolver.resolve(import_name)?;
-        resolved_import.read_to_string(&mut module_text)?;
+        let import_error = |error| RuntimeError::from(Cause::Import(import_name.to_string(), None, error));
+        let mut resolved_import = resolver.resolve(import_name).map_err(import_error)?;
+        resolved_import.read_to_string(&mut module_text).map_err(import_error)?;
+        self.sources.insert(resolved_import.source.clone(), module_text.clone());
         let source = Some(resolved_import.source);
 
         let module = parser::parse_module(source, &module_text)?;
 
+        let mut definitions = vec![];
         for import in module.imports {
-            let Import(name) = import;
-            self.import(&name, resolver, false)?;
+            let Import(name, span) = import;
+            let imported_definitions = self.load(&name, resolver, false).map_err(|mut error| {
+                if let Cause::Import(_name, import_span @ None, _error) = error.cause.as_mut() {
+                    *import_span = Some(span);
+                }
+                error
+            })?;
+            definitions.extend(imported_definitions);
         }
 
-        for definition in module.definitions.iter() {
-            let Def(name, typ, body) = definition;
-            if is_main || name != "main" {
-                let type_context = self.builtin_type_ctx.append(self.definition_type_ctx.clone()).extend(name, typ.clone());
-                check::check_type(&body, type_context, &self.inductive_typedefs, typ.clone())?;
-                self.definition_type_ctx = self.definition_type_ctx.extend(&name.to_string(), typ.clone());
+        for type_decl in module.type_decls.iter() {
+            self.declare_type(type_decl)?;
+        }
 
-                let body_value = self.eval(&body, Context::empty());
-                self.definition_ctx = self.definition_ctx.extend(&name.to_string(), body_value);
+        for definition in module.definitions {
+            let Def(name, _typ, _body, _span) = &definition;
+            if is_main || name != "main" {
+                self.check_definition(&definition)?;
+                definitions.push(definition);
             }
         }
+        Ok(definitions)
+    }
+
+    /// Checks a type declaration and brings the type and its constructors into scope.
+    pub fn declare_type(&mut self, type_decl: &TypeDecl) -> Result<(), RuntimeError> {
+        check::check_type_decl(type_decl, &self.inductive_typedefs)?;
+        self.add_typedef(TypeDef::from(type_decl));
+        Ok(())
+    }
+
+    /// Typechecks a definition and, if it is well-typed, brings its type into scope.
+    pub fn check_definition(&mut self, definition: &Def) -> Result<(), RuntimeError> {
+        let Def(name, typ, body, _span) = definition;
+        check::check_type_wellformed(typ, &self.inductive_typedefs)?;
+        let type_context = self.builtin_type_ctx.append(self.definition_type_ctx.clone()).extend(name, typ.clone());
+        check::check_type(body, type_context, &self.inductive_typedefs, &mut self.hole_typings, typ.clone())?;
+        check::check_totality(definition, &self.inductive_typedefs)?;
+        self.definition_type_ctx = self.definition_type_ctx.extend(name, typ.clone());
         Ok(())
     }
 
+    ///
     /// Append a new definition to the Runtime after typechecking it.
+    /// Like those of imported modules, the definition isn't evaluated until its value is needed.
+    ///
     pub fn define(&mut self, definition: &Def) -> Result<(), RuntimeError> {
-        let Def(name, typ, body) = definition;
-        let type_context = self.builtin_type_ctx.append(self.definition_type_ctx.clone()).extend(&name, typ.clone());
-        check::check_type(&body, type_context, &self.inductive_typedefs, typ.clone())?;
-        self.definition_type_ctx = self.definition_type_ctx.extend(&name.to_string(), typ.clone());
+        self.check_definition(definition)?;
+        let Def(name, _typ, body, _span) = definition;
+        self.definition_ctx = self.definition_ctx.extend(name, Value::thunk(body.clone(), Context::empty()));
+        Ok(())
+    }
 
-        let body_value = self.eval(&body, Context::empty());
-        self.definition_ctx = self.definition_ctx.extend(&name.to_string(), body_value);
+    /// Installs the handler which is consulted whenever evaluation reaches an unfilled hole.
+    pub fn set_hole_handler(&mut self, hole_handler: rc::Rc<HoleFn>) {
+        self.hole_handler = Some(hole_handler);
+    }
+
+    /// Renders an error compiler-style, quoting the source of the module it occurred in.
+    pub fn render_error(&self, error: &RuntimeError) -> String {
+        let diagnostic = error.diagnostic();
+        let source = diagnostic.path().and_then(|path| self.sources.get(path));
+        diagnostic.render(source.map(String::as_str))
+    }
+
+    /// Checks that the program has a `main`, and that it has type `IO a`.
+    pub fn check_main(&self) -> Result<(), RuntimeError> {
+        let main_typ = self.definition_type_ctx.lookup("main", 0)
+            .ok_or_else(|| RuntimeError::from("There should be a main in your module".to_string()))?;
+        match main_typ.as_ref() {
+            TypeNode::App(name, _args) if name == "IO" => Ok(()),
+            _ => Err(RuntimeError::from(format!("main should have the type IO a, but it has the type {}", *main_typ))),
+        }
+    }
+
+    /// Runs the program by carrying out the effects described by `main`, which must have type `IO a`.
+    pub fn exec(&mut self) -> Result<(), RuntimeError> {
+        self.check_main()?;
+
+        let main = self.definition_ctx.lookup("main", 0)
+            .ok_or_else(|| RuntimeError::from("There should be a main in your module".to_string()))?;
+        self.metered(|runtime| {
+            runtime.with_frame(CallSite::Definition("main".to_string()), |runtime| runtime.perform(&main))
+        })?;
         Ok(())
     }
 
-    pub fn exec(&mut self) {
-        self.definition_ctx.lookup("main", 0).expect("There should be a main in your module");
+    ///
+    /// Carries out the effects described by a value of type `IO a`, and returns the result.
+    /// This is the only place where effects happen. Evaluation itself is pure.
+    ///
+    pub fn perform(&mut self, io: &Value) -> Result<Value, RuntimeError> {
+        // The functions which still need to be applied to the result of the current effect.
+        let mut continuations = vec![];
+        let mut io = self.force(io)?;
+
+        loop {
+            let result = match &io {
+                Value::Effect(effect) => match effect.as_ref() {
+                    Effect::Pure(value) => value.clone(),
+                    Effect::Println(value) => {
+                        match value {
+                            Value::Str(s) => println!("{}", s),
+                            _ => {
+                                let message = format!("expected a Str, but found {:?}", value);
+                                return Err(self.error(Cause::Prim("println".to_string(), message)));
+                            },
+                        }
+                        Value::Ctor("top".to_string(), vec![])
+                    },
+                    Effect::Bind(first, f) => {
+                        continuations.push(f.clone());
+                        io = self.force(first)?;
+                        continue;
+                    },
+                },
+                _ => return Err(self.error(Cause::NotAnEffect(format!("{:?}", io)))),
+            };
+
+            match continuations.pop() {
+                Some(f) => {
+                    let f = self.force(&f)?;
+                    let next = self.apply(f, vec![result])?;
+                    io = self.force(&next)?;
+                },
+                None => return Ok(result),
+            }
+        }
+    }
+
+    ///
+    /// Makes an error with the given cause, recording the current call stack.
+    /// Prims should use this to report arguments they can't handle.
+    ///
+    pub fn error(&self, cause: Cause) -> RuntimeError {
+        RuntimeError {
+            cause: Box::new(cause),
+            stack: self.call_stack.iter().rev().map(CallSite::frame).collect(),
+        }
+    }
+
+    /// Runs `f` with `frame` pushed onto the call stack. The frame is popped again even if `f` fails.
+    fn with_frame<T>(
+        &mut self,
+        frame: CallSite,
+        f: impl FnOnce(&mut Runtime) -> Result<T, RuntimeError>,
+    ) -> Result<T, RuntimeError> {
+        self.call_stack.push(frame);
+        let result = f(self);
+        self.call_stack.pop();
+        result
+    }
+
+    ///
+    /// Runs `f` against the budget. Evaluations nested inside of another, such as those made while
+    /// running `main`, share its budget. Otherwise, `f` starts with the whole budget to spend.
+    ///
+    fn metered<T>(&mut self, f: impl FnOnce(&mut Runtime) -> Result<T, RuntimeError>) -> Result<T, RuntimeError> {
+        if self.meter.is_some() {
+            return f(self);
+        }
+        self.meter = Some(Meter::new());
+        let result = f(self);
+        self.meter = None;
+        result
+    }
+
+    /// Spends a reduction step of the budget.
+    fn step(&mut self) -> Result<(), RuntimeError> {
+        let meter = self.meter.as_mut().expect("Evaluation should be metered");
+        meter.step(&self.budget).map_err(|resource| self.out_of_budget(resource))
+    }
+
+    /// Spends `count` newly allocated values of the budget.
+    fn allocate(&mut self, count: usize) -> Result<(), RuntimeError> {
+        let meter = self.meter.as_mut().expect("Evaluation should be metered");
+        meter.allocate(count, &self.budget).map_err(|resource| self.out_of_budget(resource))
+    }
+
+    ///
+    /// Calls a prim, and spends a value of the budget for every word of a Nat or Str it makes.
+    /// A prim like `mul` takes a single step however big its result, so a program which keeps
+    /// squaring a number would otherwise run out of memory long before its budget.
+    ///
+    fn call_prim(&mut self, prim: &rc::Rc<PrimFn>, args: Vec<Value>) -> Result<Value, RuntimeError> {
+        let value = prim(self, args)?;
+        let words = match &value {
+            Value::Nat(n) => n.bits().div_ceil(64) as usize,
+            Value::Str(s) => s.len().div_ceil(8),
+            _ => 0,
+        };
+        self.allocate(words)?;
+        Ok(value)
+    }
+
+    fn out_of_budget(&self, resource: Resource) -> RuntimeError {
+        self.error(Cause::OutOfBudget(resource, self.budget.describe(resource)))
+    }
+
+    ///
+    /// Applies a definition to arguments from Rust, and reads the result back into Rust. The
+    /// application is typechecked first, so that the arguments must have the types the definition
+    /// expects, and the result must have the type asked for. For example,
+    /// `runtime.call::<u64>("length", vec![Arg::from(vec![true, false])])`.
+    ///
+    pub fn call<R: FromValue>(&mut self, name: &str, args: Vec<Arg>) -> Result<R, RuntimeError> {
+        if self.definition_type_ctx.lookup(name, 0).is_none() {
+            return Err(Cause::UnboundVariable(name.to_string()).into());
+        }
+
+        // The arguments are bound to names which can't be written in Quail, so they can't be shadowed.
+        let mut type_ctx = self.builtin_type_ctx.append(self.definition_type_ctx.clone());
+        let mut ctx = Context::empty();
+        let mut arg_terms = vec![];
+        for (i, Arg { value, typ }) in args.into_iter().enumerate() {
+            let x = format!("<argument {}>", i + 1);
+            type_ctx = type_ctx.extend(&x, typ);
+            ctx = ctx.extend(&x, value);
+            arg_terms.push(Term::from(TermNode::Var(Variable { name: x, layer: 0 })));
+        }
+        let f = Term::from(TermNode::Var(Variable { name: name.to_string(), layer: 0 }));
+        let term = if arg_terms.is_empty() { f } else { Term::from(TermNode::App(f, arg_terms)) };
+
+        check::check_type(&term, type_ctx, &self.inductive_typedefs, &mut self.hole_typings, R::quail_type())?;
+        let value = self.eval(&term, ctx)?;
+        let value = self.force_deep(&value)?;
+        R::from_value(value)
     }
 
     /// Applies to a function its list of arguments and returns the result.
-    fn apply(self: &mut Runtime, func: Value, args: Vec<Value>) -> Value {
-        match &func {
-            Value::Fun(x, body, local_ctx) => {
-                match args.clone().split_first() {
-                    None => func,
-                    Some((v, vs_remaining)) => {
-                        let new_ctx = local_ctx.extend(&x, v.clone());
-                        let new_func = self.eval(&body, new_ctx);
-                        let new_func = self.force(&new_func);
-                        self.apply(new_func, vs_remaining.to_vec())
-                    },
-                }
-            },
-            Value::Ctor(tag, contents) => {
-                let mut new_contents = contents.clone();
-                new_contents.extend(args);
-                Value::Ctor(tag.to_string(), new_contents)
-            },
-            Value::CoCtor(tag, contents) => {
-                let mut new_contents = contents.clone();
-                new_contents.extend(args);
-                Value::CoCtor(tag.to_string(), new_contents)
-            },
-            Value::Prim(prim) => {
-                let args = args.into_iter().map(|a| self.force_deep(&a)).collect();
-                prim(self, args)
-            },
-            _ => panic!(format!("Applied arguments to non-function {:?}", func)),
+    pub fn apply(&mut self, func: Value, args: Vec<Value>) -> Result<Value, RuntimeError> {
+        if args.is_empty() {
+            return Ok(func);
         }
+        self.run(Control::Return(func), vec![Continuation::Apply(args)])
+    }
+
+    /// Evaluates a term in a given local context and returns the result.
+    pub fn eval(&mut self, t: &Term, ctx: Context<Value>) -> Result<Value, RuntimeError> {
+        self.run(Control::Eval(t.clone(), ctx), vec![])
     }
 
-    fn eval_variable(&self, v: &Variable, ctx: Context<Value>) -> Option<Value> {
+    /// Renders a value the way it would be written in Quail, evaluating it as needed. This is what the `show` prim does.
+    pub fn show(&mut self, value: Value) -> Result<String, RuntimeError> {
+        super::show::show(self, value)
+    }
+
+    /// Evaluates a value until it is no longer a thunk. Each thunk forced along the way is
+    /// updated with the result, so that it is never evaluated again.
+    pub fn force(&mut self, value: &Value) -> Result<Value, RuntimeError> {
+        self.run(Control::Return(value.clone()), vec![])
+    }
+
+    /// Evaluates a value completely, including everything inside of its constructors.
+    pub fn force_deep(&mut self, value: &Value) -> Result<Value, RuntimeError> {
+        self.run(Control::Return(value.clone()), vec![Continuation::ForceDeep])
+    }
+
+    fn eval_variable(&self, v: &Variable, ctx: &Context<Value>) -> Option<Value> {
         let x = &v.name;
         let k = v.layer;
 
@@ -164,96 +478,475 @@ impl Runtime {
             .or_else(|| self.builtin_ctx.lookup(x, k))
     }
 
-    pub fn eval_match(&mut self, t: &TermNode, match_arms: &[MatchArm], ctx: Context<Value>) -> Value {
-        let t_value = self.eval(&t, ctx.clone());
-        let t_value = self.force(&t_value);
-        match t_value {
-            Value::Ctor(tag, contents) => {
-                let MatchArm(pat, body) = ast::find_matching_arm(&tag, &match_arms);
+    /// Whether a term refers to a top-level definition, by way of a variable which isn't shadowed locally.
+    fn is_definition(&self, t: &Term, ctx: &Context<Value>) -> bool {
+        match t.as_ref() {
+            TermNode::Var(v) => ctx.lookup(&v.name, v.layer).is_none() && self.definition_ctx.lookup(&v.name, v.layer).is_some(),
+            _ => false,
+        }
+    }
 
-                let bind_names: Vec<String> = pat[1..].to_vec();
-                let bind_values: Vec<Value> = contents.clone();
-                let bindings: Vec<(String, Value)> = bind_names.into_iter().zip(bind_values).collect();
+    /// The term a hole has been filled with. The first time a hole is reached, the hole
+    /// handler is asked for a term to fill it with. Afterwards, the hole always evaluates to that term.
+    fn hole_fill(&mut self, hole_info: &HoleInfo, ctx: &Context<Value>) -> Result<Term, RuntimeError> {
+        if let Some(fill) = self.hole_fills.get(&hole_info.hole_id) {
+            return Ok(fill.clone());
+        }
+        let hole_handler = self.hole_handler
+            .clone()
+            .ok_or_else(|| self.error(Cause::UnfilledHole(hole_info.clone())))?;
+        // Evaluations made while the hole is being filled are given a budget of their own.
+        let meter = self.meter.take();
+        let fill = hole_handler(self, hole_info, ctx.clone());
+        self.meter = meter;
+        let fill = fill?;
+        self.hole_fills.insert(hole_info.hole_id, fill.clone());
+        Ok(fill)
+    }
 
-                let extended_ctx = ctx.extend_many(&bindings);
-                self.eval(&body, extended_ctx)
-            },
-            Value::CoCtor(tag, contents) => {
-                let MatchArm(pat, body) = ast::find_matching_arm(&tag, &match_arms);
+    ///
+    /// Runs the evaluator until the continuation stack is empty, and returns the final value.
+    /// Whatever happens, the call stack is left as it was found.
+    ///
+    fn run(&mut self, control: Control, continuations: Vec<Continuation>) -> Result<Value, RuntimeError> {
+        let depth = self.call_stack.len();
+        let result = self.metered(|runtime| runtime.step_until_done(control, continuations));
+        self.call_stack.truncate(depth);
+        result
+    }
 
-                let bind_names: Vec<String> = pat[1..].to_vec();
-                let bind_values: Vec<Value> = contents.clone();
-                let bindings: Vec<(String, Value)> = bind_names.into_iter().zip(bind_values).collect();
+    ///
+    /// The evaluator proper. Rather than recursing on the native stack, it keeps what is left
+    /// to do with each value on an explicit stack of continuations in the heap. This way, the
+    /// depth of recursion in a Quail program is limited only by memory.
+    ///
+    /// Each value reaching a continuation has been forced, so it is never a thunk.
+    ///
+    fn step_until_done(&mut self, mut control: Control, mut continuations: Vec<Continuation>) -> Result<Value, RuntimeError> {
+        loop {
+            self.step()?;
+            let value = match control {
+                Control::Eval(t, ctx) => match t.as_ref() {
+                    TermNode::Var(v) => self.eval_variable(v, &ctx)
+                        .ok_or_else(|| self.error(Cause::UnboundVariable(v.name.to_string())))?,
+                    TermNode::StrLit(contents) => {
+                        self.allocate(1)?;
+                        Value::Str(contents.to_string())
+                    },
+                    TermNode::NatLit(n) => {
+                        self.allocate(1)?;
+                        Value::Nat(n.clone())
+                    },
+                    TermNode::Hole(hole_info) => {
+                        let fill = self.hole_fill(hole_info, &ctx)?;
+                        self.call_stack.push(CallSite::Term(t.clone()));
+                        continuations.push(Continuation::PopFrame);
+                        control = Control::Eval(fill, ctx);
+                        continue;
+                    },
+                    TermNode::As(term, _typ) => {
+                        control = Control::Eval(term.clone(), ctx);
+                        continue;
+                    },
+                    TermNode::Match(discriminee, _match_arms) => {
+                        self.call_stack.push(CallSite::Term(t.clone()));
+                        continuations.push(Continuation::Match(t.clone(), ctx.clone()));
+                        control = Control::Eval(discriminee.clone(), ctx);
+                        continue;
+                    },
+                    TermNode::Lam(x, body) => {
+                        self.allocate(1)?;
+                        Value::Fun(x.clone(), body.clone(), ctx)
+                    },
+                    TermNode::App(f, vs) => {
+                        if self.is_definition(f, &ctx) {
+                            // A call in tail position takes the place of the frames it would return
+                            // through, so that a tail-recursive loop runs in constant space.
+                            while let Some(Continuation::PopFrame) = continuations.last() {
+                                continuations.pop();
+                                self.call_stack.pop();
+                            }
+                            self.call_stack.push(CallSite::Term(t.clone()));
+                            continuations.push(Continuation::PopFrame);
+                        }
+                        self.allocate(vs.len())?;
+                        let args = vs.iter()
+                            .map(|v| Value::thunk(v.clone(), ctx.clone()))
+                            .collect();
+                        continuations.push(Continuation::Apply(args));
+                        control = Control::Eval(f.clone(), ctx);
+                        continue;
+                    },
+                    TermNode::Let(x, v, body) => {
+                        self.allocate(1)?;
+                        let extended_ctx = ctx.extend(x, Value::thunk(v.clone(), ctx.clone()));
+                        control = Control::Eval(body.clone(), extended_ctx);
+                        continue;
+                    },
+                },
+                Control::Return(value) => value,
+            };
+
+            // Thunks are forced before they are passed on, and updated once their value is known.
+            if let Value::Thunk(thunk) = &value {
+                let state = thunk.borrow().clone();
+                control = match state {
+                    Thunk::Forced(value) => Control::Return(value),
+                    Thunk::Delayed(t, ctx) => {
+                        continuations.push(Continuation::Update(thunk.clone()));
+                        Control::Eval(t, ctx)
+                    },
+                };
+                continue;
+            }
 
-                let extended_ctx = ctx.extend_many(&bindings);
-                self.eval(&body, extended_ctx)
-            },
-            _ => panic!(format!("Expected a constructor during match statement, but found {:?}", &t_value)),
+            let mut value = value;
+            control = match continuations.pop() {
+                None => return Ok(value),
+                Some(Continuation::Update(thunk)) => {
+                    *thunk.borrow_mut() = Thunk::Forced(value.clone());
+                    Control::Return(value)
+                },
+                Some(Continuation::PopFrame) => {
+                    self.call_stack.pop();
+                    Control::Return(value)
+                },
+                Some(Continuation::Match(t, ctx)) => {
+                    let match_arms = match t.as_ref() {
+                        TermNode::Match(_discriminee, match_arms) => match_arms,
+                        _ => unreachable!("Match continuations hold match terms"),
+                    };
+                    let (tag, contents) = match &mut value {
+                        Value::Ctor(tag, contents) => (mem::take(tag), mem::take(contents)),
+                        Value::CoCtor(tag, contents) => (mem::take(tag), mem::take(contents)),
+                        Value::Nat(n) if n.is_zero() => ("zero".to_string(), vec![]),
+                        Value::Nat(n) => ("succ".to_string(), vec![Value::Nat(&*n - 1u32)]),
+                        _ => return Err(self.error(Cause::NotAConstructor(format!("{:?}", &value)))),
+                    };
+
+                    let MatchArm(pat, body, _span) = ast::find_matching_arm(&tag, match_arms)
+                        .ok_or_else(|| self.error(Cause::NoMatchingArm(tag)))?;
+
+                    continuations.push(Continuation::PopFrame);
+
+                    let mut ctx = ctx;
+                    for (x, content) in pat[1..].iter().zip(contents) {
+                        ctx = ctx.extend(x, content);
+                    }
+                    Control::Eval(body.clone(), ctx)
+                },
+                Some(Continuation::Apply(mut args)) => match &mut value {
+                    Value::Fun(x, body, local_ctx) => {
+                        let arg = args.remove(0);
+                        if !args.is_empty() {
+                            continuations.push(Continuation::Apply(args));
+                        }
+                        Control::Eval(body.clone(), local_ctx.extend(x, arg))
+                    },
+                    Value::Ctor(tag, contents) => {
+                        self.allocate(1)?;
+                        let mut new_contents = mem::take(contents);
+                        new_contents.extend(args);
+                        Control::Return(Value::Ctor(mem::take(tag), new_contents))
+                    },
+                    Value::CoCtor(tag, contents) => {
+                        self.allocate(1)?;
+                        let mut new_contents = mem::take(contents);
+                        new_contents.extend(args);
+                        Control::Return(Value::CoCtor(mem::take(tag), new_contents))
+                    },
+                    Value::Prim(prim, arity, collected) => {
+                        let mut all_args = collected.clone();
+                        all_args.extend(args);
+                        if all_args.len() < *arity {
+                            // Like a constructor, a prim collects its arguments until it has them all.
+                            self.allocate(1)?;
+                            Control::Return(Value::Prim(prim.clone(), *arity, all_args))
+                        } else {
+                            // The result of the prim is applied to any arguments beyond those it takes.
+                            let extra_args = all_args.split_off(*arity);
+                            if !extra_args.is_empty() {
+                                continuations.push(Continuation::Apply(extra_args));
+                            }
+                            // The arguments to a prim are evaluated completely before it is called.
+                            all_args.reverse();
+                            match all_args.pop() {
+                                Some(arg) => {
+                                    continuations.push(Continuation::PrimArgs(prim.clone(), vec![], all_args));
+                                    continuations.push(Continuation::ForceDeep);
+                                    Control::Return(arg)
+                                },
+                                None => Control::Return(self.call_prim(prim, vec![])?),
+                            }
+                        }
+                    },
+                    _ => return Err(self.error(Cause::NotAFunction(format!("{:?}", value)))),
+                },
+                Some(Continuation::PrimArgs(prim, mut forced, mut remaining)) => {
+                    forced.push(value);
+                    match remaining.pop() {
+                        Some(arg) => {
+                            continuations.push(Continuation::PrimArgs(prim, forced, remaining));
+                            continuations.push(Continuation::ForceDeep);
+                            Control::Return(arg)
+                        },
+                        None => Control::Return(self.call_prim(&prim, forced)?),
+                    }
+                },
+                Some(Continuation::ForceDeep) => match &mut value {
+                    Value::Ctor(tag, contents) if !contents.is_empty() => {
+                        let mut remaining = mem::take(contents);
+                        remaining.reverse();
+                        let content = remaining.pop().expect("Contents should not be empty");
+                        continuations.push(Continuation::ForceContents(mem::take(tag), vec![], remaining));
+                        continuations.push(Continuation::ForceDeep);
+                        Control::Return(content)
+                    },
+                    _ => Control::Return(value),
+                },
+                Some(Continuation::ForceContents(tag, mut forced, mut remaining)) => {
+                    forced.push(value);
+                    match remaining.pop() {
+                        Some(content) => {
+                            continuations.push(Continuation::ForceContents(tag, forced, remaining));
+                            continuations.push(Continuation::ForceDeep);
+                            Control::Return(content)
+                        },
+                        None => Control::Return(Value::Ctor(tag, forced)),
+                    }
+                },
+            };
         }
     }
+}
 
-    pub fn eval_app(&mut self, f: &Term, vs: &[Term], ctx: Context<Value>) -> Value {
-        let f_value = self.eval(&f, ctx.clone());
-        let f_value = self.force(&f_value);
-        let vs_values: Vec<Value> = vs.iter()
-            .map(|v| Value::Thunk(v.clone(), ctx.clone()))
-            .collect();
-        self.apply(f_value, vs_values)
+/// What the evaluator is doing: either evaluating a term, or passing a value on to the continuations.
+enum Control {
+    Eval(Term, Context<Value>),
+    Return(Value),
+}
+
+/// What remains to be done with a value once it has been evaluated.
+enum Continuation {
+    /// Apply the value, which should be a function, to the arguments.
+    Apply(Vec<Value>),
+    /// Match on the value with the arms of the match term, in the given context.
+    Match(Term, Context<Value>),
+    /// Write the value back into a thunk, so that it is never evaluated again.
+    Update(rc::Rc<cell::RefCell<Thunk>>),
+    /// Pop the innermost frame of the call stack.
+    PopFrame,
+    /// Evaluate everything inside of the value's constructors, too.
+    ForceDeep,
+    /// Collect the completely evaluated contents of a constructor. Those still to be evaluated are in reverse order.
+    ForceContents(ast::Tag, Vec<Value>, Vec<Value>),
+    /// Collect the completely evaluated arguments of a prim. Those still to be evaluated are in reverse order.
+    PrimArgs(rc::Rc<PrimFn>, Vec<Value>, Vec<Value>),
+}
+
+///
+/// An error from loading or running a program. Errors raised during evaluation
+/// also carry the Quail call stack at the point where they happened.
+///
+#[derive(Debug)]
+pub struct RuntimeError {
+    pub cause: Box<Cause>,
+    /// The frames of the call stack, innermost first. Empty for errors raised outside of evaluation.
+    pub stack: Vec<Frame>,
+}
+
+/// The ways loading or running a program can fail.
+#[derive(Debug)]
+pub enum Cause {
+    /// The named module couldn't be found or read. The span is that of the import which named it.
+    Import(String, Option<Span>, std::io::Error),
+    Parse(parser::ParseErr),
+    Type(Box<check::TypeErr>),
+    Message(String),
+    /// A variable was bound in neither the local context, the definitions, nor the builtins.
+    UnboundVariable(String),
+    /// Arguments were applied to a value which isn't a function.
+    NotAFunction(String),
+    /// A value which isn't a constructor was matched on.
+    NotAConstructor(String),
+    /// A match has no arm for the tag of the value it matched on.
+    NoMatchingArm(ast::Tag),
+    /// Evaluation reached a hole, and there is no hole handler to fill it.
+    UnfilledHole(HoleInfo),
+    /// The hole handler was asked to abort the program rather than fill the hole.
+    Aborted(HoleInfo),
+    /// A value which isn't an IO action was performed.
+    NotAnEffect(String),
+    /// The named prim was given arguments it can't handle.
+    Prim(String, String),
+    /// Evaluation used up its budget of the resource. The limit is described for people to read.
+    OutOfBudget(Resource, String),
+    /// A value couldn't be read back into Rust. Holds the Quail type expected, and the value found.
+    Conversion(String, String),
+}
+
+///
+/// An entry of the call stack while evaluation is in progress. Frames are pushed for every call,
+/// so entries hold on to the term which pushed them, which is cheap to copy. They are only made
+/// into Frames when an error needs them.
+///
+pub(crate) enum CallSite {
+    /// A definition run directly by the Runtime, rather than called from Quail.
+    Definition(String),
+    /// An application of a definition, a match, or a hole.
+    Term(Term),
+}
+
+impl CallSite {
+    fn frame(&self) -> Frame {
+        match self {
+            CallSite::Definition(name) => Frame::Definition(name.to_string(), None),
+            CallSite::Term(t) => match t.as_ref() {
+                TermNode::App(f, _vs) => match f.as_ref() {
+                    TermNode::Var(v) => Frame::Definition(v.name.to_string(), f.span().cloned()),
+                    _ => unreachable!("Only applications of definitions are pushed onto the call stack"),
+                },
+                TermNode::Match(_discriminee, _match_arms) => Frame::Match(t.span().cloned()),
+                TermNode::Hole(hole_info) => Frame::Hole(hole_info.loc.clone()),
+                _ => unreachable!("Only applications, matches and holes are pushed onto the call stack"),
+            },
+        }
     }
+}
+
+/// A frame of the Quail call stack.
+#[derive(Debug, Clone)]
+pub enum Frame {
+    /// A call to a top-level definition. The span is that of the name at the call site.
+    Definition(String, Option<Span>),
+    /// A match statement, while the value it matches on and then the chosen arm are evaluated.
+    Match(Option<Span>),
+    /// The term a hole was filled with.
+    Hole(Loc),
+}
 
-    pub fn eval_let(&mut self, x: &str, v: &Term, body: &Term, ctx: Context<Value>) -> Value {
-        let v_value = self.eval(&v, ctx.clone());
-        let extended_ctx = ctx.extend(x, v_value);
-        self.eval(&body, extended_ctx)
+impl RuntimeError {
+    pub fn diagnostic(&self) -> Diagnostic {
+        let diagnostic = match self.cause.as_ref() {
+            Cause::Import(_name, span, _error) => Diagnostic::new("import", self.to_string(), span.clone()),
+            Cause::Parse(error) => error.diagnostic(),
+            Cause::Type(error) => error.diagnostic(),
+            Cause::UnfilledHole(hole_info) | Cause::Aborted(hole_info) => {
+                let span = Span { start: hole_info.loc.clone(), end: hole_info.loc.clone() };
+                Diagnostic::new(self.code(), self.to_string(), Some(span))
+            },
+            // Errors during evaluation point at the innermost frame which has a location.
+            _ => Diagnostic::new(self.code(), self.to_string(), self.stack.iter().find_map(Frame::span)),
+        };
+        diagnostic.with_notes(self.stack_notes())
     }
 
-    /// Evaluates a term in a given local context and returns the result.
-    pub fn eval(&mut self, t: &TermNode, ctx: Context<Value>) -> Value {
-        match t {
-            TermNode::Var(v) => self.eval_variable(v, ctx).expect(&format!("Unbound variable {:?}", v)),
-            TermNode::StrLit(contents) => Value::Str(contents.to_string()),
-            TermNode::Hole(_hole_info) => unimplemented!(),
-            TermNode::As(term, _typ) => self.eval(&term, ctx),
-            TermNode::Match(t, match_arms) => self.eval_match(t, match_arms, ctx),
-            TermNode::Lam(x, body) => Value::Fun(x.clone(), body.clone(), ctx.clone()),
-            TermNode::App(f, vs) => self.eval_app(f, vs.as_slice(), ctx),
-            TermNode::Let(x, v, body) => self.eval_let(x, v, body, ctx),
+    /// The frames of the stack as notes. The middle of a very deep stack is left out.
+    fn stack_notes(&self) -> Vec<String> {
+        const ENDS: usize = 8;
+        if self.stack.len() <= 2 * ENDS {
+            return self.stack.iter().map(Frame::to_string).collect();
         }
+        let mut notes: Vec<String> = self.stack[..ENDS].iter().map(Frame::to_string).collect();
+        notes.push(format!("... {} more frames ...", self.stack.len() - 2 * ENDS));
+        notes.extend(self.stack[self.stack.len() - ENDS..].iter().map(Frame::to_string));
+        notes
     }
 
-    pub fn force(&mut self, value: &Value) -> Value {
-        let mut result = value.clone();
-        while let Value::Thunk(t, ctx) = &result {
-            result = self.eval(&t, ctx.clone());
+    /// A short, stable name for the kind of error.
+    pub fn code(&self) -> &'static str {
+        match self.cause.as_ref() {
+            Cause::Import(..) => "import",
+            Cause::Parse(error) => error.code(),
+            Cause::Type(error) => error.code(),
+            Cause::Message(_) => "runtime",
+            Cause::UnboundVariable(_) => "unbound-variable",
+            Cause::NotAFunction(_) => "not-a-function",
+            Cause::NotAConstructor(_) => "not-a-constructor",
+            Cause::NoMatchingArm(_) => "no-matching-arm",
+            Cause::UnfilledHole(_) => "unfilled-hole",
+            Cause::Aborted(_) => "aborted",
+            Cause::NotAnEffect(_) => "not-an-effect",
+            Cause::Prim(..) => "prim",
+            Cause::OutOfBudget(..) => "out-of-budget",
+            Cause::Conversion(..) => "conversion",
         }
-        result
     }
+}
 
-    pub fn force_deep(&mut self, value: &Value) -> Value {
-        let mut result = value.clone();
-        while let Value::Thunk(t, ctx) = result {
-            result = self.eval(&t, ctx.clone());
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.cause.as_ref() {
+            Cause::Import(import_name, _span, error) => write!(f, "Could not import {}: {}", import_name, error),
+            Cause::Parse(error) => write!(f, "{}", error),
+            Cause::Type(error) => write!(f, "{}", error),
+            Cause::Message(message) => write!(f, "{}", message),
+            Cause::UnboundVariable(name) => write!(f, "Unbound variable {}", name),
+            Cause::NotAFunction(value) => write!(f, "Applied arguments to non-function {}", value),
+            Cause::NotAConstructor(value) => write!(f, "Expected a constructor during match statement, but found {}", value),
+            Cause::NoMatchingArm(tag) => write!(f, "No matching arm found for tag {}", tag),
+            Cause::UnfilledHole(hole_info) => match &hole_info.name {
+                Some(name) => write!(f, "Evaluation reached the hole ?{}", name),
+                None => write!(f, "Evaluation reached a hole"),
+            },
+            Cause::Aborted(hole_info) => match &hole_info.name {
+                Some(name) => write!(f, "Aborted at the hole ?{}", name),
+                None => write!(f, "Aborted at a hole"),
+            },
+            Cause::NotAnEffect(value) => write!(f, "Expected an IO action, but found {}", value),
+            Cause::Prim(name, message) => write!(f, "{}: {}", name, message),
+            Cause::OutOfBudget(resource, limit) => write!(f, "Evaluation ran out of {}, having been limited to {}", resource, limit),
+            Cause::Conversion(typ, value) => write!(f, "Expected a value of type {}, but found {}", typ, value),
         }
+    }
+}
 
-        if let Value::Ctor(tag, contents) = result {
-            let contents = contents.iter().map(|v| self.force_deep(v)).collect();
-            result = Value::Ctor(tag.to_string(), contents);
+impl Frame {
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            Frame::Definition(_name, span) => span.clone(),
+            Frame::Match(span) => span.clone(),
+            Frame::Hole(loc) => Some(Span { start: loc.clone(), end: loc.clone() }),
         }
-        result
     }
 }
 
-#[derive(Debug)]
-pub struct RuntimeError(String);
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let at = |loc: &Loc| format!("{}:{}:{}", loc.path.as_deref().unwrap_or("<input>"), loc.line + 1, loc.col + 1);
+        match self {
+            Frame::Definition(name, Some(span)) => write!(f, "in {}, called at {}", name, at(&span.start)),
+            Frame::Definition(name, None) => write!(f, "in {}", name),
+            Frame::Match(Some(span)) => write!(f, "in the match at {}", at(&span.start)),
+            Frame::Match(None) => write!(f, "in a match"),
+            Frame::Hole(loc) => write!(f, "in the hole at {}", at(loc)),
+        }
+    }
+}
 
-impl std::convert::From<std::io::Error> for RuntimeError {
-    fn from(error: std::io::Error) -> Self {
-        RuntimeError(error.to_string())
+impl std::convert::From<Cause> for RuntimeError {
+    fn from(cause: Cause) -> Self {
+        RuntimeError {
+            cause: Box::new(cause),
+            stack: vec![],
+        }
     }
 }
 
 impl std::convert::From<String> for RuntimeError {
     fn from(error: String) -> Self {
-        RuntimeError(error)
+        Cause::Message(error).into()
+    }
+}
+
+impl std::convert::From<parser::ParseErr> for RuntimeError {
+    fn from(error: parser::ParseErr) -> Self {
+        Cause::Parse(error).into()
+    }
+}
+
+impl std::convert::From<Box<check::TypeErr>> for RuntimeError {
+    fn from(error: Box<check::TypeErr>) -> Self {
+        Cause::Type(error).into()
     }
 }
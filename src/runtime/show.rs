@@ -0,0 +1,164 @@
+use std::fmt::Write;
+use std::mem;
+
+use num_bigint::BigUint;
+
+use crate::ast::Flavor;
+
+use super::Runtime;
+use super::RuntimeError;
+use super::TypeDef;
+use super::Value;
+
+///
+/// Renders a value the way it would be written in Quail. Which type each constructor belongs
+/// to is looked up in the Runtime's typedefs. This way, a `Nat` is written in decimal and a
+/// `List` as `[a, b]`, while the constructors of other types are applied to their contents,
+/// with parentheses wherever they are needed.
+///
+/// Values are forced as they are rendered. Coinductive values may go on forever, so once
+/// `Runtime::show_depth` of their constructors have been written, the rest is written as `...`.
+///
+/// The value is taken apart as it goes, rather than copied, since a completely evaluated value
+/// may be very deep.
+///
+pub(super) fn show(runtime: &mut Runtime, value: Value) -> Result<String, RuntimeError> {
+    let mut out = String::new();
+    write_value(runtime, value, &mut out)?;
+    Ok(out)
+}
+
+/// What is left to be written, innermost last.
+enum Task {
+    /// A value, along with how many coinductive constructors it is inside of, and whether it is
+    /// nested inside of a constructor, in which case it is parenthesized if it has contents of its own.
+    Value(Value, usize, bool),
+    /// The rest of a list whose first element has been written, or which has none yet if the flag is set.
+    List(Value, usize, bool),
+    Text(&'static str),
+}
+
+///
+/// Writes the value to `out`. What remains to be written is kept on a worklist rather than the
+/// native stack, so a value can be as deep as memory allows.
+///
+fn write_value(runtime: &mut Runtime, value: Value, out: &mut String) -> Result<(), RuntimeError> {
+    let mut tasks = vec![Task::Value(value, 0, false)];
+    while let Some(task) = tasks.pop() {
+        match task {
+            Task::Value(value, codepth, nested) => write_one(runtime, value, codepth, nested, &mut tasks, out)?,
+            Task::List(list, codepth, first) => {
+                let mut list = force(runtime, list)?;
+                match &mut list {
+                    Value::Ctor(tag, contents) if tag == "cons" && contents.len() == 2 => {
+                        let tail = contents.pop().unwrap();
+                        let head = contents.pop().unwrap();
+                        if !first {
+                            out.push_str(", ");
+                        }
+                        tasks.push(Task::List(tail, codepth, false));
+                        tasks.push(Task::Value(head, codepth, false));
+                    },
+                    _ => out.push(']'),
+                }
+            },
+            Task::Text(text) => out.push_str(text),
+        }
+    }
+    Ok(())
+}
+
+/// Writes as much of the value as it can right away, and leaves its contents for later.
+fn write_one(
+    runtime: &mut Runtime,
+    value: Value,
+    codepth: usize,
+    nested: bool,
+    tasks: &mut Vec<Task>,
+    out: &mut String,
+) -> Result<(), RuntimeError> {
+    let mut value = force(runtime, value)?;
+    let (tag, contents) = match &mut value {
+        Value::Ctor(tag, contents) | Value::CoCtor(tag, contents) => (tag, contents),
+        Value::Str(s) => {
+            write!(out, "{:?}", s).unwrap();
+            return Ok(());
+        },
+        Value::Nat(n) => {
+            write!(out, "{}", n).unwrap();
+            return Ok(());
+        },
+        Value::Fun(..) | Value::Prim(..) => {
+            out.push_str("<function>");
+            return Ok(());
+        },
+        Value::Effect(_) => {
+            out.push_str("<IO action>");
+            return Ok(());
+        },
+        Value::Thunk(_) => unreachable!("Forced values should not be thunks"),
+    };
+
+    let (name, flavor) = match typedef_of_tag(runtime, tag) {
+        Some(typedef) => (typedef.name.to_string(), typedef.flavor.clone()),
+        None => (String::new(), Flavor::Inductive),
+    };
+    if name == "Nat" {
+        let n = nat_value(runtime, value)?;
+        write!(out, "{}", n).unwrap();
+        return Ok(());
+    } else if name == "List" {
+        out.push('[');
+        tasks.push(Task::List(value, codepth, true));
+        return Ok(());
+    }
+
+    let codepth = match flavor {
+        Flavor::Inductive => codepth,
+        Flavor::Coinductive if codepth >= runtime.show_depth => {
+            out.push_str("...");
+            return Ok(());
+        },
+        Flavor::Coinductive => codepth + 1,
+    };
+
+    let parenthesize = nested && !contents.is_empty();
+    if parenthesize {
+        out.push('(');
+        tasks.push(Task::Text(")"));
+    }
+    out.push_str(tag);
+    for content in mem::take(contents).into_iter().rev() {
+        tasks.push(Task::Value(content, codepth, true));
+        tasks.push(Task::Text(" "));
+    }
+    Ok(())
+}
+
+/// Counts up a Nat which may be packed, spelled out with `succ` and `zero`, or a mix of the two.
+fn nat_value(runtime: &mut Runtime, value: Value) -> Result<BigUint, RuntimeError> {
+    let mut value = value;
+    let mut succs: u64 = 0;
+    loop {
+        let pred = match &mut value {
+            Value::Nat(n) => return Ok(&*n + succs),
+            Value::Ctor(tag, contents) if tag == "succ" && contents.len() == 1 => contents.pop().unwrap(),
+            _ => return Ok(BigUint::from(succs)),
+        };
+        value = force(runtime, pred)?;
+        succs += 1;
+    }
+}
+
+/// Forces the value if it is a thunk. Other values are passed on as they are, without being copied.
+fn force(runtime: &mut Runtime, value: Value) -> Result<Value, RuntimeError> {
+    match value {
+        Value::Thunk(_) => runtime.force(&value),
+        _ => Ok(value),
+    }
+}
+
+/// The typedef of the type the constructor with the given tag belongs to.
+fn typedef_of_tag<'a>(runtime: &'a Runtime, tag: &str) -> Option<&'a TypeDef> {
+    runtime.inductive_typedefs.values().find(|typedef| typedef.ctor_types.contains_key(tag))
+}
@@ -1,7 +1,12 @@
+use std::cell;
 use std::fmt;
+use std::mem;
 use std::rc;
 
+use num_bigint::BigUint;
+
 use super::Runtime;
+use super::RuntimeError;
 
 use crate::context::Context;
 use crate::ast::Tag;
@@ -12,12 +17,97 @@ pub enum Value {
     Ctor(Tag, Vec<Value>),
     CoCtor(Tag, Vec<Value>),
     Fun(String, Term, Context<Value>),
-    Prim(rc::Rc<PrimFn>),
+    /// A prim which takes the given number of arguments, and those it has been applied to so far.
+    /// It is only called once it has all of them.
+    Prim(rc::Rc<PrimFn>, usize, Vec<Value>),
     Str(String),
-    Thunk(Term, Context<Value>),
+    /// A natural number, packed rather than spelled out as a chain of `succ`s.
+    /// It is matched on as though it were `zero` or `succ n`.
+    Nat(BigUint),
+    Thunk(rc::Rc<cell::RefCell<Thunk>>),
+    Effect(rc::Rc<Effect>),
+}
+
+///
+/// A delayed computation. Arguments to functions are passed as thunks, and every copy of a
+/// thunk shares the same cell. The first time it is forced, the result is written back into
+/// the cell, so a thunk is evaluated at most once.
+///
+#[derive(Clone)]
+pub enum Thunk {
+    Delayed(Term, Context<Value>),
+    Forced(Value),
+}
+
+pub type PrimFn = dyn Fn(&mut Runtime, Vec<Value>) -> Result<Value, RuntimeError>;
+
+///
+/// A description of an effect, which is the value of a term of type `IO a`. Evaluating such a
+/// term never carries the effect out. Only `Runtime::exec` does so, when it runs `main`.
+///
+pub enum Effect {
+    /// Does nothing, and results in the given value.
+    Pure(Value),
+    /// Prints the given value, and results in `top`.
+    Println(Value),
+    /// Carries out the first effect, then applies the function to its result to get the next.
+    Bind(Value, Value),
+}
+
+impl Value {
+    /// A thunk which will evaluate the term in the given context when it is forced.
+    pub fn thunk(t: Term, ctx: Context<Value>) -> Value {
+        Value::Thunk(rc::Rc::new(cell::RefCell::new(Thunk::Delayed(t, ctx))))
+    }
+}
+
+///
+/// Values can be nested very deeply, as with a list with a million elements. Dropping them the
+/// usual way would recurse once for each level, and so overflow the native stack. Instead, the
+/// contents of each value which is about to go away are moved onto a worklist and dropped in turn.
+///
+impl Drop for Value {
+    fn drop(&mut self) {
+        let mut worklist = vec![];
+        self.take_contents(&mut worklist);
+        while let Some(mut value) = worklist.pop() {
+            value.take_contents(&mut worklist);
+        }
+    }
 }
 
-type PrimFn = dyn Fn(&mut Runtime, Vec<Value>) -> Value;
+impl Value {
+    /// Moves out the values held by this one, unless they are shared with another value.
+    fn take_contents(&mut self, worklist: &mut Vec<Value>) {
+        match self {
+            Value::Ctor(_tag, contents) => worklist.append(contents),
+            Value::CoCtor(_tag, contents) => worklist.append(contents),
+            Value::Prim(_prim, _arity, args) => worklist.append(args),
+            Value::Fun(_x, _body, ctx) => worklist.extend(ctx.take_unshared()),
+            Value::Thunk(thunk) => {
+                if let Some(thunk) = rc::Rc::get_mut(thunk) {
+                    match thunk.get_mut() {
+                        Thunk::Delayed(_t, ctx) => worklist.extend(ctx.take_unshared()),
+                        Thunk::Forced(value) => worklist.push(mem::replace(value, Value::Str(String::new()))),
+                    }
+                }
+            },
+            Value::Effect(effect) => {
+                if let Some(effect) = rc::Rc::get_mut(effect) {
+                    match effect {
+                        Effect::Pure(value) => worklist.push(mem::replace(value, Value::Str(String::new()))),
+                        Effect::Println(value) => worklist.push(mem::replace(value, Value::Str(String::new()))),
+                        Effect::Bind(first, f) => {
+                            worklist.push(mem::replace(first, Value::Str(String::new())));
+                            worklist.push(mem::replace(f, Value::Str(String::new())));
+                        },
+                    }
+                }
+            },
+            Value::Str(_) | Value::Nat(_) => (),
+        }
+    }
+}
 
 impl fmt::Debug for Value {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -37,9 +127,11 @@ impl fmt::Debug for Value {
                 Ok(())
             },
             Value::Str(s) => write!(f, "{:?}", s),
+            Value::Nat(n) => write!(f, "{}", n),
             Value::Fun(_, _, _) => write!(f, "<fun>"),
-            Value::Prim(_) => write!(f, "<prim>"),
-            Value::Thunk(_, _) => write!(f, "<thunk>"),
+            Value::Prim(..) => write!(f, "<prim>"),
+            Value::Thunk(_) => write!(f, "<thunk>"),
+            Value::Effect(_) => write!(f, "<io>"),
         }
     }
 }
@@ -1,56 +1,65 @@
 use std::rc::Rc;
 
+use num_bigint::BigUint;
+
 pub type Var = String;
 pub type Ctor = String;
 pub type UpdateFlag = bool;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Program(pub Vec<Binding>);
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Binding(pub Var, pub LambdaForm);
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct LambdaForm(pub Vec<Var>, pub bool, pub Vec<Var>, pub Expr);
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Expr(Rc<ExprNode>);
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ExprNode {
     Let(LetType, Vec<Binding>, Expr),
     Case(Expr, Alts),
     App(AppType, Var, Vec<Atom>),
-    Lit(usize),
+    Lit(Lit),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Atom {
     Var(Var),
-    Lit(usize),
+    Lit(Lit),
+}
+
+/// An unboxed value. Quail's Nats are represented by Ints, so they may be of any size.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Lit {
+    Int(BigUint),
+    Str(Rc<str>),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum AppType {
     Fun,
     Ctor,
     Prim,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum LetType {
     Recursive,
     NonRecursive,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Alt {
     Ctor(Ctor, Vec<Var>, Expr),
-    Lit(usize, Expr),
+    Lit(Lit, Expr),
     Default(Var, Expr),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Alts(pub Vec<Alt>);
 
 impl From<&str> for Atom {
@@ -59,6 +68,27 @@ impl From<&str> for Atom {
     }
 }
 
+impl From<usize> for Lit {
+    fn from(k: usize) -> Self {
+        Lit::Int(BigUint::from(k))
+    }
+}
+
+impl From<&str> for Lit {
+    fn from(s: &str) -> Self {
+        Lit::Str(s.into())
+    }
+}
+
+impl std::fmt::Display for Lit {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        match self {
+            Lit::Int(k) => write!(f, "{}", k),
+            Lit::Str(s) => write!(f, "{:?}", s),
+        }
+    }
+}
+
 impl std::ops::Deref for Expr {
     type Target = ExprNode;
 
@@ -100,9 +130,9 @@ impl std::fmt::Display for Program {
         for Binding(var, lf) in self.0.iter() {
             write!(f, "{} = ", var)?;
             pprint_lf_header(f, lf)?;
-            writeln!(f, " -> ")?;
+            writeln!(f, " ->")?;
             write!(f, "    ")?;
-            pprint_exprnode(f, &lf.3, 0)?;
+            pprint_exprnode(f, &lf.3, 1)?;
             writeln!(f)?;
             writeln!(f)?;
         }
@@ -130,7 +160,12 @@ fn pprint_indent_prefix(f: &mut std::fmt::Formatter, indent: usize) -> Result<()
 
 fn pprint_exprnode(f: &mut std::fmt::Formatter, e: &ExprNode, indent: usize) -> Result<(), std::fmt::Error> {
     match e {
-        ExprNode::App(_app_type, fun, vs) => {
+        ExprNode::App(app_type, fun, vs) => {
+            match app_type {
+                AppType::Fun => (),
+                AppType::Ctor => write!(f, "con ")?,
+                AppType::Prim => write!(f, "prim ")?,
+            }
             write!(f, "{} ", fun)?;
             let args = vs.iter().map(|v| {
                 match v {
@@ -143,7 +178,7 @@ fn pprint_exprnode(f: &mut std::fmt::Formatter, e: &ExprNode, indent: usize) ->
         ExprNode::Let(let_type, bindings, e) => {
             match let_type {
                 LetType::NonRecursive => write!(f, "let ")?,
-                LetType::Recursive => write!(f, "letrc ")?,
+                LetType::Recursive => write!(f, "letrec ")?,
             }
 
             for (i, Binding(name, lf)) in bindings.iter().enumerate() {
@@ -159,10 +194,11 @@ fn pprint_exprnode(f: &mut std::fmt::Formatter, e: &ExprNode, indent: usize) ->
                 writeln!(f)?;
             }
             pprint_indent_prefix(f, indent + 1)?;
-            write!(f, "in {}", e.as_ref())?;
+            write!(f, "in ")?;
+            pprint_exprnode(f, e, indent + 1)?;
         },
         ExprNode::Case(e, alts) => {
-            writeln!(f, "case {} of", e.as_ref())?;
+            writeln!(f, "case {} of {{", e.as_ref())?;
             for (i, alt) in alts.0.iter().enumerate() {
                 pprint_indent_prefix(f, indent + 1)?;
                 match alt {
@@ -186,6 +222,8 @@ fn pprint_exprnode(f: &mut std::fmt::Formatter, e: &ExprNode, indent: usize) ->
                     writeln!(f)?;
                 }
             }
+            pprint_indent_prefix(f, indent)?;
+            write!(f, "}}")?;
         },
         ExprNode::Lit(k) => write!(f, "{}", k)?,
     }
@@ -222,10 +260,10 @@ impl Alts {
         self.find_alt_default()
     }
 
-    pub fn find_alt_for_int(&self,  k: usize) -> Option<&Alt> {
+    pub fn find_alt_for_lit(&self, k: &Lit) -> Option<&Alt> {
         for alt in self.0.iter() {
             if let Alt::Lit(j, _e) = alt {
-                if *j == k {
+                if j == k {
                     return Some(&alt);
                 }
             }
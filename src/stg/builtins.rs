@@ -0,0 +1,415 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::rc::Rc;
+
+use num_bigint::BigUint;
+use num_traits::ToPrimitive;
+use num_traits::Zero;
+
+use crate::ast::{Flavor, HoleInfo};
+use crate::tokenizer::Loc;
+use crate::runtime::{Cause, RuntimeError, TypeDef};
+
+use super::ast::*;
+use super::machine::{Closure, PrimOp, StgMachine, Value, Whnf};
+
+/// The constructors IO actions are made of. They are capitalized, so they can't clash with those of Quail types.
+pub const PURE: &str = "Pure";
+pub const PRINTLN: &str = "Println";
+pub const BIND: &str = "Bind";
+
+///
+/// Quail's builtins, written in STG. Those which work on Nats and Strs evaluate their arguments
+/// and pass the literals to a primop, such as `add#`. Nats are Ints, so `zero` and `succ` are
+/// builtins too, rather than constructors. The IO builtins only build up an IO action, which
+/// is carried out by `exec`.
+///
+pub fn builtin_bindings() -> Vec<Binding> {
+    vec![
+        fun("zero", &[], ExprNode::Lit(0.into()).into()),
+        strict_prim_binding("succ", "+1", 1),
+        strict_prim_binding("add", "add#", 2),
+        strict_prim_binding("mul", "mul#", 2),
+        strict_prim_binding("sub", "sub#", 2),
+        bool_prim_binding("eq", "eq#", 2),
+        strict_prim_binding("cat", "cat#", 2),
+        strict_prim_binding("str_length", "str_length#", 1),
+        bool_prim_binding("str_eq", "str_eq#", 2),
+        bool_prim_binding("str_lt", "str_lt#", 2),
+        strict_prim_binding("substr", "substr#", 3),
+        strict_prim_binding("chars", "chars#", 1),
+        strict_prim_binding("split", "split#", 2),
+        strict_prim_binding("parse_nat", "parse_nat#", 1),
+        strict_prim_binding("show_nat", "show_nat#", 1),
+        fun("show", &["x"], app(AppType::Prim, "show#", &["x"])),
+        fun("println", &["s"], app(AppType::Ctor, PRINTLN, &["s"])),
+        fun("pure", &["x"], app(AppType::Ctor, PURE, &["x"])),
+        fun("bind", &["m", "f"], app(AppType::Ctor, BIND, &["m", "f"])),
+        // then = {} \n {m, n} -> let f = {n} \n {x} -> n {} in Bind {m, f}
+        fun("then", &["m", "n"], ExprNode::Let(
+            LetType::NonRecursive,
+            vec![Binding("f".to_owned(), LambdaForm(vars(&["n"]), false, vars(&["x"]), app(AppType::Fun, "n", &[])))],
+            app(AppType::Ctor, BIND, &["m", "f"]),
+        ).into()),
+    ]
+}
+
+///
+/// The primops Quail's builtins are made of. They work the same as the prims of the Runtime.
+/// Those which answer a question, like `eq#`, return 1 for true and 0 for false.
+///
+/// `show#` looks up which type each constructor belongs to in the typedefs, and writes `...` for
+/// what lies more than `show_depth` constructors deep in a coinductive value.
+///
+pub fn primops(typedefs: &HashMap<String, TypeDef>, show_depth: usize) -> Vec<PrimOp> {
+    let mut ctor_types = HashMap::new();
+    for typedef in typedefs.values() {
+        for tag in typedef.ctor_tags() {
+            ctor_types.insert(tag, (typedef.name.clone(), typedef.flavor.clone()));
+        }
+    }
+    let shower = Rc::new(Shower { ctor_types, show_depth });
+
+    vec![
+        PrimOp::new("add#", 2, Rc::new(|_m, vs| Ok(Lit::Int(vs[0].as_int() + vs[1].as_int()).into()))),
+        PrimOp::new("mul#", 2, Rc::new(|_m, vs| Ok(Lit::Int(vs[0].as_int() * vs[1].as_int()).into()))),
+        // Subtraction on Nat stops at zero.
+        PrimOp::new("sub#", 2, Rc::new(|_m, vs| {
+            let (n, m) = (vs[0].as_int(), vs[1].as_int());
+            Ok(Lit::Int(if n > m { n - m } else { BigUint::zero() }).into())
+        })),
+        PrimOp::new("eq#", 2, Rc::new(|_m, vs| Ok(truth(vs[0].as_int() == vs[1].as_int())))),
+        PrimOp::new("cat#", 2, Rc::new(|_m, vs| Ok(Lit::Str(format!("{}{}", vs[0].as_str(), vs[1].as_str()).into()).into()))),
+        PrimOp::new("str_length#", 1, Rc::new(|_m, vs| Ok(Lit::from(vs[0].as_str().chars().count()).into()))),
+        PrimOp::new("str_eq#", 2, Rc::new(|_m, vs| Ok(truth(vs[0].as_str() == vs[1].as_str())))),
+        PrimOp::new("str_lt#", 2, Rc::new(|_m, vs| Ok(truth(vs[0].as_str() < vs[1].as_str())))),
+        // Neither the start nor the count can be larger than the length of a string in memory,
+        // so anything bigger is as good as usize::MAX.
+        PrimOp::new("substr#", 3, Rc::new(|_m, vs| {
+            let start = vs[1].as_int().to_usize().unwrap_or(usize::MAX);
+            let count = vs[2].as_int().to_usize().unwrap_or(usize::MAX);
+            Ok(Lit::Str(vs[0].as_str().chars().skip(start).take(count).collect::<String>().into()).into())
+        })),
+        PrimOp::new("chars#", 1, Rc::new(|m, vs| {
+            let chars = vs[0].as_str().chars().map(String::from);
+            Ok(alloc_str_list(m, chars.collect()))
+        })),
+        // An empty separator splits the string into its characters.
+        PrimOp::new("split#", 2, Rc::new(|m, vs| {
+            let (separator, s) = (vs[0].as_str(), vs[1].as_str());
+            let parts = if separator.is_empty() {
+                s.chars().map(String::from).collect()
+            } else {
+                s.split(separator).map(String::from).collect()
+            };
+            Ok(alloc_str_list(m, parts))
+        })),
+        PrimOp::new("parse_nat#", 1, Rc::new(|m, vs| {
+            let s = vs[0].as_str();
+            if !s.is_empty() && s.chars().all(|ch| ch.is_ascii_digit()) {
+                let n = s.parse::<BigUint>().expect("Digits should parse as a Nat");
+                Ok(Value::Addr(m.alloc_ctor("some", vec![Lit::Int(n).into()])))
+            } else {
+                Ok(Value::Addr(m.alloc_ctor("none", vec![])))
+            }
+        })),
+        PrimOp::new("show_nat#", 1, Rc::new(|_m, vs| Ok(Lit::Str(vs[0].as_int().to_string().into()).into()))),
+        PrimOp::new("show#", 1, Rc::new(move |m, vs| {
+            let mut out = String::new();
+            shower.write_value(m, vs[0].clone(), &mut out)?;
+            Ok(Lit::Str(out.into()).into())
+        })),
+        // A hole is described by its id, its name, and the path, line and column of its location,
+        // where an empty name or path stands for none.
+        PrimOp::new("hole#", 5, Rc::new(|_m, vs| {
+            let name = Some(vs[1].as_str().to_string()).filter(|name| !name.is_empty());
+            let loc = Loc {
+                path: Some(vs[2].as_str().to_string()).filter(|path| !path.is_empty()),
+                line: vs[3].as_int().to_usize().expect("The line of a hole should fit in a usize"),
+                col: vs[4].as_int().to_usize().expect("The column of a hole should fit in a usize"),
+            };
+            let hole_id = vs[0].as_int().to_usize().expect("The id of a hole should fit in a usize");
+            Err(Cause::UnfilledHole(HoleInfo::new(hole_id, name, None, loc)).into())
+        })),
+    ]
+}
+
+fn truth(b: bool) -> Value {
+    Lit::from(b as usize).into()
+}
+
+/// Builds a list of Strs in the heap.
+fn alloc_str_list(machine: &mut StgMachine, strs: Vec<String>) -> Value {
+    let mut list = machine.alloc_ctor("nil", vec![]);
+    for s in strs.into_iter().rev() {
+        list = machine.alloc_ctor("cons", vec![Lit::Str(s.into()).into(), Value::Addr(list)]);
+    }
+    Value::Addr(list)
+}
+
+///
+/// Runs a program by carrying out the effects described by the IO action `main` evaluates to,
+/// as `Runtime::perform` does, and returns its result. It fails the same way `perform` does.
+///
+pub fn exec(machine: &mut StgMachine, main: &str) -> Result<Value, RuntimeError> {
+    // If an action fails, the continuations still waiting for it are no longer held on to.
+    let pins = machine.pinned.len();
+    let result = perform(machine, main);
+    machine.pinned.truncate(pins);
+    result
+}
+
+fn perform(machine: &mut StgMachine, main: &str) -> Result<Value, RuntimeError> {
+    let main = machine.lookup_global_addr(main)
+        .ok_or_else(|| RuntimeError::from(format!("There should be a {} in your module", main)))?;
+    let top = machine.lookup_global_addr("top").expect("There should be a binding for top");
+
+    // The functions which still need to be applied to the result of the current action. They are pinned until then.
+    let mut continuations = vec![];
+    let mut io = Value::Addr(main);
+
+    loop {
+        let result = match machine.whnf(io)? {
+            Whnf::Ctor(tag, mut ws) if tag == PURE && ws.len() == 1 => ws.remove(0),
+            Whnf::Ctor(tag, ws) if tag == PRINTLN && ws.len() == 1 => {
+                let s = machine.whnf(ws[0].clone())?;
+                match s {
+                    Whnf::Lit(Lit::Str(s)) => println!("{}", s),
+                    _ => {
+                        let message = format!("expected a Str, but found {:?}", s);
+                        return Err(Cause::Prim("println".to_string(), message).into());
+                    },
+                }
+                Value::Addr(top)
+            },
+            Whnf::Ctor(tag, mut ws) if tag == BIND && ws.len() == 2 => {
+                let f = ws.pop().unwrap();
+                if let Value::Addr(a) = f {
+                    machine.pinned.push(a);
+                }
+                continuations.push(f);
+                io = ws.pop().unwrap();
+                continue;
+            },
+            whnf => return Err(Cause::NotAnEffect(format!("{:?}", whnf)).into()),
+        };
+
+        match continuations.pop() {
+            Some(f) => {
+                if let Value::Addr(_) = f {
+                    machine.pinned.pop();
+                }
+                // The result is passed to f by a thunk: {f, x} \u {} -> f {x}
+                let lf = LambdaForm(vars(&["f", "x"]), true, vec![], app(AppType::Fun, "f", &["x"]));
+                io = Value::Addr(machine.alloc(Closure(lf, vec![f, result])));
+            },
+            None => return Ok(result),
+        }
+    }
+}
+
+/// Renders values the way `Runtime::show` does.
+struct Shower {
+    /// The name and flavor of the type each constructor belongs to.
+    ctor_types: HashMap<Ctor, (String, Flavor)>,
+    show_depth: usize,
+}
+
+/// What is left to be written, innermost last. It is the same as for `Runtime::show`.
+enum Task {
+    /// A value, along with how many coinductive constructors it is inside of, and whether it is
+    /// nested inside of a constructor, in which case it is parenthesized if it has contents of its own.
+    Value(Value, usize, bool),
+    /// The rest of a list whose first element has been written.
+    List(Value, usize),
+    Text(&'static str),
+}
+
+impl Shower {
+    ///
+    /// Writes the value to `out`. What remains to be written is kept on a worklist rather than
+    /// the native stack, so a value can be as deep as memory allows.
+    ///
+    /// The host is holding on to the values on the worklist, so they mustn't be collected while
+    /// they wait. Each one is pinned while it is on the worklist, in the same order.
+    ///
+    fn write_value(&self, machine: &mut StgMachine, value: Value, out: &mut String) -> Result<(), RuntimeError> {
+        let pins = machine.pinned.len();
+        let written = self.write_tasks(machine, value, out);
+        machine.pinned.truncate(pins);
+        written
+    }
+
+    fn write_tasks(&self, machine: &mut StgMachine, value: Value, out: &mut String) -> Result<(), RuntimeError> {
+        let mut tasks = vec![];
+        push_task(machine, &mut tasks, Task::Value(value, 0, false));
+        while let Some(task) = tasks.pop() {
+            match task {
+                Task::Value(value, codepth, nested) => {
+                    if addr(&value).is_some() {
+                        machine.pinned.pop();
+                    }
+                    self.write_one(machine, value, codepth, nested, &mut tasks, out)?;
+                },
+                Task::List(tail, codepth) => {
+                    if addr(&tail).is_some() {
+                        machine.pinned.pop();
+                    }
+                    match machine.whnf(tail)? {
+                        Whnf::Ctor(tag, ws) => write_list_node(machine, tag, ws, codepth, false, &mut tasks, out),
+                        _ => out.push(']'),
+                    }
+                },
+                Task::Text(text) => out.push_str(text),
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes as much of the value as it can right away, and leaves its contents for later.
+    fn write_one(
+        &self,
+        machine: &mut StgMachine,
+        value: Value,
+        codepth: usize,
+        nested: bool,
+        tasks: &mut Vec<Task>,
+        out: &mut String,
+    ) -> Result<(), RuntimeError> {
+        let (tag, ws) = match machine.whnf(value)? {
+            Whnf::Lit(k) => {
+                write!(out, "{}", k).unwrap();
+                return Ok(());
+            },
+            Whnf::Fun => {
+                out.push_str("<function>");
+                return Ok(());
+            },
+            Whnf::Ctor(tag, _ws) if tag == PURE || tag == PRINTLN || tag == BIND => {
+                out.push_str("<IO action>");
+                return Ok(());
+            },
+            Whnf::Ctor(tag, ws) => (tag, ws),
+        };
+
+        let (name, flavor) = match self.ctor_types.get(&tag) {
+            Some((name, flavor)) => (name.as_str(), flavor.clone()),
+            None => ("", Flavor::Inductive),
+        };
+        if name == "List" {
+            out.push('[');
+            write_list_node(machine, tag, ws, codepth, true, tasks, out);
+            return Ok(());
+        }
+
+        let codepth = match flavor {
+            Flavor::Inductive => codepth,
+            Flavor::Coinductive if codepth >= self.show_depth => {
+                out.push_str("...");
+                return Ok(());
+            },
+            Flavor::Coinductive => codepth + 1,
+        };
+
+        let parenthesize = nested && !ws.is_empty();
+        if parenthesize {
+            out.push('(');
+            tasks.push(Task::Text(")"));
+        }
+        out.push_str(&tag);
+        for w in ws.into_iter().rev() {
+            push_task(machine, tasks, Task::Value(w, codepth, true));
+            tasks.push(Task::Text(" "));
+        }
+        Ok(())
+    }
+}
+
+/// Writes a node of a list's spine, which has already been evaluated, leaving its head and the rest of the list for later.
+fn write_list_node(
+    machine: &mut StgMachine,
+    tag: Ctor,
+    mut ws: Vec<Value>,
+    codepth: usize,
+    first: bool,
+    tasks: &mut Vec<Task>,
+    out: &mut String,
+) {
+    if tag == "cons" && ws.len() == 2 {
+        let tail = ws.pop().unwrap();
+        let head = ws.pop().unwrap();
+        if !first {
+            out.push_str(", ");
+        }
+        push_task(machine, tasks, Task::List(tail, codepth));
+        push_task(machine, tasks, Task::Value(head, codepth, false));
+    } else {
+        out.push(']');
+    }
+}
+
+/// Puts a task on the worklist, pinning the value it holds.
+fn push_task(machine: &mut StgMachine, tasks: &mut Vec<Task>, task: Task) {
+    match &task {
+        Task::Value(value, ..) | Task::List(value, _) => machine.pinned.extend(addr(value)),
+        Task::Text(_) => (),
+    }
+    tasks.push(task);
+}
+
+fn addr(value: &Value) -> Option<usize> {
+    match value {
+        Value::Addr(a) => Some(*a),
+        Value::Lit(_) => None,
+    }
+}
+
+fn vars(names: &[&str]) -> Vec<Var> {
+    names.iter().map(|name| name.to_string()).collect()
+}
+
+fn app(app_type: AppType, f: &str, args: &[&str]) -> Expr {
+    ExprNode::App(app_type, f.to_owned(), args.iter().map(|&arg| arg.into()).collect()).into()
+}
+
+/// A top-level function: {} \n {params} -> body
+fn fun(name: &str, params: &[&str], body: Expr) -> Binding {
+    Binding(name.to_owned(), LambdaForm(vec![], false, vars(params), body))
+}
+
+///
+/// The parameters and body of a function which evaluates each of its arguments, and then passes
+/// the literals to a primop: {} \n {a0, a1} -> case a0 {} of k0 -> case a1 {} of k1 -> prim {k0, k1}
+///
+fn strict_prim(prim: &str, arity: usize) -> (Vec<Var>, Expr) {
+    let params: Vec<Var> = (0..arity).map(|i| format!("a{}", i)).collect();
+    let literals: Vec<Var> = (0..arity).map(|i| format!("k{}", i)).collect();
+    let literal_refs: Vec<&str> = literals.iter().map(String::as_str).collect();
+
+    let mut body = app(AppType::Prim, prim, &literal_refs);
+    for (param, literal) in params.iter().zip(literals.iter()).rev() {
+        body = ExprNode::Case(
+            app(AppType::Fun, param, &[]),
+            Alts(vec![Alt::Default(literal.clone(), body)]),
+        ).into();
+    }
+    (params, body)
+}
+
+fn strict_prim_binding(name: &str, prim: &str, arity: usize) -> Binding {
+    let (params, body) = strict_prim(prim, arity);
+    Binding(name.to_owned(), LambdaForm(vec![], false, params, body))
+}
+
+/// Like `strict_prim_binding`, but for a primop which answers a question, which is turned into a Bool.
+fn bool_prim_binding(name: &str, prim: &str, arity: usize) -> Binding {
+    let (params, body) = strict_prim(prim, arity);
+    let body = ExprNode::Case(
+        body,
+        Alts(vec![
+            Alt::Lit(0.into(), app(AppType::Ctor, "false", &[])),
+            Alt::Default("r".to_owned(), app(AppType::Ctor, "true", &[])),
+        ]),
+    ).into();
+    Binding(name.to_owned(), LambdaForm(vec![], false, params, body))
+}
@@ -1,11 +1,19 @@
 use std::collections::HashMap;
+use std::collections::HashSet;
 
-use super::machine::{Closure, Addr};
+use super::machine::{Closure, Addr, Value};
+
+/// The fewest closures the heap holds before it is worth collecting.
+const DEFAULT_MIN_GC_THRESHOLD: usize = 1 << 16;
 
 #[derive(Debug, Clone)]
 pub struct Heap {
     map: HashMap<Addr, Closure>,
     next_addr: Addr,
+    /// Once the heap holds this many closures, it is due to be collected.
+    gc_threshold: usize,
+    /// The threshold never drops below this, so that small heaps aren't collected over and over.
+    min_gc_threshold: usize,
  }
 
 impl Heap {
@@ -13,9 +21,26 @@ impl Heap {
         Heap {
             map: HashMap::new(),
             next_addr: 0 as Addr,
+            gc_threshold: DEFAULT_MIN_GC_THRESHOLD,
+            min_gc_threshold: DEFAULT_MIN_GC_THRESHOLD,
         }
     }
 
+    /// Changes how many closures the heap may hold before it is first collected.
+    pub fn set_min_gc_threshold(&mut self, min_gc_threshold: usize) {
+        self.min_gc_threshold = min_gc_threshold;
+        self.gc_threshold = min_gc_threshold.max(self.map.len());
+    }
+
+    /// The number of closures in the heap, including garbage which hasn't been collected yet.
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
     // TODO all lookup functions should return Option types.
     pub fn lookup_mut(&mut self, addr: Addr) -> &mut Closure {
         self.map.get_mut(&addr).unwrap()
@@ -35,11 +60,11 @@ impl Heap {
         closures
     }
 
+    ///
+    /// Allocates a closure. The heap is never collected here, since the closure may not be
+    /// reachable from the roots yet. Instead, the machine checks `should_gc` between steps.
+    ///
     pub fn alloc(&mut self, closure: Closure) -> Addr {
-        if self.should_gc() {
-            self.gc();
-        }
-
         let addr = self.next_addr;
         self.next_addr += 1;
 
@@ -47,12 +72,37 @@ impl Heap {
         addr
     }
 
-    fn should_gc(&self) -> bool {
-        false
+    /// Whether the heap has grown enough since it was last collected that it should be collected again.
+    pub fn should_gc(&self) -> bool {
+        self.map.len() >= self.gc_threshold
     }
 
-    pub fn gc(&mut self) {
-        todo!()
+    ///
+    /// Collects every closure which can't be reached from the given roots, by marking those which
+    /// can and then sweeping away the rest. Addresses don't change, so the roots stay valid.
+    ///
+    /// Afterwards, the heap may grow to twice what survived before it is due to be collected again.
+    /// This way, the time spent collecting stays in proportion to the number of allocations.
+    ///
+    pub fn gc(&mut self, roots: impl IntoIterator<Item = Addr>) {
+        let mut marked = HashSet::new();
+        let mut worklist: Vec<Addr> = roots.into_iter().collect();
+
+        while let Some(addr) = worklist.pop() {
+            if marked.insert(addr) {
+                let Closure(_lf, ws) = self.lookup(addr);
+                for w in ws {
+                    if let Value::Addr(a) = w {
+                        if !marked.contains(a) {
+                            worklist.push(*a);
+                        }
+                    }
+                }
+            }
+        }
+
+        self.map.retain(|addr, _closure| marked.contains(addr));
+        self.gc_threshold = self.min_gc_threshold.max(2 * self.map.len());
     }
 }
 
@@ -1,14 +1,42 @@
+use std::mem;
 use std::rc::Rc;
 
+use num_bigint::BigUint;
+
+use crate::runtime::RuntimeError;
+
 use super::heap::Heap;
 use super::ast::*;
+use super::trace::{Event, QuietTracer, Tracer};
 
-const DEBUG: bool = true;
-
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub enum Value {
     Addr(usize),
-    Int(usize),
+    Lit(Lit),
+}
+
+impl From<Lit> for Value {
+    fn from(k: Lit) -> Self {
+        Value::Lit(k)
+    }
+}
+
+impl Value {
+    /// The Int held by an unboxed value. Primops use this to read their arguments.
+    pub fn as_int(&self) -> &BigUint {
+        match self {
+            Value::Lit(Lit::Int(k)) => k,
+            _ => panic!("Expected an Int, but found {:?}", self),
+        }
+    }
+
+    /// The Str held by an unboxed value. Primops use this to read their arguments.
+    pub fn as_str(&self) -> &str {
+        match self {
+            Value::Lit(Lit::Str(s)) => s,
+            _ => panic!("Expected a Str, but found {:?}", self),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -93,10 +121,31 @@ pub enum Instr {
     Eval(Expr, Context),
     Enter(Addr),
     RetCtor(Ctor, Vec<Value>),
-    RetInt(usize),
+    RetLit(Lit),
 }
 
-type PrimFn = Rc<dyn Fn(&[usize]) -> usize>;
+///
+/// The weak head normal form of a value, as found by `StgMachine::whnf`: a constructor applied
+/// to its arguments, an unboxed literal, or a function, which can't be looked into any further.
+///
+#[derive(Debug, Clone)]
+pub enum Whnf {
+    Ctor(Ctor, Vec<Value>),
+    Lit(Lit),
+    Fun,
+}
+
+/// An evaluation set aside while the host evaluates something else, to be picked up again afterwards.
+#[derive(Debug, Clone)]
+struct Suspended(Option<Instr>, ArgStack, RetStack, UpdStack);
+
+///
+/// The code of a primop. It is given the machine, so that primops such as `show#` may evaluate
+/// the closures they are passed with `StgMachine::whnf`. Most primops only take literals, and
+/// return one, but a primop may also build a data structure in the heap and return its address,
+/// as `chars#` does. A primop which fails, as `hole#` always does, halts the machine.
+///
+type PrimFn = Rc<dyn Fn(&mut StgMachine, &[Value]) -> Result<Value, RuntimeError>>;
 
 #[derive(Clone)]
 pub struct PrimOp {
@@ -113,7 +162,7 @@ pub struct PrimOp {
 }
 
 impl PrimOp {
-    pub fn new(name: &str, arg_count: usize, op: Rc<dyn Fn(&[usize]) -> usize>) -> Self {
+    pub fn new(name: &str, arg_count: usize, op: PrimFn) -> Self {
         PrimOp {
             name: name.to_string(),
             arg_count,
@@ -121,13 +170,13 @@ impl PrimOp {
         }
     }
 
-    pub fn apply(&self, args: &[usize]) -> usize {
+    pub fn apply(&self, machine: &mut StgMachine, args: &[Value]) -> Result<Value, RuntimeError> {
         assert_eq!(args.len(), self.arg_count);
-        (*self.op)(args)
+        (*self.op)(machine, args)
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct StgMachine {
     pub instr: Option<Instr>,
 //    pub ctors: Vec<Var>
@@ -137,6 +186,15 @@ pub struct StgMachine {
     pub ret_stack: RetStack,
     pub upd_stack: UpdStack,
     pub heap: Heap,
+    /// Addresses the host is holding on to, such as the closure being forced by `seq`.
+    /// The garbage collector keeps these, along with everything else reachable from them.
+    pub pinned: Vec<Addr>,
+    /// The evaluations suspended by `whnf`, innermost last.
+    suspended: Vec<Suspended>,
+    /// Told about each step the machine takes. By default, nothing is done with them.
+    pub tracer: Box<dyn Tracer>,
+    /// Why the machine halted, if it was because a primop failed. `whnf` takes it to return.
+    pub error: Option<RuntimeError>,
 }
 
 impl StgMachine {
@@ -167,10 +225,10 @@ impl StgMachine {
         };
 
         let primops = vec![
-            PrimOp::new("+1", 1, Rc::new(|vs| vs[0] + 1)),
-            PrimOp::new("-1", 1, Rc::new(|vs| vs[0] - 1)),
-            PrimOp::new("+", 2, Rc::new(|vs| vs[0] + vs[1])),
-            PrimOp::new("halt", 0, Rc::new(|_vs| panic!("HALT"))),
+            PrimOp::new("+1", 1, Rc::new(|_m, vs| Ok(Lit::Int(vs[0].as_int() + 1u32).into()))),
+            PrimOp::new("-1", 1, Rc::new(|_m, vs| Ok(Lit::Int(vs[0].as_int() - 1u32).into()))),
+            PrimOp::new("+", 2, Rc::new(|_m, vs| Ok(Lit::Int(vs[0].as_int() + vs[1].as_int()).into()))),
+            PrimOp::new("halt", 0, Rc::new(|_m, _vs| panic!("HALT"))),
         ];
 
         StgMachine {
@@ -181,22 +239,55 @@ impl StgMachine {
             globals,
             heap,
             instr,
+            pinned: vec![],
+            suspended: vec![],
+            tracer: Box::new(QuietTracer),
+            error: None,
         }
     }
 
+    ///
+    /// Allocates a closure which returns the constructor applied to the values, the same as the
+    /// closure a thunk is updated with once it has returned a constructor.
+    ///
+    pub fn alloc_ctor(&mut self, c: &str, ws: Vec<Value>) -> Addr {
+        let vs = (0..ws.len()).map(|i| format!("gensym_v{}", i)).collect::<Vec<Var>>();
+        let e = ExprNode::App(
+            AppType::Ctor,
+            c.to_owned(),
+            vs.iter().map(|v| Atom::Var(v.clone())).collect(),
+        ).into();
+        self.alloc(Closure(LambdaForm(vs, false, vec![], e), ws))
+    }
+
+    /// Allocates a closure in the heap.
+    pub fn alloc(&mut self, closure: Closure) -> Addr {
+        let a = self.heap.alloc(closure);
+        self.tracer.trace(&Event::Alloc(a));
+        a
+    }
+
     pub fn is_halted(&self) -> bool {
         self.instr.is_none()
     }
 
     pub fn step(&mut self) {
-        debug("*******************************************************************************");
+        // Between steps, every live closure is reachable from the roots, so this is a safe time to collect.
+        if self.heap.should_gc() {
+            self.gc();
+        }
         if let Some(instr) = self.instr.clone() {
-            debug(&format!("INSTR: {}", &instr));
+            self.tracer.trace(&match &instr {
+                Instr::Eval(e, _p) => Event::Eval(e),
+                Instr::Enter(addr) => Event::Enter(*addr),
+                Instr::RetCtor(c, vs) => Event::RetCtor(c, vs),
+                Instr::RetLit(k) => Event::RetLit(k),
+            });
             match instr {
                 Instr::Eval(e, p) => self.step_eval(e, p),
                 Instr::Enter(addr) => self.step_enter(addr),
                 Instr::RetCtor(c, vs) => self.step_retctor(&c, vs.as_slice()),
-                Instr::RetInt(k) => self.step_retint(k),
+                Instr::RetLit(k) => self.step_retlit(k),
             }
         }
     }
@@ -208,42 +299,35 @@ impl StgMachine {
                 match self.lookup_var(&f, &p) {
                     Value::Addr(a) => {
                         // case 1
-                        debug(&format!("LOOKING UP ATOMS {:?} in CONTEXT {:?}", &vs, &p));
                         let mut args = self.lookup_atoms(&vs, &p);
                         args.reverse();
-                        debug(&format!("PUSHING ARGUMENTS {:?}", &args));
                         self.arg_stack.extend(args);
                         Some(Instr::Enter(a))
                     },
-                    Value::Int(k) => {
+                    Value::Lit(k) => {
                         // case 10
                         assert!(vs.is_empty(), "Cannot Eval a boxed value with parameters or something");
-                        Some(Instr::RetInt(k))
+                        Some(Instr::RetLit(k))
                     },
                 }
             },
             ExprNode::App(AppType::Ctor, c, vs) => {
                 // case 5
-                debug(&format!("LOOKING UP ATOMS {:?} in CONTEXT {:?}", &vs, &p));
                 Some(Instr::RetCtor(c.clone(), self.lookup_atoms(&vs, &p)))
             },
             ExprNode::App(AppType::Prim, f, vs) => {
                 // case 14
-                debug(&format!("LOOKING UP PRIMOP {:?}", &f));
-                let primop = self.lookup_prim(&f).expect(&format!("Unknonwn primop referenced: {:?}.", f));
-
-                debug(&format!("LOOKING UP ATOMS {:?} in CONTEXT {:?}", &vs, &p));
-                let vals = self.lookup_atoms(&vs, &p)
-                    .iter()
-                    .map(|val| {
-                        match val {
-                            Value::Int(k) => *k,
-                            Value::Addr(a) => panic!("Unexpected address {} found as argument to primop {:?}", a, primop)
-                        }
-                    }).collect::<Vec<_>>();
-                let k = primop.apply(&vals);
-                debug(&format!("RESULT OF PRIMOP: {}", k));
-                Some(Instr::RetInt(k))
+                let primop = self.lookup_prim(f).unwrap_or_else(|| panic!("Unknonwn primop referenced: {:?}.", f)).clone();
+
+                let vals = self.lookup_atoms(vs, &p);
+                match primop.apply(self, &vals) {
+                    Ok(Value::Lit(k)) => Some(Instr::RetLit(k)),
+                    Ok(Value::Addr(a)) => Some(Instr::Enter(a)),
+                    Err(error) => {
+                        self.error = Some(error);
+                        None
+                    },
+                }
             },
             ExprNode::Let(let_type, bindings, e) => {
                 // case 3
@@ -253,9 +337,7 @@ impl StgMachine {
                 for Binding(var, lf) in bindings {
                     let closure = Closure(lf.clone(), vec![]);
 
-                    debug(&format!("ALLOCATING CLOSURE {:?}", &closure));
-                    let a = self.heap.alloc(closure);
-                    debug(&format!("ADDRESS: {}", a));
+                    let a = self.alloc(closure);
                     addrs.push(a);
                     vars.push(var.to_string());
                 }
@@ -272,9 +354,7 @@ impl StgMachine {
                     let Closure(LambdaForm(vs, _pi, _xs, _e), _ws_empty) = self.heap.lookup(*a);
                     let vs: Vec<&Var> = vs.iter().map(|v| v).collect();
 
-                    debug(&format!("LOOKING UP {:?} in CONTEXT {:?}", &vs, &p_rhs));
                     let new_ws = self.lookup_vars(&vs, &p_rhs);
-                    debug(&format!("CLOSURE CLOSES OVER {:?}", &new_ws));
 
                     let Closure(LambdaForm(_vs, _pi, _xs, _e), ref mut ws) = self.heap.lookup_mut(*a);
                     *ws = new_ws;
@@ -284,13 +364,13 @@ impl StgMachine {
             },
             ExprNode::Case(e, alts) => {
                 // case 4
-                debug(&format!("PUSHING CONTINUATION ONTO STACK {:?} {}", &alts, &p));
                 self.ret_stack.push(Continuation(alts.clone(), p.clone()));
+                self.tracer.trace(&Event::PushContinuation(self.ret_stack.len()));
                 Some(Instr::Eval(e.clone(), p.clone()))
             },
             ExprNode::Lit(k) => {
                 // case 9
-                Some(Instr::RetInt(*k))
+                Some(Instr::RetLit(k.clone()))
             },
         }
     }
@@ -298,25 +378,18 @@ impl StgMachine {
     fn step_enter(&mut self, addr: Addr)  {
         // handles cases: 2 15 17
         let closure = self.heap.lookup(addr);
-        let global_name = self.lookup_global_name_from_addr(addr).unwrap_or_default();
-        debug(&format!("LOOKING UP IN HEAP AT {} [{}]", &addr, &global_name));
-        debug(&format!("FOUND: {:?}", &closure));
 
         if !closure.is_updatable() {
-            debug(&format!("NOT UPDATABLE"));
             let Closure(LambdaForm(vs, _pi, xs, e), ws_f) = closure;
 
-            debug(&format!("ARE THERE ENOUGH ARGS ON THE STACK?"));
             if  self.arg_stack.len() >= xs.len() {
                 // enough args on arg stack
                 // case 2
-                debug(&format!("YES, THERE ARE ENOUGH ARGS"));
 
                 let mut args = Vec::new();
                 for _ in 0..xs.len() {
                     args.push(self.arg_stack.pop().unwrap()); // len() check above guarantees this is safe
                 }
-                debug(&format!("POP {} ARGS OFF THE ARG STACK: {:?}", xs.len(), &args));
                 let p = Context::from(vs.clone(), ws_f.clone())
                     .extend_many(xs.clone(), args);
 
@@ -324,29 +397,32 @@ impl StgMachine {
             } else {
                 // not enough args on arg stack
                 // case 17
-                debug(&format!("NO, THERE ARE INSUFFICIENT ARGS"));
-                let Closure(LambdaForm(vs, _pi, xs, _e), ws_f) = self.heap.lookup(addr).clone();
+                let Closure(LambdaForm(vs, _pi, xs, e), ws_f) = self.heap.lookup(addr).clone();
                 assert!(xs.len() > 0); // only applies if the number of arguments #xs is greater than zero, so the closure being entered will be non-updatable
-                let (xs1, _xs2) = xs.split_at(self.arg_stack.len());
+                let (xs1, xs2) = xs.split_at(self.arg_stack.len());
 
                 assert!(self.ret_stack.is_empty()); // TODO why?
 
                 match self.upd_stack.pop() {
                     Some(UpdateFrame(as_u, rs_u, a_u)) => {
                         assert_ne!(addr, a_u); // TODO I'm just curious to see if this ever happens
-                        let Closure(LambdaForm(ref mut closure_vs, _pi, _xs, _e), ref mut closure_ws) = self.heap.lookup_mut(a_u);
 
-                        let mut new_arg_stack = self.arg_stack.clone();
-                        new_arg_stack.extend(as_u.iter());
-                        let orig_arg_stack = self.arg_stack.clone();
+                        // The first argument is on top of the stack, which is the end of the Vec.
+                        let args: Vec<Value> = self.arg_stack.iter().rev().cloned().collect();
+
+                        // The arguments on the stack go on top of those saved in the update frame.
+                        let mut new_arg_stack = as_u;
+                        new_arg_stack.append(&mut self.arg_stack);
 
                         self.ret_stack = rs_u;
                         self.arg_stack = new_arg_stack;
 
-                        *closure_vs = vs.iter().chain(xs1.iter()).map(|s| s.to_owned()).collect();
-                        *closure_ws = ws_f.iter().chain(orig_arg_stack.iter()).cloned().collect();
-
-                        closure_vs.extend(xs1.iter().map(|s| s.to_owned()));
+                        // The closure is updated with the partial application: (vs ++ xs1) \n xs2 -> e
+                        let closure_vs = vs.iter().chain(xs1.iter()).cloned().collect();
+                        let closure_ws = ws_f.iter().cloned().chain(args).collect();
+                        let closure = Closure(LambdaForm(closure_vs, false, xs2.to_vec(), e), closure_ws);
+                        *self.heap.lookup_mut(a_u) = closure;
+                        self.tracer.trace(&Event::Update(a_u));
                         // self.instr stays as Enter a
                     },
                     None => {
@@ -357,7 +433,6 @@ impl StgMachine {
         } else {
             // if updatable
             // case 15
-            debug(&format!("UPDATABLE"));
             let Closure(LambdaForm(vs, _pi, _xs, e), ws_f) = closure;
             self.upd_stack.push(UpdateFrame(self.arg_stack.clone(), self.ret_stack.clone(), addr));
             self.arg_stack = vec![];
@@ -375,14 +450,10 @@ impl StgMachine {
             None => {
                 // case 16
                 assert!(self.arg_stack.is_empty()); // TODO explain why
-                debug(&format!("POP FROM ARG STACK"));
                 match self.upd_stack.pop() {
                     Some(UpdateFrame(as_u, rs_u, a_u)) => {
                         // replace the closure at a_u with a standard constructor closure
                         let closure = self.heap.lookup_mut(a_u);
-                        debug(&format!("LOOKUP IN HEAP AT {} FOUND {:?}", &a_u, &closure));
-                        debug(&format!("RESTORING ARG STACK {:?}", &as_u));
-                        debug(&format!("RESTORING ARG STACK {:?}", &rs_u));
                         self.arg_stack = as_u;
                         self.ret_stack = rs_u;
 
@@ -396,7 +467,7 @@ impl StgMachine {
                         ).into();
 
                         *closure = Closure(LambdaForm(vs, pi, xs, e), ws.iter().cloned().collect());
-                        debug(&format!("OVERWRITE CLOSURE WITH {:?}", &closure));
+                        self.tracer.trace(&Event::Update(a_u));
 
                         // no change to instruction
                         self.instr.clone()
@@ -405,20 +476,16 @@ impl StgMachine {
                 }
             },
             Some(Continuation(alts, ctx)) => {
-                debug(&format!("POPPING CONTINUATION FROM RET STACK"));
-                debug(&format!("CTX IS: {:?}", &ctx));
-                debug(&format!("FINDING MATCHING ALT"));
+                self.tracer.trace(&Event::PopContinuation(self.ret_stack.len()));
                 match alts.find_alt_for_ctor(c) {
-                    Some(Alt::Ctor(ctor_tag, vars, e)) => {
+                    Some(Alt::Ctor(_ctor_tag, vars, e)) => {
                         // case 6
-                        debug(&format!("USING {} {:?} => {}", &ctor_tag, &vars, &e));
                         let new_ctx = Context::from(vars.to_owned(), ws.to_owned()).concat(&ctx);
                         Some(Instr::Eval(e.clone(), new_ctx))
                     },
                     Some(Alt::Default(_var, e)) => {
                         // case 7
                         // TODO todo!() var isn't actually BOUND here!
-                        debug(&format!("USING DEFAULT"));
                         Some(Instr::Eval(e.clone(), ctx))
                     }
                     _ => unreachable!(),
@@ -427,20 +494,40 @@ impl StgMachine {
         }
     }
 
-    fn step_retint(&mut self, k: usize) {
+    fn step_retlit(&mut self, k: Lit) {
         // handles cases 11 12 13
         // TODO doesn't handle case 13 (non-binding default). This is unnecessary.
         self.instr = match self.ret_stack.pop() {
-            None => None, // halt
+            None => {
+                // Unlike in the paper, where thunks are never of unboxed type, a thunk may evaluate to
+                // a literal here. It is updated with a closure which returns the literal right away.
+                match self.upd_stack.pop() {
+                    Some(UpdateFrame(as_u, rs_u, a_u)) => {
+                        self.arg_stack = as_u;
+                        self.ret_stack = rs_u;
+
+                        let v = "gensym_v0".to_owned();
+                        let e = ExprNode::App(AppType::Fun, v.clone(), vec![]).into();
+                        let closure = self.heap.lookup_mut(a_u);
+                        *closure = Closure(LambdaForm(vec![v], false, vec![], e), vec![Value::Lit(k)]);
+                        self.tracer.trace(&Event::Update(a_u));
+
+                        // no change to instruction
+                        self.instr.clone()
+                    },
+                    None => None, // halt
+                }
+            },
             Some(Continuation(alts, ctx)) => {
-                match alts.find_alt_for_int(k) {
+                self.tracer.trace(&Event::PopContinuation(self.ret_stack.len()));
+                match alts.find_alt_for_lit(&k) {
                     Some(Alt::Lit(_n, e)) => {
                         // case 11
                         Some(Instr::Eval(e.clone(), ctx))
                     },
                     Some(Alt::Default(var, e)) => {
                         // case 12
-                        let new_ctx = ctx.extend_many(vec![var.to_owned()], vec![Value::Int(k)]);
+                        let new_ctx = ctx.extend_many(vec![var.to_owned()], vec![Value::Lit(k)]);
                         Some(Instr::Eval(e.clone(), new_ctx))
                     },
                     _ => unreachable!(),
@@ -449,6 +536,60 @@ impl StgMachine {
         }
     }
 
+    /// Collects the closures in the heap which can no longer be reached from the roots.
+    pub fn gc(&mut self) {
+        let roots = self.roots();
+        self.heap.gc(roots);
+        self.tracer.trace(&Event::Gc(self.heap.len()));
+    }
+
+    ///
+    /// The addresses the machine can reach directly: the globals, the values on the argument
+    /// stack, the contexts saved by continuations and update frames, the closures waiting to be
+    /// updated, those mentioned by the current instruction, and anything pinned by the host.
+    /// The same goes for each evaluation suspended by `whnf`.
+    ///
+    pub fn roots(&self) -> Vec<Addr> {
+        fn push_addrs<'a>(roots: &mut Vec<Addr>, values: impl Iterator<Item = &'a Value>) {
+            for value in values {
+                if let Value::Addr(a) = value {
+                    roots.push(*a);
+                }
+            }
+        }
+
+        fn push_ret_stack_addrs(roots: &mut Vec<Addr>, ret_stack: &[Continuation]) {
+            for Continuation(_alts, ctx) in ret_stack {
+                push_addrs(roots, ctx.iter().map(|(_var, value)| value));
+            }
+        }
+
+        fn push_state_addrs(roots: &mut Vec<Addr>, instr: &Option<Instr>, arg_stack: &[Value], ret_stack: &[Continuation], upd_stack: &[UpdateFrame]) {
+            push_addrs(roots, arg_stack.iter());
+            push_ret_stack_addrs(roots, ret_stack);
+            for UpdateFrame(arg_stack, ret_stack, addr) in upd_stack.iter() {
+                push_addrs(roots, arg_stack.iter());
+                push_ret_stack_addrs(roots, ret_stack);
+                roots.push(*addr);
+            }
+            match instr {
+                Some(Instr::Eval(_e, ctx)) => push_addrs(roots, ctx.iter().map(|(_var, value)| value)),
+                Some(Instr::Enter(addr)) => roots.push(*addr),
+                Some(Instr::RetCtor(_c, ws)) => push_addrs(roots, ws.iter()),
+                Some(Instr::RetLit(_)) | None => (),
+            }
+        }
+
+        let mut roots = vec![];
+        push_addrs(&mut roots, self.globals.iter().map(|(_var, value)| value));
+        push_state_addrs(&mut roots, &self.instr, &self.arg_stack, &self.ret_stack, &self.upd_stack);
+        for Suspended(instr, arg_stack, ret_stack, upd_stack) in self.suspended.iter() {
+            push_state_addrs(&mut roots, instr, arg_stack, ret_stack, upd_stack);
+        }
+        roots.extend(self.pinned.iter());
+        roots
+    }
+
     pub fn lookup_prim(&self, name: &str) -> Option<&PrimOp> {
         for primop in &self.primops {
             if &primop.name == name {
@@ -463,7 +604,7 @@ impl StgMachine {
     fn lookup_atom(&self, atom: &Atom, locals: &Context) -> Value {
         match atom {
             Atom::Var(var) => self.lookup_var(var, locals),
-            Atom::Lit(k) => Value::Int(*k),
+            Atom::Lit(k) => Value::Lit(k.clone()),
         }
     }
 
@@ -483,7 +624,7 @@ impl StgMachine {
         let Context(global_bindings) = &self.globals;
         for (global_var, value) in global_bindings {
             if global_var == var {
-                return Some(*value);
+                return Some(value.clone());
             }
         }
         None
@@ -501,7 +642,7 @@ impl StgMachine {
         let Context(local_bindings) = locals;
         for (local_var, value) in local_bindings {
             if local_var == var {
-                return *value;
+                return value.clone();
             }
         }
         match self.lookup_var_global(var)
@@ -511,7 +652,8 @@ impl StgMachine {
         }
     }
 
-    fn lookup_global_name_from_addr(&self, a: Addr) -> Option<Var> {
+    /// The name of the global at an address, if there is one. This helps to make sense of a trace.
+    pub fn lookup_global_name_from_addr(&self, a: Addr) -> Option<Var> {
         for (global_var, value) in self.globals.iter() {
             if let Value::Addr(addr) = value {
                 if a == *addr {
@@ -522,46 +664,83 @@ impl StgMachine {
         None
     }
 
+    ///
+    /// Evaluates a value to weak head normal form, updating it if it's a thunk, and returns what
+    /// it evaluated to. Unlike `seq`, this may be called in the middle of an evaluation, as
+    /// primops do. That evaluation is suspended until this one is done.
+    ///
+    /// The value is pinned while it is evaluated, but anything else the host holds on to must
+    /// be pinned by the host.
+    ///
+    /// If a primop fails along the way, its error is returned. The evaluation which was
+    /// suspended is picked up again all the same, so a primop may pass the error on.
+    ///
+    pub fn whnf(&mut self, value: Value) -> Result<Whnf, RuntimeError> {
+        let a = match value {
+            Value::Addr(a) => a,
+            Value::Lit(k) => return Ok(Whnf::Lit(k)),
+        };
+
+        let instr = self.instr.replace(Instr::Enter(a));
+        let arg_stack = mem::take(&mut self.arg_stack);
+        let ret_stack = mem::take(&mut self.ret_stack);
+        let upd_stack = mem::take(&mut self.upd_stack);
+        self.suspended.push(Suspended(instr, arg_stack, ret_stack, upd_stack));
+        self.pinned.push(a);
+
+        // The evaluation is done once it returns with nothing left to return to. Partial
+        // applications halt the machine instead, and those are functions.
+        let whnf = loop {
+            let done = self.ret_stack.is_empty() && self.upd_stack.is_empty();
+            match &self.instr {
+                Some(Instr::RetCtor(c, ws)) if done => break Ok(Whnf::Ctor(c.clone(), ws.clone())),
+                Some(Instr::RetLit(k)) if done => break Ok(Whnf::Lit(k.clone())),
+                None => match self.error.take() {
+                    Some(error) => break Err(error),
+                    None => break Ok(Whnf::Fun),
+                },
+                Some(_) => self.step(),
+            }
+        };
+
+        self.pinned.pop();
+        let Suspended(instr, arg_stack, ret_stack, upd_stack) = self.suspended.pop().unwrap();
+        self.instr = instr;
+        self.arg_stack = arg_stack;
+        self.ret_stack = ret_stack;
+        self.upd_stack = upd_stack;
+        whnf
+    }
+
     pub fn seq(&mut self, a: Addr) -> &Closure {
-        debug(&format!("SEQ ON {}", a));
+        self.pinned.push(a);
         self.instr = Some(Instr::Enter(a));
         while !self.is_halted() {
             self.step();
         }
+        self.pinned.pop();
         self.heap.lookup(a)
     }
 
     pub fn deep_seq(&mut self, a: Addr) -> &Closure {
-        println!("%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%");
-        debug(&format!("DEEP SEQ ON {}", a));
-        println!("%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%%");
         let original_a = a;
+        // Everything queued is reachable from the original closure, so pinning it keeps them all.
+        self.pinned.push(original_a);
         let mut seq_queue = vec![a];
         while let Some(a) = seq_queue.pop() {
-            dbg!(&a);
-            let closure = self.seq(a);
-            debug(&format!("RESULT OF SEQ ON {}: {:?}", a, &closure));
-
             let Closure(_lf, ws) = self.seq(a);
             for w in ws {
                 if let Value::Addr(wa) = w {
-                    debug(&format!("QUEUEING {} FOR SEQ'ING", wa));
                     seq_queue.push(*wa);
                 }
             }
         }
-        dbg!();
+        self.pinned.pop();
         self.heap.lookup(original_a)
     }
 
 }
 
-fn debug(msg: &str) {
-    if DEBUG {
-        eprintln!("{}", msg);
-    }
-}
-
 impl std::fmt::Display for Context {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
         writeln!(f, "{:?}", self.0)
@@ -574,7 +753,7 @@ impl std::fmt::Display for Instr {
             Instr::Eval(e, env) => write!(f, "Eval {} {}", e, env),
             Instr::Enter(a) => write!(f, "Enter {}", a),
             Instr::RetCtor(c, ws) => write!(f, "ReturnCon {} {:?}", c, ws),
-            Instr::RetInt(k) => write!(f, "ReturnInt {}", k),
+            Instr::RetLit(k) => write!(f, "ReturnLit {}", k),
         }
     }
 }
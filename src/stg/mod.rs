@@ -2,6 +2,9 @@ pub mod ast;
 pub mod machine;
 pub mod transform;
 pub mod heap;
+pub mod builtins;
+pub mod parser;
+pub mod trace;
 
 pub use machine::StgMachine;
 
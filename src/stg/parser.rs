@@ -0,0 +1,444 @@
+//!
+//! The textual syntax of STG programs, which is the same one the pretty-printer in `ast` writes.
+//! A program is a list of bindings:
+//!
+//! ```text
+//! -- Comments run from -- to the end of the line.
+//! map = {} \n {f, xs} ->
+//!     case xs {} of {
+//!         nil {} -> con nil {}
+//!         cons {y, ys} ->
+//!             let fy = {f, y} \u {} -> f {y}
+//!                 mys = {f, ys} \u {} -> map {f, ys}
+//!             in con cons {fy, mys}
+//!     }
+//! ```
+//!
+//! A lambda form lists its free variables, then `\u` if it is updatable or `\n` if it isn't, then its
+//! parameters. Applications of constructors are marked with `con` and those of primops with `prim`;
+//! any other application calls a function. A `case` lists its alternatives between braces. Those are
+//! a constructor with the variables it binds, a literal, or a variable which matches anything.
+//! `letrec` is the recursive form of `let`.
+//!
+//! Names may contain letters, digits, `_`, `'` and `#`, and may also begin with `+` or `-`, as
+//! the primops `+1` and `-1` do. The keywords are only keywords where a name couldn't be, so a
+//! function may still be called `case` or `of`. Literals are natural numbers and strings, which
+//! are quoted and escaped the same as in Quail.
+//!
+
+use std::fmt;
+
+use num_bigint::BigUint;
+
+use crate::tokenizer::Loc;
+
+use super::ast::*;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Name(String),
+    Lit(Lit),
+    LeftCurly,
+    RightCurly,
+    Comma,
+    Equals,
+    Arrow,
+    /// `\u` or `\n`, which says whether a lambda form is updatable.
+    Update(UpdateFlag),
+}
+
+#[derive(Debug, Clone)]
+pub struct ParseErr {
+    pub loc: Loc,
+    pub message: String,
+}
+
+struct Tokenizer {
+    input: Vec<char>,
+    cur: usize,
+    loc: Loc,
+}
+
+struct Parser {
+    tokens: Vec<(Token, Loc)>,
+    cur: usize,
+    end: Loc,
+}
+
+///
+/// Parses an STG program. `source` is the path it was read from, which is only used to report errors.
+///
+pub fn parse_program(source: Option<String>, input: &str) -> Result<Program, ParseErr> {
+    let mut tokenizer = Tokenizer {
+        input: input.chars().collect(),
+        cur: 0,
+        loc: Loc::new(source),
+    };
+    let tokens = tokenizer.tokenize()?;
+    let mut parser = Parser {
+        tokens,
+        cur: 0,
+        end: tokenizer.loc,
+    };
+    parser.parse_program()
+}
+
+impl Tokenizer {
+    fn tokenize(&mut self) -> Result<Vec<(Token, Loc)>, ParseErr> {
+        let mut tokens = Vec::new();
+        loop {
+            self.skip_whitespace();
+            let loc = self.loc.clone();
+            let token = match self.peek() {
+                None => return Ok(tokens),
+                Some('{') => { self.consume(); Token::LeftCurly },
+                Some('}') => { self.consume(); Token::RightCurly },
+                Some(',') => { self.consume(); Token::Comma },
+                Some('=') => { self.consume(); Token::Equals },
+                Some('-') if self.peek_ahead(1) == Some('>') => {
+                    self.consume();
+                    self.consume();
+                    Token::Arrow
+                },
+                Some('\\') => {
+                    self.consume();
+                    match self.consume() {
+                        Some('u') => Token::Update(true),
+                        Some('n') => Token::Update(false),
+                        _ => return Err(self.error("Expected \\u or \\n.")),
+                    }
+                },
+                Some('"') => self.tokenize_str()?,
+                Some(ch) if ch.is_ascii_digit() => self.tokenize_int(),
+                Some(ch) if is_name_start(ch) => self.tokenize_name(),
+                Some(ch) => return Err(self.error(&format!("Unexpected character {:?}.", ch))),
+            };
+            tokens.push((token, loc));
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        loop {
+            match self.peek() {
+                Some(ch) if ch.is_whitespace() => { self.consume(); },
+                Some('-') if self.peek_ahead(1) == Some('-') => {
+                    while let Some(ch) = self.consume() {
+                        if ch == '\n' {
+                            break;
+                        }
+                    }
+                },
+                _ => return,
+            }
+        }
+    }
+
+    fn tokenize_name(&mut self) -> Token {
+        let mut name = String::new();
+        name.extend(self.consume());
+        while let Some(ch) = self.peek() {
+            if ch.is_ascii_alphanumeric() || ch == '_' || ch == '\'' || ch == '#' {
+                name.extend(self.consume());
+            } else {
+                break;
+            }
+        }
+        Token::Name(name)
+    }
+
+    fn tokenize_int(&mut self) -> Token {
+        let mut digits = String::new();
+        while let Some(ch) = self.peek() {
+            if ch.is_ascii_digit() {
+                digits.extend(self.consume());
+            } else {
+                break;
+            }
+        }
+        Token::Lit(Lit::Int(digits.parse::<BigUint>().expect("Digits should parse as an Int")))
+    }
+
+    /// Strings are escaped the same as in Quail, which is also how Rust's Debug writes them.
+    fn tokenize_str(&mut self) -> Result<Token, ParseErr> {
+        assert_eq!(self.consume(), Some('"'));
+        let mut s = String::new();
+        loop {
+            match self.consume() {
+                None => return Err(self.error("Expected \" but found end of input.")),
+                Some('"') => return Ok(Token::Lit(Lit::Str(s.into()))),
+                Some('\\') => s.push(self.tokenize_escape()?),
+                Some(ch) => s.push(ch),
+            }
+        }
+    }
+
+    fn tokenize_escape(&mut self) -> Result<char, ParseErr> {
+        match self.consume() {
+            Some('n') => Ok('\n'),
+            Some('r') => Ok('\r'),
+            Some('t') => Ok('\t'),
+            Some('0') => Ok('\0'),
+            Some('\\') => Ok('\\'),
+            Some('"') => Ok('"'),
+            Some('\'') => Ok('\''),
+            Some('u') => {
+                if self.consume() != Some('{') {
+                    return Err(self.error("Expected { after \\u in a string."));
+                }
+                let mut digits = String::new();
+                loop {
+                    match self.consume() {
+                        Some('}') => break,
+                        Some(ch) if ch.is_ascii_hexdigit() && digits.len() < 6 => digits.push(ch),
+                        _ => return Err(self.error("Expected up to six hex digits and then } after \\u{ in a string.")),
+                    }
+                }
+                u32::from_str_radix(&digits, 16).ok()
+                    .and_then(std::char::from_u32)
+                    .ok_or_else(|| self.error(&format!("\\u{{{}}} is not a character.", digits)))
+            },
+            Some(ch) => Err(self.error(&format!("Unknown escape sequence \\{} in a string.", ch))),
+            None => Err(self.error("Expected \" but found end of input.")),
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.peek_ahead(0)
+    }
+
+    fn peek_ahead(&self, k: usize) -> Option<char> {
+        self.input.get(self.cur + k).copied()
+    }
+
+    fn consume(&mut self) -> Option<char> {
+        let ch = self.peek()?;
+        if ch == '\n' {
+            self.loc.line += 1;
+            self.loc.col = 0;
+        } else {
+            self.loc.col += 1;
+        }
+        self.cur += 1;
+        Some(ch)
+    }
+
+    fn error(&self, message: &str) -> ParseErr {
+        ParseErr {
+            loc: self.loc.clone(),
+            message: message.to_owned(),
+        }
+    }
+}
+
+fn is_name_start(ch: char) -> bool {
+    ch.is_ascii_alphabetic() || ch == '_' || ch == '+' || ch == '-'
+}
+
+impl Parser {
+    fn parse_program(&mut self) -> Result<Program, ParseErr> {
+        let mut bindings = Vec::new();
+        while self.peek().is_some() {
+            bindings.push(self.parse_binding()?);
+        }
+        Ok(Program(bindings))
+    }
+
+    fn parse_binding(&mut self) -> Result<Binding, ParseErr> {
+        let name = self.expect_name()?;
+        self.expect(Token::Equals, "=")?;
+        Ok(Binding(name, self.parse_lambda_form()?))
+    }
+
+    fn parse_lambda_form(&mut self) -> Result<LambdaForm, ParseErr> {
+        let vs = self.parse_list(|parser| parser.expect_name())?;
+        let pi = match self.consume() {
+            Some(Token::Update(pi)) => pi,
+            _ => return Err(self.error_before("\\u or \\n")),
+        };
+        let xs = self.parse_list(|parser| parser.expect_name())?;
+        self.expect(Token::Arrow, "->")?;
+        Ok(LambdaForm(vs, pi, xs, self.parse_expr()?))
+    }
+
+    fn parse_expr(&mut self) -> Result<Expr, ParseErr> {
+        if let Some(Token::Lit(k)) = self.peek() {
+            let k = k.clone();
+            self.consume();
+            return Ok(ExprNode::Lit(k).into());
+        }
+
+        if self.peek_keyword("let") || self.peek_keyword("letrec") {
+            self.parse_let()
+        } else if self.peek_keyword("case") {
+            self.parse_case()
+        } else if self.peek_keyword("con") {
+            self.consume();
+            self.parse_app(AppType::Ctor)
+        } else if self.peek_keyword("prim") {
+            self.consume();
+            self.parse_app(AppType::Prim)
+        } else {
+            self.parse_app(AppType::Fun)
+        }
+    }
+
+    fn parse_app(&mut self, app_type: AppType) -> Result<Expr, ParseErr> {
+        let f = self.expect_name()?;
+        let args = self.parse_list(|parser| parser.parse_atom())?;
+        Ok(ExprNode::App(app_type, f, args).into())
+    }
+
+    fn parse_atom(&mut self) -> Result<Atom, ParseErr> {
+        match self.consume() {
+            Some(Token::Name(x)) => Ok(Atom::Var(x)),
+            Some(Token::Lit(k)) => Ok(Atom::Lit(k)),
+            _ => Err(self.error_before("a variable or a literal")),
+        }
+    }
+
+    fn parse_let(&mut self) -> Result<Expr, ParseErr> {
+        let let_type = match self.consume() {
+            Some(Token::Name(keyword)) if keyword == "letrec" => LetType::Recursive,
+            _ => LetType::NonRecursive,
+        };
+
+        let mut bindings = vec![self.parse_binding()?];
+        while !self.peek_keyword("in") {
+            bindings.push(self.parse_binding()?);
+        }
+        self.consume();
+        Ok(ExprNode::Let(let_type, bindings, self.parse_expr()?).into())
+    }
+
+    fn parse_case(&mut self) -> Result<Expr, ParseErr> {
+        self.consume();
+        let e = self.parse_expr()?;
+        match self.consume() {
+            Some(Token::Name(keyword)) if keyword == "of" => (),
+            _ => return Err(self.error_before("of")),
+        }
+        self.expect(Token::LeftCurly, "{")?;
+
+        let mut alts = Vec::new();
+        while self.peek() != Some(&Token::RightCurly) {
+            alts.push(self.parse_alt()?);
+        }
+        self.consume();
+        Ok(ExprNode::Case(e, Alts(alts)).into())
+    }
+
+    fn parse_alt(&mut self) -> Result<Alt, ParseErr> {
+        match self.consume() {
+            Some(Token::Lit(k)) => {
+                self.expect(Token::Arrow, "->")?;
+                Ok(Alt::Lit(k, self.parse_expr()?))
+            },
+            Some(Token::Name(x)) => {
+                if self.peek() == Some(&Token::LeftCurly) {
+                    let xs = self.parse_list(|parser| parser.expect_name())?;
+                    self.expect(Token::Arrow, "->")?;
+                    Ok(Alt::Ctor(x, xs, self.parse_expr()?))
+                } else {
+                    self.expect(Token::Arrow, "->")?;
+                    Ok(Alt::Default(x, self.parse_expr()?))
+                }
+            },
+            _ => Err(self.error_before("an alternative")),
+        }
+    }
+
+    /// Parses a list between braces, such as `{x, y}`.
+    fn parse_list<T>(&mut self, parse_item: impl Fn(&mut Self) -> Result<T, ParseErr>) -> Result<Vec<T>, ParseErr> {
+        self.expect(Token::LeftCurly, "{")?;
+        let mut items = Vec::new();
+        if self.peek() == Some(&Token::RightCurly) {
+            self.consume();
+            return Ok(items);
+        }
+        loop {
+            items.push(parse_item(self)?);
+            match self.consume() {
+                Some(Token::Comma) => (),
+                Some(Token::RightCurly) => return Ok(items),
+                _ => return Err(self.error_before(", or }")),
+            }
+        }
+    }
+
+    ///
+    /// Whether the next token is the given keyword. A keyword followed by `{` or `=` is instead
+    /// the name of a function being called or bound.
+    ///
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        match (self.peek(), self.peek_ahead(1)) {
+            (_, Some(Token::LeftCurly)) | (_, Some(Token::Equals)) => false,
+            (Some(Token::Name(name)), _) => name == keyword,
+            _ => false,
+        }
+    }
+
+    fn expect_name(&mut self) -> Result<Var, ParseErr> {
+        match self.consume() {
+            Some(Token::Name(name)) => Ok(name),
+            _ => Err(self.error_before("a name")),
+        }
+    }
+
+    fn expect(&mut self, token: Token, expected: &str) -> Result<(), ParseErr> {
+        if self.consume() == Some(token) {
+            Ok(())
+        } else {
+            Err(self.error_before(expected))
+        }
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.peek_ahead(0)
+    }
+
+    fn peek_ahead(&self, k: usize) -> Option<&Token> {
+        self.tokens.get(self.cur + k).map(|(token, _loc)| token)
+    }
+
+    fn consume(&mut self) -> Option<Token> {
+        let token = self.peek().cloned();
+        self.cur += 1;
+        token
+    }
+
+    /// An error about the token which was just consumed.
+    fn error_before(&self, expected: &str) -> ParseErr {
+        match self.tokens.get(self.cur - 1) {
+            Some((token, loc)) => ParseErr {
+                loc: loc.clone(),
+                message: format!("Expected {} but found {}.", expected, token),
+            },
+            None => ParseErr {
+                loc: self.end.clone(),
+                message: format!("Expected {} but found end of input.", expected),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Name(name) => write!(f, "{}", name),
+            Token::Lit(k) => write!(f, "{}", k),
+            Token::LeftCurly => write!(f, "{{"),
+            Token::RightCurly => write!(f, "}}"),
+            Token::Comma => write!(f, ","),
+            Token::Equals => write!(f, "="),
+            Token::Arrow => write!(f, "->"),
+            Token::Update(true) => write!(f, "\\u"),
+            Token::Update(false) => write!(f, "\\n"),
+        }
+    }
+}
+
+impl fmt::Display for ParseErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.loc)
+    }
+}
@@ -68,7 +68,7 @@ fn test_stg_works() {
             ExprNode::App(AppType::Fun, "n".to_owned(), vec![]).into(),
             Alts(vec![
                 Alt::Lit(
-                    0,
+                    0.into(),
                     ExprNode::App(AppType::Ctor, "Nil".to_owned(), vec![]).into()
                 ),
                 Alt::Default(
@@ -87,7 +87,7 @@ fn test_stg_works() {
                                                  ExprNode::App(AppType::Fun, "zeroes".to_owned(), vec![Atom::Var("m".to_owned())]).into())
                                         ),
                                     ],
-                                    ExprNode::App(AppType::Ctor, "Cons".to_owned(), vec![Atom::Lit(0), Atom::Var("tail".to_owned())]).into()
+                                    ExprNode::App(AppType::Ctor, "Cons".to_owned(), vec![Atom::Lit(0.into()), Atom::Var("tail".to_owned())]).into()
                                 ).into()
                             ),
                         ]),
@@ -101,14 +101,14 @@ fn test_stg_works() {
     let example1 = ExprNode::App(
         AppType::Fun,
         "pure".to_owned(),
-        vec![Atom::Lit(3)],
+        vec![Atom::Lit(3.into())],
     );
 
     #[allow(unused_variables)]
     let example2 = ExprNode::App(
         AppType::Prim,
         "+".to_owned(),
-        vec![Atom::Lit(2), Atom::Lit(3)],
+        vec![Atom::Lit(2.into()), Atom::Lit(3.into())],
     );
 
     #[allow(unused_variables)]
@@ -122,7 +122,7 @@ fn test_stg_works() {
     let example4 = ExprNode::App(
         AppType::Fun,
         "zeroes".to_owned(),
-        vec![Atom::Lit(3)],
+        vec![Atom::Lit(3.into())],
     );
 
     let main = example4;
@@ -188,7 +188,7 @@ fn print_stuff(m: &StgMachine, addr: Addr) {
                                 print_stuff(m, a);
                                 print!(")");
                             },
-                            Value::Int(k) => print!(" {}", k),
+                            Value::Lit(k) => print!(" {}", k),
                         }
                     },
                 }
@@ -201,6 +201,7 @@ fn print_stuff(m: &StgMachine, addr: Addr) {
 }
 
 use crate::parser::*;
+use crate::runtime::Runtime;
 use crate::resolver::FileImportResolver;
 use crate::resolver::ImportResolver;
 
@@ -211,7 +212,7 @@ fn test_transform() {
     let mut import_resolver = FileImportResolver::new("stg_examples");
     let text = import_resolver.resolve("nat").unwrap().text();
     let module = parse_module(None, &text).unwrap();
-    let program = transform(module);
+    let program = transform(&module.definitions, &Runtime::new().inductive_typedefs);
 
     eprintln!("{}", program);
 }
@@ -222,7 +223,7 @@ fn test_transform_run() {
     let mut import_resolver = FileImportResolver::new("stg_examples");
     let text = import_resolver.resolve("nat").unwrap().text();
     let module = parse_module(None, &text).unwrap();
-    let program = transform(module);
+    let program = transform(&module.definitions, &Runtime::new().inductive_typedefs);
     let mut m = StgMachine::new(&program, Some("main"));
     println!("********************************************************************************");
     println!("PROGRAM:");
@@ -263,3 +264,473 @@ fn test_transform_run() {
     println!();
     println!("##########");
 }
+
+/// A program which builds the list `[n, n - 1, ..., 1]` lazily, and then finds its length with a
+/// tail-recursive loop. Its result is `Done {length}`.
+fn count_and_consume(n: usize) -> Program {
+    fn vars(names: &[&str]) -> Vec<Var> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    fn app(app_type: AppType, f: &str, args: Vec<Atom>) -> Expr {
+        ExprNode::App(app_type, f.to_owned(), args).into()
+    }
+
+    // count = {} \n {n} -> case n {} of 0 -> Nil {}; default -> let tail = {n} \u {} -> (case -1 {n} of m -> count {m}) in Cons {n, tail}
+    let count = LambdaForm(vec![], false, vars(&["n"]),
+        ExprNode::Case(
+            app(AppType::Fun, "n", vec![]),
+            Alts(vec![
+                Alt::Lit(0.into(), app(AppType::Ctor, "Nil", vec![])),
+                Alt::Default(
+                    "k".to_owned(),
+                    ExprNode::Let(
+                        LetType::NonRecursive,
+                        vec![Binding("tail".to_owned(), LambdaForm(vars(&["n"]), true, vec![],
+                            ExprNode::Case(
+                                app(AppType::Prim, "-1", vec!["n".into()]),
+                                Alts(vec![Alt::Default("m".to_owned(), app(AppType::Fun, "count", vec!["m".into()]))]),
+                            ).into(),
+                        ))],
+                        app(AppType::Ctor, "Cons", vec!["n".into(), "tail".into()]),
+                    ).into(),
+                ),
+            ]),
+        ).into(),
+    );
+
+    // len = {} \n {xs, acc} -> case xs {} of Nil {} -> Done {acc}; Cons {y, ys} -> case +1 {acc} of k -> len {ys, k}
+    let len = LambdaForm(vec![], false, vars(&["xs", "acc"]),
+        ExprNode::Case(
+            app(AppType::Fun, "xs", vec![]),
+            Alts(vec![
+                Alt::Ctor("Nil".to_owned(), vec![], app(AppType::Ctor, "Done", vec!["acc".into()])),
+                Alt::Ctor(
+                    "Cons".to_owned(),
+                    vars(&["y", "ys"]),
+                    ExprNode::Case(
+                        app(AppType::Prim, "+1", vec!["acc".into()]),
+                        Alts(vec![Alt::Default("k".to_owned(), app(AppType::Fun, "len", vec!["ys".into(), "k".into()]))]),
+                    ).into(),
+                ),
+            ]),
+        ).into(),
+    );
+
+    // main = {} \u {} -> let xs = {} \u {} -> count {n} in len {xs, 0}
+    let main = LambdaForm(vec![], true, vec![],
+        ExprNode::Let(
+            LetType::NonRecursive,
+            vec![Binding("xs".to_owned(), LambdaForm(vec![], true, vec![], app(AppType::Fun, "count", vec![Atom::Lit(n.into())])))],
+            app(AppType::Fun, "len", vec!["xs".into(), Atom::Lit(0.into())]),
+        ).into(),
+    );
+
+    Program(vec![
+        Binding("count".to_owned(), count),
+        Binding("len".to_owned(), len),
+        Binding("main".to_owned(), main),
+    ])
+}
+
+#[test]
+fn test_gc_bounds_heap() {
+    const LENGTH: usize = 5000;
+    const THRESHOLD: usize = 256;
+
+    let program = count_and_consume(LENGTH);
+    let mut m = StgMachine::new(&program, Some("main"));
+    m.heap.set_min_gc_threshold(THRESHOLD);
+
+    let mut max_heap_len = 0;
+    while !m.is_halted() {
+        m.step();
+        max_heap_len = max_heap_len.max(m.heap.len());
+    }
+
+    // Without collection, every cell of the list and each of its thunks would still be in the heap.
+    assert!(max_heap_len <= THRESHOLD + 1, "The heap grew to {} closures", max_heap_len);
+
+    let main_addr = m.lookup_global_addr("main").unwrap();
+    let Closure(LambdaForm(_vs, _pi, _xs, e), ws) = m.heap.lookup(main_addr);
+    match e.as_ref() {
+        ExprNode::App(AppType::Ctor, c, _args) => assert_eq!(c, "Done"),
+        _ => panic!("main should have been updated with its result, but it is {}", e),
+    }
+    match ws.as_slice() {
+        [Value::Lit(k)] => assert_eq!(*k, LENGTH.into()),
+        _ => panic!("Unexpected result {:?}", ws),
+    }
+}
+
+#[test]
+fn test_gc_keeps_reachable_closures() {
+    let program = count_and_consume(10);
+    let mut m = StgMachine::new(&program, None);
+
+    let count = m.lookup_global_addr("count").unwrap();
+    let list = m.heap.alloc(Closure(LambdaForm(vec![], true, vec![],
+        ExprNode::App(AppType::Fun, "count".to_owned(), vec![Atom::Lit(3.into())]).into()), vec![]));
+    m.pinned.push(list);
+    m.deep_seq(list);
+
+    let before = m.heap.len();
+    m.gc();
+    assert!(m.heap.len() <= before);
+    assert!(m.roots().contains(&count));
+
+    // The whole list survives, since it is pinned.
+    let mut addr = list;
+    let mut elements = vec![];
+    loop {
+        let Closure(LambdaForm(_vs, _pi, _xs, e), ws) = m.heap.lookup(addr).clone();
+        match (e.as_ref(), ws.as_slice()) {
+            (ExprNode::App(AppType::Ctor, c, _), [Value::Lit(k), Value::Addr(tail)]) if c == "Cons" => {
+                elements.push(k.clone());
+                addr = *tail;
+            },
+            (ExprNode::App(AppType::Ctor, c, _), []) if c == "Nil" => break,
+            _ => panic!("Unexpected closure {}", e),
+        }
+    }
+    assert_eq!(elements, vec![3.into(), 2.into(), 1.into()]);
+
+    // Once nothing holds on to it, the list is collected.
+    m.pinned.clear();
+    m.gc();
+    assert_eq!(m.heap.len(), 3);
+}
+
+/// Runs the definition of `result : Str` in the given source on both the Runtime and the STG
+/// machine, checks they agree, and returns the string.
+fn run_on_both_backends(text: &str) -> String {
+    let module = parse_module(None, text).unwrap();
+    let mut runtime = Runtime::new();
+    for type_decl in &module.type_decls {
+        runtime.declare_type(type_decl).unwrap();
+    }
+    for definition in &module.definitions {
+        runtime.define(definition).unwrap();
+    }
+    let expected: String = runtime.call("result", vec![]).unwrap();
+
+    let program = transform(&module.definitions, &runtime.inductive_typedefs);
+    let mut m = StgMachine::new(&program, None);
+    m.primops.extend(super::builtins::primops(&runtime.inductive_typedefs, runtime.show_depth));
+    let result_addr = m.lookup_global_addr("result").unwrap();
+    match m.whnf(Value::Addr(result_addr)).unwrap() {
+        super::machine::Whnf::Lit(Lit::Str(s)) => assert_eq!(&*s, expected.as_str()),
+        _ => panic!("result should be a Str"),
+    }
+    expected
+}
+
+#[test]
+fn test_transform_layered_variables() {
+    let text = "
+        def x : Nat = 5
+        def second : Nat -> Nat -> Nat = fun n n => n$1
+        def shadow : Nat -> Nat = fun x => add x 1
+        def result : Str = show (cons (second 1 2) (cons (shadow x) nil))
+    ";
+    assert_eq!(run_on_both_backends(text), "[1, 6]");
+}
+
+#[test]
+fn test_transform_user_types() {
+    let text = "
+        inductive Shape
+            circle Nat
+            rect Nat Nat
+
+        def area : Shape -> Nat = fun s => match s
+            with circle r => mul 3 (mul r r)
+            with rect w h => mul w h
+
+        def result : Str = show (cons (area (circle 2)) (cons (area (rect 3 4)) nil))
+    ";
+    assert_eq!(run_on_both_backends(text), "[12, 12]");
+}
+
+#[test]
+fn test_transform_string_prims() {
+    let text = r#"
+        def words : List Str = split " " "the quick brown fox"
+        def result : Str = cat (show words) (cat (show (chars "abc"))
+            (cat (show (str_length "λx")) (cat (show (str_lt "a" "b")) (cat (show (str_eq "a" "b"))
+            (cat (substr "quail" 1 3) (cat (show (parse_nat "42")) (cat (show (parse_nat "4x")) (show_nat (add 40 2)))))))))
+    "#;
+    assert_eq!(
+        run_on_both_backends(text),
+        r#"["the", "quick", "brown", "fox"]["a", "b", "c"]2truefalseuaisome 42none42"#,
+    );
+}
+
+#[test]
+fn test_transform_show_deep_value() {
+    let text = "
+        inductive Tower
+            ground
+            floor Nat Tower
+
+        def build : Nat -> Tower = fun n =>
+            match n
+                with zero => ground
+                with succ m => floor 0 (build m)
+
+        def result : Str = show (build 200000)
+    ";
+    let shown = run_on_both_backends(text);
+    assert!(shown.starts_with("floor 0 (floor 0 ("));
+    assert_eq!(shown.matches("floor").count(), 200000);
+}
+
+#[test]
+fn test_exec_errors() {
+    use crate::runtime::Cause;
+
+    let text = "
+        def main : Nat = 3
+        def greeting : IO Top = println (show 3)
+    ";
+    let module = parse_module(None, text).unwrap();
+    let runtime = Runtime::new();
+    let program = transform(&module.definitions, &runtime.inductive_typedefs);
+    let mut m = StgMachine::new(&program, None);
+    m.primops.extend(super::builtins::primops(&runtime.inductive_typedefs, runtime.show_depth));
+
+    let error = super::builtins::exec(&mut m, "main").unwrap_err();
+    assert!(matches!(error.cause.as_ref(), Cause::NotAnEffect(_)));
+    let error = super::builtins::exec(&mut m, "missing").unwrap_err();
+    assert_eq!(error.to_string(), "There should be a missing in your module");
+    assert!(super::builtins::exec(&mut m, "greeting").is_ok());
+}
+
+#[test]
+fn test_exec_reaching_a_hole() {
+    use crate::runtime::Cause;
+
+    // The hole is reached while `show#` is evaluating its argument, so the error is passed up through it.
+    let text = "
+        def f : Nat -> Nat = fun n => match n
+            with zero => ?oops
+            with succ m => m
+        def main : IO Top = println (show (f zero))
+    ";
+    let module = parse_module(Some("stuck".to_string()), text).unwrap();
+    let mut runtime = Runtime::new();
+    for definition in &module.definitions {
+        runtime.define(definition).unwrap();
+    }
+    let expected = runtime.exec().unwrap_err();
+
+    let program = transform(&module.definitions, &runtime.inductive_typedefs);
+    let mut m = StgMachine::new(&program, None);
+    m.primops.extend(super::builtins::primops(&runtime.inductive_typedefs, runtime.show_depth));
+    let error = super::builtins::exec(&mut m, "main").unwrap_err();
+    match (error.cause.as_ref(), expected.cause.as_ref()) {
+        (Cause::UnfilledHole(hole_info), Cause::UnfilledHole(expected_hole_info)) => {
+            assert_eq!(hole_info.hole_id, expected_hole_info.hole_id);
+            assert_eq!(hole_info.loc, expected_hole_info.loc);
+        },
+        causes => panic!("Expected both to reach the hole, but found {:?}", causes),
+    }
+    assert_eq!(error.to_string(), "Evaluation reached the hole ?oops");
+    assert_eq!(error.diagnostic().span, expected.diagnostic().span);
+    assert!(m.pinned.is_empty());
+}
+
+use super::parser::parse_program;
+
+/// Parses a program from the `stg_examples` directory.
+fn load_stg_example(name: &str) -> Program {
+    let path = format!("stg_examples/{}.stg", name);
+    let text = std::fs::read_to_string(&path).unwrap();
+    parse_program(Some(path), &text).unwrap()
+}
+
+/// Runs a program to completion, where `main` gives a constructor applied to literals, and returns them.
+fn run_to_main(program: &Program) -> (Ctor, Vec<Lit>) {
+    let mut m = StgMachine::new(program, Some("main"));
+    while !m.is_halted() {
+        m.step();
+    }
+    let main_addr = m.lookup_global_addr("main").unwrap();
+    let Closure(LambdaForm(_vs, _pi, _xs, e), ws) = m.heap.lookup(main_addr).clone();
+    match e.as_ref() {
+        ExprNode::App(AppType::Ctor, c, _args) => {
+            let lits = ws.into_iter().map(|w| match w {
+                Value::Lit(k) => k,
+                Value::Addr(a) => panic!("Expected a literal, but found the address {}", a),
+            });
+            (c.clone(), lits.collect())
+        },
+        _ => panic!("main should have been updated with its result, but it is {}", e),
+    }
+}
+
+fn assert_round_trips(program: &Program) {
+    let text = program.to_string();
+    match parse_program(None, &text) {
+        Ok(parsed) => assert_eq!(&parsed, program, "The program changed when printed and parsed again:\n{}", text),
+        Err(error) => panic!("{}\n{}", error, text),
+    }
+}
+
+#[test]
+fn test_parse_round_trips() {
+    for entry in std::fs::read_dir("stg_examples").unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().and_then(|ext| ext.to_str()) == Some("stg") {
+            let text = std::fs::read_to_string(&path).unwrap();
+            let program = parse_program(Some(path.display().to_string()), &text).unwrap();
+            assert_round_trips(&program);
+        }
+    }
+
+    assert_round_trips(&count_and_consume(10));
+
+    let mut import_resolver = FileImportResolver::new("examples");
+    for entry in std::fs::read_dir("examples").unwrap() {
+        let path = entry.unwrap().path();
+        let name = path.file_stem().unwrap().to_str().unwrap();
+        let mut runtime = Runtime::new();
+        let definitions = runtime.load(name, &mut import_resolver, true).unwrap();
+        assert_round_trips(&transform(&definitions, &runtime.inductive_typedefs));
+    }
+}
+
+#[test]
+fn test_parse_matches_ast() {
+    let program = load_stg_example("count");
+    let mut expected = count_and_consume(1000);
+    for Binding(_name, LambdaForm(_vs, _pi, _xs, e)) in expected.0.iter_mut() {
+        *e = rename_ctors(e);
+    }
+    assert_eq!(program, expected);
+}
+
+/// The constructors of `count_and_consume` are capitalized, but those in count.stg are not.
+fn rename_ctors(e: &Expr) -> Expr {
+    let rename = |c: &str| c.to_lowercase();
+    match e.as_ref() {
+        ExprNode::App(AppType::Ctor, c, args) => ExprNode::App(AppType::Ctor, rename(c), args.clone()).into(),
+        ExprNode::App(..) | ExprNode::Lit(_) => e.clone(),
+        ExprNode::Let(let_type, bindings, body) => {
+            let bindings = bindings.iter().map(|Binding(x, LambdaForm(vs, pi, xs, e))| {
+                Binding(x.clone(), LambdaForm(vs.clone(), *pi, xs.clone(), rename_ctors(e)))
+            }).collect();
+            ExprNode::Let(let_type.clone(), bindings, rename_ctors(body)).into()
+        },
+        ExprNode::Case(e, Alts(alts)) => {
+            let alts = alts.iter().map(|alt| match alt {
+                Alt::Ctor(c, xs, e) => Alt::Ctor(rename(c), xs.clone(), rename_ctors(e)),
+                Alt::Lit(k, e) => Alt::Lit(k.clone(), rename_ctors(e)),
+                Alt::Default(x, e) => Alt::Default(x.clone(), rename_ctors(e)),
+            }).collect();
+            ExprNode::Case(rename_ctors(e), Alts(alts)).into()
+        },
+    }
+}
+
+#[test]
+fn test_run_stg_examples() {
+    let (c, ws) = run_to_main(&load_stg_example("count"));
+    assert_eq!(c, "done");
+    assert_eq!(ws, vec![1000.into()]);
+
+    let (c, ws) = run_to_main(&load_stg_example("map"));
+    assert_eq!(c, "done");
+    assert_eq!(ws, vec![9.into()]);
+
+    let (c, ws) = run_to_main(&load_stg_example("strings"));
+    assert_eq!(c, "greeting");
+    assert_eq!(ws, vec!["Hello,\n\t\"world\" \u{3bb}".into()]);
+}
+
+#[test]
+fn test_parse_errors() {
+    let error = parse_program(Some("bad.stg".to_owned()), "main = {} \\u {} ->\n    case x {} of {\n        y - z {}\n").unwrap_err();
+    assert_eq!(error.to_string(), "Expected -> but found -. (Line 3 col 11 at bad.stg)");
+
+    let error = parse_program(None, "main = {} \\u {} -> f {x,").unwrap_err();
+    assert_eq!(error.to_string(), "Expected a variable or a literal but found end of input. (Line 1 col 25)");
+}
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use super::trace::{HumanTracer, JsonTracer, Tracer};
+
+/// Somewhere for a tracer to write to, which the test can still read once the machine owns the tracer.
+#[derive(Clone, Default)]
+struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl std::io::Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Runs a program with a tracer writing to the buffer, and returns the lines it wrote.
+fn trace_program(text: &str, make_tracer: impl Fn(SharedBuffer) -> Box<dyn Tracer>) -> Vec<String> {
+    let buffer = SharedBuffer::default();
+    let program = parse_program(None, text).unwrap();
+    let mut m = StgMachine::new(&program, Some("main"));
+    m.tracer = make_tracer(buffer.clone());
+    while !m.is_halted() {
+        m.step();
+    }
+    let output = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+    output.lines().map(String::from).collect()
+}
+
+#[test]
+fn test_tracers() {
+    let text = r"
+        main = {} \u {} ->
+            let x = {} \u {} -> con done { 5 }
+            in case x {} of {
+                done { y } -> prim +1 { y }
+            }
+    ";
+
+    assert_eq!(trace_program(text, |buffer| Box::new(HumanTracer(buffer))), vec![
+        "eval main {}",
+        "enter 0",
+        "eval let x = {} \\u {} -> con done { 5 }",
+        "    in case x {} of {",
+        "        done { y } -> prim +1 { y }",
+        "    }",
+        "alloc 1",
+        "eval case x {} of {",
+        "    done { y } -> prim +1 { y }",
+        "}",
+        "push continuation (1 on the return stack)",
+        "eval x {}",
+        "enter 1",
+        "eval con done { 5 }",
+        "return done {5}",
+        "update 1",
+        "return done {5}",
+        "pop continuation (0 on the return stack)",
+        "eval prim +1 { y }",
+        "return 6",
+        "update 0",
+        "return 6",
+    ]);
+
+    let events = trace_program(text, |buffer| Box::new(JsonTracer(buffer)));
+    let events: Vec<serde_json::Value> = events.iter().map(|line| serde_json::from_str(line).unwrap()).collect();
+    let names: Vec<&str> = events.iter().map(|event| event["event"].as_str().unwrap()).collect();
+    assert_eq!(names, vec![
+        "eval", "enter", "eval", "alloc", "eval", "push_continuation", "eval", "enter", "eval",
+        "ret_ctor", "update", "ret_ctor", "pop_continuation", "eval", "ret_lit", "update", "ret_lit",
+    ]);
+    assert_eq!(events[9], serde_json::json!({ "event": "ret_ctor", "ctor": "done", "args": [{ "int": "5" }] }));
+    assert_eq!(events[14], serde_json::json!({ "event": "ret_lit", "lit": { "int": "6" } }));
+}
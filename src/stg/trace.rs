@@ -0,0 +1,127 @@
+use std::io::Write;
+
+use serde_json::json;
+
+use super::ast::*;
+use super::machine::{Addr, Value};
+
+///
+/// Something which happens as the STG machine runs. The first four are the instructions the
+/// machine steps through. The rest are the changes it makes to the heap and the return stack
+/// along the way.
+///
+#[derive(Debug, Clone, Copy)]
+pub enum Event<'a> {
+    /// The machine evaluates an expression.
+    Eval(&'a Expr),
+    /// The machine enters the closure at an address.
+    Enter(Addr),
+    /// A constructor applied to its arguments is returned.
+    RetCtor(&'a Ctor, &'a [Value]),
+    /// A literal is returned.
+    RetLit(&'a Lit),
+    /// The thunk at an address is overwritten with the value it evaluated to.
+    Update(Addr),
+    /// The continuation of a `case` is pushed onto the return stack, leaving it this deep.
+    PushContinuation(usize),
+    /// A continuation is popped off the return stack, leaving it this deep.
+    PopContinuation(usize),
+    /// A closure is allocated in the heap at an address.
+    Alloc(Addr),
+    /// The garbage collector ran, and this many closures survived.
+    Gc(usize),
+}
+
+///
+/// Receives the events of an `StgMachine` as it runs. Set `StgMachine::tracer` to watch a
+/// program being evaluated.
+///
+pub trait Tracer {
+    fn trace(&mut self, event: &Event);
+}
+
+impl std::fmt::Debug for dyn Tracer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "TRACER")
+    }
+}
+
+/// Ignores every event. This is the tracer a machine starts out with.
+pub struct QuietTracer;
+
+/// Writes each event as a line for people to read.
+pub struct HumanTracer<W: Write>(pub W);
+
+///
+/// Writes each event as a line of JSON, for tools to analyze afterwards. Every object has an
+/// `event` field naming the kind of event, such as `"enter"`, and a field for each of its parts.
+///
+pub struct JsonTracer<W: Write>(pub W);
+
+impl Tracer for QuietTracer {
+    fn trace(&mut self, _event: &Event) {}
+}
+
+impl<W: Write> Tracer for HumanTracer<W> {
+    fn trace(&mut self, event: &Event) {
+        let line = match event {
+            Event::Eval(e) => format!("eval {}", e),
+            Event::Enter(a) => format!("enter {}", a),
+            Event::RetCtor(c, ws) => {
+                let ws = ws.iter().map(value_to_string).collect::<Vec<String>>();
+                format!("return {} {{{}}}", c, ws.join(", "))
+            },
+            Event::RetLit(k) => format!("return {}", k),
+            Event::Update(a) => format!("update {}", a),
+            Event::PushContinuation(depth) => format!("push continuation ({} on the return stack)", depth),
+            Event::PopContinuation(depth) => format!("pop continuation ({} on the return stack)", depth),
+            Event::Alloc(a) => format!("alloc {}", a),
+            Event::Gc(survivors) => format!("gc ({} closures survived)", survivors),
+        };
+        writeln!(self.0, "{}", line).expect("Could not write the trace");
+    }
+}
+
+impl<W: Write> Tracer for JsonTracer<W> {
+    fn trace(&mut self, event: &Event) {
+        let json = match event {
+            Event::Eval(e) => json!({ "event": "eval", "expr": e.to_string() }),
+            Event::Enter(a) => json!({ "event": "enter", "addr": a }),
+            Event::RetCtor(c, ws) => json!({
+                "event": "ret_ctor",
+                "ctor": c,
+                "args": ws.iter().map(value_to_json).collect::<Vec<serde_json::Value>>(),
+            }),
+            Event::RetLit(k) => json!({ "event": "ret_lit", "lit": lit_to_json(k) }),
+            Event::Update(a) => json!({ "event": "update", "addr": a }),
+            Event::PushContinuation(depth) => json!({ "event": "push_continuation", "depth": depth }),
+            Event::PopContinuation(depth) => json!({ "event": "pop_continuation", "depth": depth }),
+            Event::Alloc(a) => json!({ "event": "alloc", "addr": a }),
+            Event::Gc(survivors) => json!({ "event": "gc", "survivors": survivors }),
+        };
+        writeln!(self.0, "{}", json).expect("Could not write the trace");
+    }
+}
+
+/// Addresses are written with an `@`, so they can't be mistaken for Ints.
+fn value_to_string(value: &Value) -> String {
+    match value {
+        Value::Addr(a) => format!("@{}", a),
+        Value::Lit(k) => k.to_string(),
+    }
+}
+
+fn value_to_json(value: &Value) -> serde_json::Value {
+    match value {
+        Value::Addr(a) => json!({ "addr": a }),
+        Value::Lit(k) => lit_to_json(k),
+    }
+}
+
+/// Ints may be too big for a JSON number to hold exactly, so they are written as strings of digits.
+fn lit_to_json(k: &Lit) -> serde_json::Value {
+    match k {
+        Lit::Int(n) => json!({ "int": n.to_string() }),
+        Lit::Str(s) => json!({ "str": &**s }),
+    }
+}
@@ -1,218 +1,370 @@
-#![allow(dead_code, unused_variables)]
+use std::collections::HashMap;
 use std::collections::HashSet;
 
 use crate::ast as q;
+use crate::runtime::TypeDef;
 use super::ast as m;
+use super::builtins;
 
-pub fn transform(module: q::Module) -> m::Program {
-    assert!(module.imports.is_empty()); // TODO deal with non-empty imports later
+///
+/// Transforms the definitions of a Quail program into an STG program. The definitions are
+/// those of a module together with those of the modules it imports, as returned by
+/// `Runtime::load`. The typedefs are those of the Runtime which loaded them.
+///
+/// Besides a binding for each definition, the program has bindings for Quail's builtins, and
+/// one for each constructor, so that constructors may be passed around like functions.
+///
+pub fn transform(definitions: &[q::Def], typedefs: &HashMap<String, TypeDef>) -> m::Program {
+    let builtin_bindings = builtins::builtin_bindings();
 
-    let mut bindings = vec![];
+    let mut ctors = HashMap::new();
+    for typedef in typedefs.values() {
+        // Nats are Ints, and `zero` and `succ` are builtins.
+        if typedef.name != "Nat" {
+            for tag in typedef.ctor_tags() {
+                let arity = typedef.ctor_arity(&tag).unwrap();
+                ctors.insert(tag, arity);
+            }
+        }
+    }
+
+    let mut globals: HashSet<m::Var> = HashSet::new();
+    globals.extend(definitions.iter().map(|q::Def(name, _typ, _term, _span)| name.clone()));
+    globals.extend(builtin_bindings.iter().map(|m::Binding(name, _lf)| name.clone()));
+    globals.extend(ctors.keys().cloned());
 
-    for q::Def(var, typ, term) in &module.definitions {
-        let e = transform_term(term.clone(), &module);
-        let updatable = var == "main";
-        let lf = m::LambdaForm(vec![], updatable, vec![], e);
-        bindings.push(m::Binding(var.clone(), lf));
+    let mut transformer = Transformer {
+        ctors,
+        globals,
+        next_name: 0,
+    };
+
+    let mut bindings = vec![];
+    let mut defined = HashSet::new();
+    for q::Def(name, _typ, term, _span) in definitions {
+        bindings.push(m::Binding(name.clone(), transformer.transform_lambda_form(term, &Scope::new())));
+        defined.insert(name.clone());
     }
 
-    let ctors = [
-        ("zero", vec![]),
-        ("succ", vec!["n"]),
-        ("nil",  vec![]),
-        ("cons", vec!["x", "xs"]),
-        ("true",  vec![]),
-        ("false",  vec![]),
-    ];
+    // A definition may shadow a builtin.
+    for binding in builtin_bindings {
+        if !defined.contains(&binding.0) {
+            bindings.push(binding);
+        }
+    }
 
-    for (ref name, args) in ctors.iter() {
-        let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+    let mut ctors: Vec<(&m::Ctor, &usize)> = transformer.ctors.iter().collect();
+    ctors.sort();
+    for (tag, arity) in ctors {
+        let args: Vec<m::Var> = (0..*arity).map(|i| format!("x_{}", i)).collect();
         let args_atoms: Vec<m::Atom> = args.iter().map(|s| m::Atom::Var(s.to_string())).collect();
-        let e = m::ExprNode::App(m::AppType::Ctor, name.to_string(), args_atoms).into();
+        let e = m::ExprNode::App(m::AppType::Ctor, tag.to_string(), args_atoms).into();
         let lf = m::LambdaForm(vec![], false, args, e);
-        bindings.push(m::Binding(name.to_string(), lf));
+        bindings.push(m::Binding(tag.to_string(), lf));
     }
 
     m::Program(bindings)
 }
 
-fn transform_term_let(x: &str, s: q::Term, t: q::Term, program: &q::Module) -> m::Expr {
-    let mut free_vars = gather_free_vars(s.clone(), program);
-    free_vars.retain(|fv| fv != x);
-
-    m::ExprNode::Let(
-        m::LetType::NonRecursive,
-        vec![
-            m::Binding(
-                x.to_owned(),
-                m::LambdaForm(
-                    free_vars,
-                    decide_updatability(),
-                    vec![], // TODO should this be fused if the target is a lambda?
-                    transform_term(s.clone(), program),
-                )
-            ),
-        ],
-        transform_term(t.clone(), program),
-    ).into()
-}
+///
+/// The local variables in scope, innermost last, each with the name it is given in STG.
+/// Every local variable is given a name of its own, so that a variable which is shadowed in
+/// Quail, such as the `n$1` in `fun n n => n$1`, can still be referred to in STG.
+///
+type Scope = Vec<(String, m::Var)>;
 
-fn transform_term_lam(x: &str, t: q::Term, program: &q::Module) -> m::Expr {
-    let gensym = "gensym_0".to_owned();
-    let mut free_vars = gather_free_vars(t.clone(), program);
-    free_vars.retain(|fv| fv != &x);
-
-    m::ExprNode::Let(
-        m::LetType::NonRecursive,
-        vec![m::Binding(
-            gensym.clone(),
-            m::LambdaForm(
-                free_vars,
-                false,
-                vec![x.to_owned()],
-                transform_term(t.clone(), program),
-            ),
-        )],
-        m::ExprNode::App(m::AppType::Fun, gensym, vec![]).into(),
-    ).into()
+struct Transformer {
+    /// The arity of each constructor.
+    ctors: HashMap<m::Ctor, usize>,
+    /// The names of the top-level bindings. Every other variable is local.
+    globals: HashSet<m::Var>,
+    /// Counts up to make fresh names.
+    next_name: usize,
 }
 
-fn transform_term_app(t: q::Term, vs: &[q::Term], program: &q::Module) -> m::Expr {
-    let mut i = 0;
-    let mut temps = vec![];
+impl Transformer {
+    ///
+    /// Makes a name for a local variable. Quail identifiers never contain digits, so it can't be
+    /// the same as the name of a definition.
+    ///
+    fn fresh(&mut self, name: &str) -> m::Var {
+        let fresh = format!("{}_{}", name, self.next_name);
+        self.next_name += 1;
+        fresh
+    }
 
-    let f = get_var_name(t.clone()).unwrap_or_else(|| {
-        let name = format!("gensym_{}", i);
-        temps.push((name.clone(), t.clone()));
-        i += 1;
-        name
-    });
-
-    let vs_expr = vs.iter().map(|v| {
-        m::Atom::Var(get_var_name(v.clone()).unwrap_or_else(|| {
-            let name = format!("gensym_{}", i);
-            temps.push((name.clone(), v.clone()));
-            i += 1;
-            name
-        }))
-    }).collect();
-
-    let app_type = if is_ctor(&f) {
-        m::AppType::Ctor
-    } else {
-        m::AppType::Fun
-    };
+    ///
+    /// Finds the name in STG of a variable. Those which aren't local refer to a definition,
+    /// builtin, or constructor, which keeps its name.
+    ///
+    fn resolve(&self, var: &q::Variable, scope: &Scope) -> m::Var {
+        let mut layer = var.layer;
+        for (name, stg_name) in scope.iter().rev() {
+            if *name == var.name {
+                if layer == 0 {
+                    return stg_name.clone();
+                }
+                layer -= 1;
+            }
+        }
+        var.name.clone()
+    }
 
-    dbg!(&app_type);
-
-    if temps.is_empty() {
-        m::ExprNode::App(app_type, f, vs_expr).into()
-    } else {
-        let mut bindings = vec![];
-        for (name, term) in temps.iter() {
-            bindings.push(m::Binding(
-                    name.clone(),
-                    m::LambdaForm(
-                        gather_free_vars(term.clone(), program), //xs
-                        decide_updatability(),
-                        vec![], // TODO should this be fused if the target is a lambda?
-                        transform_term(term.clone(), program),
-                    )
-            ))
+    ///
+    /// Makes the lambda form to be bound to a term. A lambda becomes a function, taking each
+    /// of its arguments at once, and anything else becomes a thunk.
+    ///
+    fn transform_lambda_form(&mut self, term: &q::Term, scope: &Scope) -> m::LambdaForm {
+        let mut scope = scope.clone();
+        let mut xs = vec![];
+        let mut body = term;
+        loop {
+            match body.as_ref() {
+                q::TermNode::Lam(x, t) => {
+                    let stg_x = self.fresh(x);
+                    scope.push((x.clone(), stg_x.clone()));
+                    xs.push(stg_x);
+                    body = t;
+                },
+                q::TermNode::As(t, _typ) => body = t,
+                _ => break,
+            }
         }
 
+        let e = self.transform_term(body, &scope);
+        let free_vars = free_vars(&e)
+            .into_iter()
+            .filter(|v| !xs.contains(v) && !self.globals.contains(v))
+            .collect();
+        let updatable = xs.is_empty();
+        m::LambdaForm(free_vars, updatable, xs, e)
+    }
+
+    fn transform_term(&mut self, term: &q::Term, scope: &Scope) -> m::Expr {
+        use q::TermNode::*;
+
+        match term.as_ref() {
+            As(t, _typ) => self.transform_term(t, scope),
+            StrLit(s) => m::ExprNode::Lit(m::Lit::Str(s.as_str().into())).into(),
+            NatLit(n) => m::ExprNode::Lit(m::Lit::Int(n.clone())).into(),
+            Hole(hole_info) => self.transform_term_hole(hole_info),
+            Let(x, s, t) => self.transform_term_let(x, s, t, scope),
+            Var(var) => self.transform_term_var(var, scope),
+            Lam(_x, _t) => self.transform_term_lam(term, scope),
+            App(t, vs) => self.transform_term_app(t, vs, scope),
+            Match(t, match_arms) => self.transform_term_match(t, match_arms, scope),
+        }
+    }
+
+    fn transform_term_var(&mut self, var: &q::Variable, scope: &Scope) -> m::Expr {
+        let name = self.resolve(var, scope);
+        if self.ctors.get(&name) == Some(&0) {
+            m::ExprNode::App(m::AppType::Ctor, name, vec![]).into()
+        } else {
+            m::ExprNode::App(m::AppType::Fun, name, vec![]).into()
+        }
+    }
+
+    ///
+    /// A hole can't be filled while the STG machine runs, so reaching one is an error. `hole#`
+    /// is given what it needs to fail with the same error the Runtime gives when there is no
+    /// hole handler.
+    ///
+    fn transform_term_hole(&mut self, hole_info: &q::HoleInfo) -> m::Expr {
+        let loc = &hole_info.loc;
+        let args = vec![
+            m::Lit::from(hole_info.hole_id),
+            m::Lit::Str(hole_info.name.as_deref().unwrap_or("").into()),
+            m::Lit::Str(loc.path.as_deref().unwrap_or("").into()),
+            m::Lit::from(loc.line),
+            m::Lit::from(loc.col),
+        ];
+        m::ExprNode::App(m::AppType::Prim, "hole#".to_owned(), args.into_iter().map(m::Atom::Lit).collect()).into()
+    }
+
+    fn transform_term_let(&mut self, x: &str, s: &q::Term, t: &q::Term, scope: &Scope) -> m::Expr {
+        let stg_x = self.fresh(x);
+        let lf = self.transform_lambda_form(s, scope);
+
+        let mut body_scope = scope.clone();
+        body_scope.push((x.to_owned(), stg_x.clone()));
+
         m::ExprNode::Let(
             m::LetType::NonRecursive,
-            bindings,
-            m::ExprNode::App(app_type, f, vs_expr).into(),
+            vec![m::Binding(stg_x, lf)],
+            self.transform_term(t, &body_scope),
         ).into()
     }
-}
 
-fn is_ctor(var: &str) -> bool {
-    vec![
-        "succ",
-        "zero",
-        "true",
-        "false",
-        "nil",
-        "cons",
-    ].contains(&var)
-}
+    /// A lambda which isn't bound to anything is bound to a fresh name: let f = {...} \n {x} -> ... in f {}
+    fn transform_term_lam(&mut self, term: &q::Term, scope: &Scope) -> m::Expr {
+        let f = self.fresh("gensym");
+        let lf = self.transform_lambda_form(term, scope);
 
-fn transform_term_match(t: q::Term, match_arms: &[q::MatchArm], program: &q::Module) -> m::Expr {
-    let t_expr = transform_term(t.clone(), program);
-    let arm_exprs = m::Alts(match_arms.iter().map(|q::MatchArm(pat, s)| {
-        let (ctor, xs) = pat.split_first().unwrap(); // TODO
-        let xs = xs.iter().map(|x| x.to_owned()).collect();
-        let s_expr = transform_term(s.clone(), program);
-        m::Alt::Ctor(ctor.clone(), xs, s_expr)
-    }).collect());
-
-    m::ExprNode::Case(
-        t_expr,
-        arm_exprs,
-    ).into()
-}
+        m::ExprNode::Let(
+            m::LetType::NonRecursive,
+            vec![m::Binding(f.clone(), lf)],
+            m::ExprNode::App(m::AppType::Fun, f, vec![]).into(),
+        ).into()
+    }
+
+    ///
+    /// Arguments in STG must be atoms, so any argument which isn't a variable or a literal is
+    /// bound to a fresh name first. A constructor applied to all of its arguments is built
+    /// right away, while one which is applied to fewer is a call to its binding.
+    ///
+    fn transform_term_app(&mut self, t: &q::Term, vs: &[q::Term], scope: &Scope) -> m::Expr {
+        let mut temps = vec![];
 
-fn transform_term_var(var: &q::Variable, program: &q::Module) -> m::Expr {
-    dbg!(&var.name);
-    assert_eq!(var.layer, 0); // TODO deal with this
-    if is_ctor(&var.name) {
-        m::ExprNode::App(m::AppType::Ctor, var.name.clone(), vec![]).into()
-    } else {
-        m::ExprNode::App(m::AppType::Fun, var.name.clone(), vec![]).into()
+        let f = match t.as_ref() {
+            q::TermNode::Var(var) => self.resolve(var, scope),
+            _ => self.bind_temp(t, scope, &mut temps),
+        };
+
+        let vs_atoms = vs.iter().map(|v| self.transform_atom(v, scope, &mut temps)).collect();
+
+        let app_type = if self.ctors.get(&f) == Some(&vs.len()) {
+            m::AppType::Ctor
+        } else {
+            m::AppType::Fun
+        };
+
+        let app = m::ExprNode::App(app_type, f, vs_atoms).into();
+        if temps.is_empty() {
+            app
+        } else {
+            m::ExprNode::Let(m::LetType::NonRecursive, temps, app).into()
+        }
+    }
+
+    fn transform_atom(&mut self, term: &q::Term, scope: &Scope, temps: &mut Vec<m::Binding>) -> m::Atom {
+        match term.as_ref() {
+            q::TermNode::Var(var) => m::Atom::Var(self.resolve(var, scope)),
+            q::TermNode::StrLit(s) => m::Atom::Lit(m::Lit::Str(s.as_str().into())),
+            q::TermNode::NatLit(n) => m::Atom::Lit(m::Lit::Int(n.clone())),
+            q::TermNode::As(t, _typ) => self.transform_atom(t, scope, temps),
+            _ => m::Atom::Var(self.bind_temp(term, scope, temps)),
+        }
     }
-}
 
-fn transform_term(term: q::Term, program: &q::Module) -> m::Expr {
-    use q::TermNode::*;
+    fn bind_temp(&mut self, term: &q::Term, scope: &Scope, temps: &mut Vec<m::Binding>) -> m::Var {
+        let name = self.fresh("gensym");
+        let lf = self.transform_lambda_form(term, scope);
+        temps.push(m::Binding(name.clone(), lf));
+        name
+    }
 
-    match term.as_ref() {
-        As(t, typ) => transform_term(t.clone(), program),
-        StrLit(_s) => todo!(),
-        Hole(_s) => todo!(),
-        Let(x, s, t) => transform_term_let(x, s.clone(), t.clone(), program),
-        Var(var) => transform_term_var(var, program),
-        Lam(x, t) => transform_term_lam(&x, t.clone(), program),
-        App(t, vs) => transform_term_app(t.clone(), vs, program),
-        Match(t, match_arms) => transform_term_match(t.clone(), &match_arms, program),
+    fn transform_term_match(&mut self, t: &q::Term, match_arms: &[q::MatchArm], scope: &Scope) -> m::Expr {
+        let is_nat = match_arms.iter().any(|q::MatchArm(pat, _s, _span)| pat[0] == "zero" || pat[0] == "succ");
+        if is_nat {
+            return self.transform_term_match_nat(t, match_arms, scope);
+        }
+
+        let t_expr = self.transform_term(t, scope);
+        let alts = match_arms.iter().map(|q::MatchArm(pat, s, _span)| {
+            let (ctor, xs) = pat.split_first().unwrap();
+            let mut arm_scope = scope.clone();
+            let stg_xs = xs.iter().map(|x| {
+                let stg_x = self.fresh(x);
+                arm_scope.push((x.clone(), stg_x.clone()));
+                stg_x
+            }).collect();
+            m::Alt::Ctor(ctor.clone(), stg_xs, self.transform_term(s, &arm_scope))
+        }).collect();
+
+        m::ExprNode::Case(t_expr, m::Alts(alts)).into()
     }
-}
 
-fn get_var_name(t: q::Term) -> Option<String> {
-    if let q::TermNode::Var(v) = t.as_ref() {
-        Some(v.name.clone())
-    } else {
-        None
+    ///
+    /// Nats are Ints, so a match on one is a case on an Int:
+    /// case t of 0 -> zero arm; k -> case -1 {k} of n' -> succ arm
+    ///
+    fn transform_term_match_nat(&mut self, t: &q::Term, match_arms: &[q::MatchArm], scope: &Scope) -> m::Expr {
+        let t_expr = self.transform_term(t, scope);
+        let mut alts = vec![];
+        for q::MatchArm(pat, s, _span) in match_arms {
+            match pat.as_slice() {
+                [zero] if zero == "zero" => alts.push(m::Alt::Lit(0.into(), self.transform_term(s, scope))),
+                [succ, x] if succ == "succ" => {
+                    let k = self.fresh("k");
+                    let stg_x = self.fresh(x);
+                    let mut arm_scope = scope.clone();
+                    arm_scope.push((x.clone(), stg_x.clone()));
+
+                    let pred = m::ExprNode::App(m::AppType::Prim, "-1".to_owned(), vec![m::Atom::Var(k.clone())]).into();
+                    let arm = m::ExprNode::Case(
+                        pred,
+                        m::Alts(vec![m::Alt::Default(stg_x, self.transform_term(s, &arm_scope))]),
+                    ).into();
+                    alts.push(m::Alt::Default(k, arm));
+                },
+                _ => unreachable!("Patterns on Nat are either zero or succ n"),
+            }
+        }
+
+        m::ExprNode::Case(t_expr, m::Alts(alts)).into()
     }
 }
 
-fn decide_updatability() -> bool {
-    true
-}
+/// The variables an expression refers to without binding them itself, in the order they first appear.
+fn free_vars(e: &m::Expr) -> Vec<m::Var> {
+    fn gather(e: &m::ExprNode, bound: &mut Vec<m::Var>, free: &mut Vec<m::Var>) {
+        fn note(v: &m::Var, bound: &[m::Var], free: &mut Vec<m::Var>) {
+            if !bound.contains(v) && !free.contains(v) {
+                free.push(v.clone());
+            }
+        }
 
-fn top_level_vars(module: &q::Module) -> Vec<m::Var> {
-    let mut vars = vec![];
-    for q::Def(var, _typ, _term) in module.definitions.iter() {
-        vars.push(var.clone());
+        match e {
+            m::ExprNode::App(app_type, f, vs) => {
+                if let m::AppType::Fun = app_type {
+                    note(f, bound, free);
+                }
+                for v in vs {
+                    if let m::Atom::Var(v) = v {
+                        note(v, bound, free);
+                    }
+                }
+            },
+            m::ExprNode::Let(let_type, bindings, body) => {
+                let depth = bound.len();
+                if let m::LetType::Recursive = let_type {
+                    bound.extend(bindings.iter().map(|m::Binding(name, _lf)| name.clone()));
+                }
+                for m::Binding(_name, m::LambdaForm(vs, _pi, _xs, _e)) in bindings {
+                    for v in vs {
+                        note(v, bound, free);
+                    }
+                }
+                bound.truncate(depth);
+                bound.extend(bindings.iter().map(|m::Binding(name, _lf)| name.clone()));
+                gather(body, bound, free);
+                bound.truncate(depth);
+            },
+            m::ExprNode::Case(scrutinee, alts) => {
+                gather(scrutinee, bound, free);
+                for alt in alts.0.iter() {
+                    let depth = bound.len();
+                    let e = match alt {
+                        m::Alt::Ctor(_c, xs, e) => {
+                            bound.extend(xs.iter().cloned());
+                            e
+                        },
+                        m::Alt::Lit(_k, e) => e,
+                        m::Alt::Default(x, e) => {
+                            bound.push(x.clone());
+                            e
+                        },
+                    };
+                    gather(e, bound, free);
+                    bound.truncate(depth);
+                }
+            },
+            m::ExprNode::Lit(_k) => (),
+        }
     }
-    vars
-}
 
-fn gather_free_vars(t: q::Term, module: &q::Module) -> Vec<m::Var> {
-    let free_vars = t.free_vars().iter().map(|v| v.name.clone()).collect::<HashSet<String>>();
-    let top_level_vars = top_level_vars(module).iter().map(|s| s.to_owned()).collect::<HashSet<String>>();
-    let ctors: HashSet<String> = [
-        "zero".to_owned(),
-        "succ".to_owned(),
-        "nil".to_owned(),
-        "cons".to_owned(),
-        "true".to_owned(),
-        "false".to_owned(),
-    ].iter().cloned().collect::<HashSet<_>>();
-
-    let free_vars: Vec<m::Var> = free_vars.difference(&top_level_vars).cloned().collect::<HashSet<_>>().difference(&ctors).cloned().collect::<Vec<_>>();
-    assert!(!free_vars.contains(&"succ".to_owned()));
-    free_vars
+    let mut free = vec![];
+    gather(e, &mut vec![], &mut free);
+    free
 }
@@ -14,6 +14,1049 @@ fn run_examples() {
         let mut import_resolver = FileImportResolver::new("examples");
         let import_name = filename.file_stem().unwrap().to_str().unwrap();
         runtime.import(&import_name, &mut import_resolver, true).unwrap();
-        runtime.exec();
+        runtime.exec().unwrap();
+    }
+}
+
+#[test]
+fn holes_are_filled_once() {
+    use std::cell::Cell;
+    use std::rc;
+
+    use crate::context::Context;
+    use crate::parser;
+
+    let mut runtime = Runtime::new();
+    let hole_count = rc::Rc::new(Cell::new(0));
+    let handler_hole_count = hole_count.clone();
+    runtime.set_hole_handler(rc::Rc::new(move |runtime, hole_info, _ctx| {
+        handler_hole_count.set(handler_hole_count.get() + 1);
+        let hole_typing = &runtime.hole_typings[&hole_info.hole_id];
+        assert_eq!(format!("{}", *hole_typing.typ), "Nat");
+        assert!(hole_typing.ctx.lookup("n", 0).is_some());
+        Ok(parser::parse_term(None, "succ n").unwrap())
+    }));
+
+    let definition = parser::parse_def(None, "def f : Nat -> Nat = fun n => ?").unwrap();
+    runtime.define(&definition).unwrap();
+
+    let term = parser::parse_term(None, "f (f zero)").unwrap();
+    let value = runtime.eval(&term, Context::empty()).unwrap();
+    let value = runtime.force_deep(&value).unwrap();
+    assert_eq!(format!("{:?}", value), "succ (succ (zero))");
+    assert_eq!(hole_count.get(), 1);
+}
+
+#[test]
+fn aborting_at_a_hole_ends_the_evaluation() {
+    use std::rc;
+
+    use crate::runtime::Cause;
+    use crate::context::Context;
+    use crate::parser;
+
+    let mut runtime = Runtime::new();
+    runtime.set_hole_handler(rc::Rc::new(|runtime, hole_info, _ctx| {
+        Err(runtime.error(Cause::Aborted(hole_info.clone())))
+    }));
+
+    let definition = parser::parse_def(None, "def f : Nat -> Nat = fun n => ?stop").unwrap();
+    runtime.define(&definition).unwrap();
+
+    let term = parser::parse_term(None, "f zero").unwrap();
+    let error = runtime.eval(&term, Context::empty())
+        .and_then(|value| runtime.force(&value))
+        .unwrap_err();
+    assert!(matches!(error.cause.as_ref(), Cause::Aborted(_)));
+    assert_eq!(error.to_string(), "Aborted at the hole ?stop");
+
+    // The hole wasn't filled, so it is asked about again next time.
+    assert!(runtime.hole_fills.is_empty());
+}
+
+/// Resolves imports from a fixed set of in-memory modules.
+struct TextImportResolver(Vec<(&'static str, &'static str)>);
+
+impl crate::resolver::ImportResolver for TextImportResolver {
+    fn resolve(&mut self, import_name: &str) -> Result<crate::resolver::ResolvedImport, std::io::Error> {
+        let TextImportResolver(modules) = self;
+        match modules.iter().find(|(name, _text)| *name == import_name) {
+            Some((name, text)) => Ok(crate::resolver::ResolvedImport {
+                reader: Box::new(std::io::Cursor::new(text.to_string())),
+                source: name.to_string(),
+            }),
+            None => Err(std::io::Error::new(std::io::ErrorKind::NotFound, import_name.to_string())),
+        }
+    }
+}
+
+#[test]
+fn failed_imports_can_be_retried() {
+    use crate::context::Context;
+    use crate::parser;
+
+    let mut runtime = Runtime::new();
+    let mut import_resolver = TextImportResolver(vec![
+        ("helper", "def two : Nat = 2"),
+        ("broken", "import helper\ninductive Color\n    red\n    green\ndef three : Nat = succ true"),
+    ]);
+    assert!(runtime.import("broken", &mut import_resolver, false).is_err());
+    assert!(runtime.imports.is_empty());
+    assert!(runtime.definition_type_ctx.lookup("two", 0).is_none());
+    assert!(!runtime.inductive_typedefs.contains_key("Color"));
+    assert!(runtime.builtin_type_ctx.lookup("red", 0).is_none());
+
+    let mut import_resolver = TextImportResolver(vec![
+        ("helper", "def two : Nat = 2"),
+        ("broken", "import helper\ninductive Color\n    red\n    green\ndef three : Nat = succ two"),
+    ]);
+    runtime.import("broken", &mut import_resolver, false).unwrap();
+    let term = parser::parse_term(None, "three").unwrap();
+    let value = runtime.eval(&term, Context::empty()).unwrap();
+    assert_eq!(runtime.show(value).unwrap(), "3");
+}
+
+#[test]
+fn user_defined_types() {
+    let mut import_resolver = TextImportResolver(vec![
+        ("color", "
+            inductive Color
+                red
+                green
+                blue
+
+            def next : Color -> Color = fun c =>
+                match c
+                    with red => green
+                    with green => blue
+                    with blue => red
+        "),
+        ("main", "
+            import color
+
+            coinductive Stream
+                scons Color Stream
+
+            inductive Tree
+                leaf
+                node Tree Color Tree
+
+            def tree : Tree = node leaf (next red) leaf
+        "),
+    ]);
+
+    let mut runtime = Runtime::new();
+    runtime.import("main", &mut import_resolver, true).unwrap();
+    assert!(runtime.inductive_typedefs.contains_key("Color"));
+    assert!(runtime.inductive_typedefs.contains_key("Stream"));
+    assert!(runtime.builtin_type_ctx.lookup("node", 0).is_some());
+    let tree = runtime.definition_ctx.lookup("tree", 0).unwrap();
+    let tree = runtime.force_deep(&tree).unwrap();
+    assert_eq!(format!("{:?}", tree), "node (leaf) (green) (leaf)");
+}
+
+#[test]
+fn bad_type_decls() {
+    let bad_modules = vec![
+        "inductive Nat\n zero",
+        "inductive Foo\n foo\n foo Nat",
+        "inductive Foo\n succ Nat",
+        "inductive Foo\n foo Bar",
+        "inductive Foo\n foo (Foo -> Nat)",
+        "inductive Foo a a\n foo a",
+        "inductive Foo a\n foo b",
+        "inductive Foo\n foo List",
+        "inductive Foo a\n foo (Foo a a)",
+    ];
+
+    for text in bad_modules {
+        let mut import_resolver = TextImportResolver(vec![("bad", text)]);
+        let mut runtime = Runtime::new();
+        assert!(runtime.import("bad", &mut import_resolver, true).is_err(), "{:?} should be rejected", text);
+    }
+}
+
+#[test]
+fn polymorphic_definitions() {
+    use crate::parser;
+
+    let mut runtime = Runtime::new();
+    let good_defs = vec![
+        "def id : a -> a = fun x => x",
+        "def const : a -> b -> a = fun x y => x",
+        "def twice : (a -> a) -> a -> a = fun f x => f (f x)",
+        "def singleton : a -> List a = fun x => cons x nil",
+        "def length : List a -> Nat = fun xs => match xs with nil => zero with cons x xs' => succ (length xs')",
+        "def two : Nat = length (cons (id top) (singleton top) as List Top)",
+        "def ids : List (forall a. a -> a) = nil",
+    ];
+    for text in good_defs {
+        let definition = parser::parse_def(None, text).unwrap();
+        runtime.define(&definition).unwrap_or_else(|e| panic!("{:?} should typecheck: {:?}", text, e));
+    }
+
+    let bad_defs = vec![
+        "def bad : a -> b = fun x => x",
+        "def bad : a -> Nat = fun x => x",
+        "def bad : List Nat = cons top nil",
+        "def bad : List = nil",
+        "def bad : Nat Nat = zero",
+        "def bad : Nat = id",
+    ];
+    for text in bad_defs {
+        let definition = parser::parse_def(None, text).unwrap();
+        assert!(runtime.define(&definition).is_err(), "{:?} should be rejected", text);
+    }
+}
+
+#[test]
+fn principal_types() {
+    use crate::parser;
+    use crate::types::check;
+
+    let runtime = Runtime::new();
+    let examples = vec![
+        ("fun x => x", "forall a. a -> a"),
+        ("fun x y => x", "forall a. forall b. a -> b -> a"),
+        ("fun f g x => f (g x)", "forall a. forall b. forall c. (a -> b) -> (c -> a) -> c -> b"),
+        ("fun n => match n with zero => true with succ m => false", "Nat -> Bool"),
+        ("fun xs => match xs with nil => zero with cons x xs' => x", "List Nat -> Nat"),
+        ("fun x => cons x nil", "forall a. a -> List a"),
+        ("let id = fun x => x in id (cons (id zero) nil)", "List Nat"),
+        ("let x = zero in succ x", "Nat"),
+        ("fun x => ?", "forall a. forall b. a -> b"),
+    ];
+
+    for (text, expected) in examples {
+        let term = parser::parse_term(None, text).unwrap();
+        let type_context = runtime.builtin_type_ctx.append(runtime.definition_type_ctx.clone());
+        let mut hole_typings = check::HoleTypings::new();
+        let typ = check::infer_type(&term, type_context, &runtime.inductive_typedefs, &mut hole_typings)
+            .unwrap_or_else(|e| panic!("{:?} should have a type: {:?}", text, e));
+        assert_eq!(format!("{}", *typ), expected, "{:?}", text);
+    }
+
+    let bad_terms = vec![
+        "fun x => x x",
+        "fun f => cons (f zero) (f true)",
+        "match zero with zero => true with succ n => zero",
+    ];
+
+    for text in bad_terms {
+        let term = parser::parse_term(None, text).unwrap();
+        let type_context = runtime.builtin_type_ctx.append(runtime.definition_type_ctx.clone());
+        let mut hole_typings = check::HoleTypings::new();
+        assert!(check::infer_type(&term, type_context, &runtime.inductive_typedefs, &mut hole_typings).is_err(), "{:?} should be rejected", text);
+    }
+}
+
+#[test]
+fn termination() {
+    use crate::parser;
+
+    let mut runtime = Runtime::new();
+    let good_defs = vec![
+        "def add : Nat -> Nat -> Nat = fun n m => match n with zero => m with succ n => succ (add n m)",
+        "def sub : Nat -> Nat -> Nat = fun n m => match m with zero => n with succ m' => (match n with zero => zero with succ n' => sub n' m')",
+        "def half : Nat -> Nat = fun n => match n with zero => zero with succ n' => (match n' with zero => zero with succ n'' => succ (half n''))",
+        "def length : List a -> Nat = fun xs => match xs with nil => zero with cons x xs' => let ys = xs' in succ (length ys)",
+        "def zero_again : Nat -> Nat = fun n => match n with zero => zero with succ zero_again => zero_again",
+    ];
+    for text in good_defs {
+        let definition = parser::parse_def(None, text).unwrap();
+        runtime.define(&definition).unwrap_or_else(|e| panic!("{:?} should be accepted: {:?}", text, e));
+    }
+
+    let bad_defs = vec![
+        ("def loop : Nat = loop", "`loop`"),
+        ("def loop : Nat -> Nat = fun n => loop n", "`loop n`"),
+        ("def loop : Nat -> Nat = fun n => loop (succ n)", "`loop (succ n)`"),
+        ("def loop : Nat -> Nat = fun n => match n with zero => zero with succ m => loop n", "`loop n`"),
+        ("def loop : Nat -> Nat = fun n => match n with zero => zero with succ n => loop n$1", "`loop n$1`"),
+        ("def loop : Nat -> Nat -> Nat = fun n m => match n with zero => zero with succ n' => (match m with zero => loop n' m with succ m' => loop n m')", "`loop n m'`"),
+        ("def loop : (Nat -> Nat) -> Nat = fun f => f (loop f)", "`loop f`"),
+    ];
+    for (text, call_site) in bad_defs {
+        let definition = parser::parse_def(None, text).unwrap();
+        let err = runtime.define(&definition).unwrap_err().to_string();
+        assert!(err.contains(call_site), "{:?} should be rejected at {}, but got {}", text, call_site, err);
+    }
+}
+
+#[test]
+fn productivity() {
+    use crate::parser;
+
+    let mut import_resolver = TextImportResolver(vec![("stream", "
+        coinductive Stream
+            scons Nat Stream
+    ")]);
+    let mut runtime = Runtime::new();
+    runtime.import("stream", &mut import_resolver, true).unwrap();
+
+    let good_defs = vec![
+        "def infinity : CoNat = cosucc infinity",
+        "def zeros : Stream = scons zero zeros",
+        "def nats_from : Nat -> Stream = fun n => scons n (nats_from (succ n))",
+        "def stail : Stream -> Stream = fun s => match s with scons x s' => s'",
+        "def interleave : Stream -> Stream -> Stream = fun s t => match s with scons x s' => scons x (interleave t s')",
+        "def down_from : Nat -> CoNat = fun n => match n with zero => cozero with succ n' => cosucc (let m = n' in down_from m)",
+        "def count : Nat -> CoNat -> Nat = fun n c => match n with zero => zero with succ n' => (match c with cozero => zero with cosucc c' => succ (count n' c'))",
+    ];
+    for text in good_defs {
+        let definition = parser::parse_def(None, text).unwrap();
+        runtime.define(&definition).unwrap_or_else(|e| panic!("{:?} should be accepted: {:?}", text, e));
+    }
+
+    let bad_defs = vec![
+        ("def loop : CoNat = loop", "`loop`"),
+        ("def loop : Stream -> Stream = fun s => stail (loop s)", "`loop s`"),
+        ("def loop : Stream -> Stream = fun s => scons zero (stail (loop s))", "`loop s`"),
+        ("def loop : Stream -> Stream = fun s => match loop s with scons x s' => scons x s'", "`loop s`"),
+        ("def loop : CoNat -> Nat = fun c => match c with cozero => zero with cosucc c' => succ (loop c')", "coinductive"),
+    ];
+    for (text, expected) in bad_defs {
+        let definition = parser::parse_def(None, text).unwrap();
+        let err = runtime.define(&definition).unwrap_err().to_string();
+        assert!(err.contains(expected), "{:?} should be rejected mentioning {}, but got {}", text, expected, err);
+    }
+}
+
+#[test]
+fn located_type_errors() {
+    use crate::parser;
+    use crate::types::check;
+    use crate::types::check::TypeErr;
+
+    fn type_error(text: &str) -> TypeErr {
+        let runtime = Runtime::new();
+        let definition = parser::parse_def(Some("test.ql".to_string()), text).unwrap();
+        let crate::ast::Def(name, typ, body, _span) = &definition;
+        let type_context = runtime.builtin_type_ctx.extend(name, typ.clone());
+        let mut hole_typings = check::HoleTypings::new();
+        check::check_type_wellformed(typ, &runtime.inductive_typedefs)
+            .and_then(|()| check::check_type(body, type_context, &runtime.inductive_typedefs, &mut hole_typings, typ.clone()))
+            .and_then(|()| check::check_totality(&definition, &runtime.inductive_typedefs))
+            .map_err(|error| *error)
+            .unwrap_err()
+    }
+
+    fn start(error: &TypeErr) -> (usize, usize) {
+        let span = error.span().expect("error should have a span");
+        assert_eq!(span.start.path, Some("test.ql".to_string()));
+        (span.start.line, span.start.col)
+    }
+
+    let error = type_error("def f : Nat -> Bool = fun n =>\n    match n\n        with zero => true\n        with succ m => m");
+    match &error {
+        TypeErr::Mismatch { expected, actual, .. } => {
+            assert_eq!(format!("{}", **expected), "Bool");
+            assert_eq!(format!("{}", **actual), "Nat");
+        },
+        _ => panic!("Expected a mismatch, but found {:?}", error),
+    }
+    assert_eq!(start(&error), (3, 23));
+
+    let error = type_error("def f : Nat -> Nat = fun n =>\n    match n\n        with zero => n");
+    match &error {
+        TypeErr::Coverage { missing, unexpected, duplicate, .. } => {
+            assert_eq!(missing, &vec!["succ".to_string()]);
+            assert!(unexpected.is_empty());
+            assert!(duplicate.is_empty());
+        },
+        _ => panic!("Expected a coverage error, but found {:?}", error),
+    }
+    assert_eq!(start(&error), (1, 4));
+
+    let error = type_error("def f : Nat -> Natural = fun n => n");
+    assert!(matches!(error, TypeErr::UnknownType { .. }));
+    assert_eq!(start(&error), (0, 15));
+
+    let error = type_error("def f : Nat -> Nat = fun n => succ (f n)");
+    assert!(matches!(error, TypeErr::NonTerminating { .. }));
+    assert_eq!(start(&error), (0, 36));
+}
+
+#[test]
+fn located_parse_errors() {
+    use crate::parser;
+    use crate::parser::ParseErr;
+
+    let error = parser::parse_def(None, "def f : Nat =\n    fun n => )").unwrap_err();
+    assert!(matches!(error, ParseErr::UnexpectedToken { .. }), "{:?}", error);
+    assert_eq!((error.span().start.line, error.span().start.col), (1, 13));
+
+    let error = parser::parse_def(None, "def f : Nat").unwrap_err();
+    assert!(matches!(error, ParseErr::UnexpectedEnd { .. }), "{:?}", error);
+    assert_eq!((error.span().start.line, error.span().start.col), (0, 11));
+
+    let error = parser::parse_term(None, "f \"oops").unwrap_err();
+    assert!(matches!(error, ParseErr::Tokenize { .. }), "{:?}", error);
+}
+
+#[test]
+fn rendered_diagnostics() {
+    let render = |text: &'static str| {
+        let mut runtime = Runtime::new();
+        let mut import_resolver = TextImportResolver(vec![("bad", text)]);
+        let error = runtime.import("bad", &mut import_resolver, true).unwrap_err();
+        runtime.render_error(&error)
+    };
+
+    assert_eq!(
+        render("def f : Nat -> Bool = fun n => n"),
+        "\
+error: Expected Bool but found Nat
+ --> bad:1:32
+  |
+1 | def f : Nat -> Bool = fun n => n
+  |                                ^",
+    );
+
+    assert_eq!(
+        render("def f : Nat -> Nat = fun n =>\n    match n\n        with zero => n"),
+        "\
+error: Match statement must have exactly one arm for each constructor
+ --> bad:2:5
+  |
+2 |     match n
+  |     ^^^^^^^
+  = note: missing tags: succ",
+    );
+
+    assert_eq!(
+        render("def f : Nat = (zero"),
+        "\
+error: Expected \")\" but found end of input.
+ --> bad:1:20
+  |
+1 | def f : Nat = (zero
+  |                    ^",
+    );
+
+    let mut runtime = Runtime::new();
+    let mut import_resolver = TextImportResolver(vec![]);
+    let error = runtime.import("missing", &mut import_resolver, true).unwrap_err();
+    assert!(runtime.render_error(&error).starts_with("error: Could not import missing: "));
+}
+
+#[test]
+fn json_diagnostics() {
+    let mut runtime = Runtime::new();
+    let mut import_resolver = TextImportResolver(vec![("bad", "def f : Nat -> Nat = fun n =>\n    match n\n        with zero => n")]);
+    let error = runtime.import("bad", &mut import_resolver, true).unwrap_err();
+    assert_eq!(
+        error.diagnostic().to_json(),
+        serde_json::json!({
+            "severity": "error",
+            "code": "coverage",
+            "message": "Match statement must have exactly one arm for each constructor",
+            "file": "bad",
+            "range": {
+                "start": { "line": 2, "col": 5 },
+                "end": { "line": 3, "col": 23 },
+            },
+            "notes": ["missing tags: succ"],
+        }),
+    );
+
+    let mut import_resolver = TextImportResolver(vec![]);
+    let error = runtime.import("missing", &mut import_resolver, true).unwrap_err();
+    let json = error.diagnostic().to_json();
+    assert_eq!(json["code"], "import");
+    assert_eq!(json["file"], serde_json::Value::Null);
+    assert_eq!(json["range"], serde_json::Value::Null);
+}
+
+#[test]
+fn language_server() {
+    use crate::lsp;
+    use serde_json::json;
+
+    let import_resolver = TextImportResolver(vec![("numbers", "def two : Nat = succ (succ zero)")]);
+    let mut server = lsp::Server::new(Box::new(import_resolver));
+
+    let uri = "file:///work/main.ql";
+    let text = "import numbers\n\ndef three : Nat = succ two\ndef four : Nat = succ ?hmm\ndef bad : Bool = three\n";
+    let requests = vec![
+        json!({ "jsonrpc": "2.0", "id": 1, "method": "initialize", "params": { "capabilities": {} } }),
+        json!({ "jsonrpc": "2.0", "method": "initialized", "params": {} }),
+        json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": { "textDocument": { "uri": uri, "languageId": "quail", "version": 1, "text": text } },
+        }),
+        json!({
+            "jsonrpc": "2.0",
+            "id": 2,
+            "method": "textDocument/hover",
+            "params": { "textDocument": { "uri": uri }, "position": { "line": 2, "character": 23 } },
+        }),
+        json!({
+            "jsonrpc": "2.0",
+            "id": 3,
+            "method": "textDocument/definition",
+            "params": { "textDocument": { "uri": uri }, "position": { "line": 2, "character": 23 } },
+        }),
+        json!({
+            "jsonrpc": "2.0",
+            "id": 4,
+            "method": "textDocument/hover",
+            "params": { "textDocument": { "uri": uri }, "position": { "line": 3, "character": 23 } },
+        }),
+        json!({ "jsonrpc": "2.0", "id": 5, "method": "textDocument/formatting", "params": {} }),
+        json!({ "jsonrpc": "2.0", "id": 6, "method": "shutdown" }),
+        json!({ "jsonrpc": "2.0", "method": "exit" }),
+    ];
+
+    let mut input = vec![];
+    for request in requests.iter() {
+        lsp::write_message(&mut input, request).unwrap();
+    }
+    let mut output = vec![];
+    server.run(&mut std::io::Cursor::new(input), &mut output).unwrap();
+
+    let mut output = std::io::Cursor::new(output);
+    let mut responses = vec![];
+    while let Some(response) = lsp::read_message(&mut output).unwrap() {
+        responses.push(response);
+    }
+    assert_eq!(responses.len(), 7);
+
+    assert_eq!(responses[0]["id"], 1);
+    assert_eq!(responses[0]["result"]["capabilities"]["hoverProvider"], true);
+
+    assert_eq!(responses[1]["method"], "textDocument/publishDiagnostics");
+    let diagnostics = &responses[1]["params"]["diagnostics"];
+    assert_eq!(diagnostics.as_array().unwrap().len(), 2);
+    assert_eq!(diagnostics[0]["code"], "mismatch");
+    assert_eq!(diagnostics[0]["severity"], 1);
+    assert_eq!(diagnostics[0]["range"]["start"], json!({ "line": 4, "character": 17 }));
+    assert_eq!(diagnostics[1]["code"], "hole");
+    assert_eq!(diagnostics[1]["severity"], 3);
+    assert_eq!(diagnostics[1]["message"], "Hole ?hmm has type Nat");
+
+    assert_eq!(responses[2]["result"]["contents"], "two : Nat");
+    assert_eq!(responses[3]["result"]["uri"], "file://numbers");
+    assert_eq!(responses[3]["result"]["range"]["start"], json!({ "line": 0, "character": 0 }));
+    assert_eq!(responses[4]["result"]["contents"], "?hmm : Nat");
+    assert_eq!(responses[5]["error"]["code"], -32601);
+    assert_eq!(responses[6]["id"], 6);
+    assert_eq!(responses[6]["result"], serde_json::Value::Null);
+}
+
+#[test]
+fn thunks_are_evaluated_once() {
+    use std::cell::Cell;
+    use std::convert::TryFrom;
+    use std::rc::Rc;
+
+    use crate::ast::Type;
+    use crate::context::Context;
+    use crate::parser;
+    use crate::runtime::Value;
+
+    let mut import_resolver = TextImportResolver(vec![("sharing", "
+        def add : Nat -> Nat -> Nat = fun n m =>
+            match n
+                with zero => m
+                with succ n' => succ (add n' m)
+
+        def double : Nat -> Nat = fun n => add n n
+
+        def thrice : Nat -> Nat = fun n =>
+            match n
+                with zero => n
+                with succ m => (match n with zero => n with succ m' => n)
+    ")]);
+    let mut runtime = Runtime::new();
+    runtime.import("sharing", &mut import_resolver, true).unwrap();
+
+    // tick is the identity on Nat, but it counts how many times it is evaluated.
+    let ticks = Rc::new(Cell::new(0));
+    let counter = ticks.clone();
+    runtime.builtin_ctx = runtime.builtin_ctx.extend("tick", Value::Prim(Rc::new(move |_runtime: &mut Runtime, vs: Vec<Value>| {
+        counter.set(counter.get() + 1);
+        Ok(vs[0].clone())
+    }), 1, vec![]));
+    runtime.builtin_type_ctx = runtime.builtin_type_ctx.extend("tick", Type::try_from("Nat -> Nat").unwrap());
+
+    for (text, expected) in vec![
+        ("double (tick (succ (succ zero)))", 4),
+        ("thrice (tick (succ zero))", 1),
+        ("(fun x => add x (add x x)) (tick (succ zero))", 3),
+    ] {
+        ticks.set(0);
+        let term = parser::parse_term(None, text).unwrap();
+        let value = runtime.eval(&term, Context::empty()).unwrap();
+        let value = runtime.force_deep(&value).unwrap();
+        assert_eq!(format!("{:?}", value), format!("{}zero", "succ (".repeat(expected)) + &")".repeat(expected));
+        assert_eq!(ticks.get(), 1, "{} should evaluate its argument exactly once", text);
+    }
+}
+
+#[test]
+fn effects_only_happen_in_exec() {
+    use std::cell::Cell;
+    use std::rc;
+
+    use crate::context::Context;
+    use crate::parser;
+
+    // Loading a module mustn't evaluate its definitions, so this hole is never reached.
+    let mut import_resolver = TextImportResolver(vec![("effects", "
+        def unfinished : Nat = ?
+        def greeting : IO Top = println \"hello\"
+        def main : IO Nat = bind (then greeting (pure (succ zero))) (fun n => pure (succ n))
+    ")]);
+    let mut runtime = Runtime::new();
+    let hole_count = rc::Rc::new(Cell::new(0));
+    let handler_hole_count = hole_count.clone();
+    runtime.set_hole_handler(rc::Rc::new(move |_runtime, _hole_info, _ctx| {
+        handler_hole_count.set(handler_hole_count.get() + 1);
+        Ok(parser::parse_term(None, "zero").unwrap())
+    }));
+    runtime.import("effects", &mut import_resolver, true).unwrap();
+    assert_eq!(hole_count.get(), 0);
+
+    // Evaluating an IO term only describes the effect.
+    let term = parser::parse_term(None, "greeting").unwrap();
+    let value = runtime.eval(&term, Context::empty()).unwrap();
+    assert_eq!(format!("{:?}", runtime.force(&value).unwrap()), "<io>");
+
+    let term = parser::parse_term(None, "main").unwrap();
+    let value = runtime.eval(&term, Context::empty()).unwrap();
+    let result = runtime.perform(&value).unwrap();
+    assert_eq!(format!("{:?}", runtime.force_deep(&result).unwrap()), "succ (succ (zero))");
+    runtime.exec().unwrap();
+
+    let mut import_resolver = TextImportResolver(vec![("pure", "def main : Nat = zero")]);
+    let mut runtime = Runtime::new();
+    runtime.import("pure", &mut import_resolver, true).unwrap();
+    let error = runtime.exec().unwrap_err();
+    assert_eq!(error.to_string(), "main should have the type IO a, but it has the type Nat");
+}
+
+#[test]
+fn runtime_errors_carry_a_stack_trace() {
+    use crate::context::Context;
+    use crate::parser;
+    use crate::runtime::Cause;
+    use crate::runtime::Frame;
+    use crate::runtime::Value;
+
+    let mut import_resolver = TextImportResolver(vec![("stuck", "def f : Nat -> Nat = fun n => match n
+    with zero => ?oops
+    with succ m => m
+def main : IO Top = println (show (f zero))
+")]);
+    let mut runtime = Runtime::new();
+    runtime.import("stuck", &mut import_resolver, true).unwrap();
+
+    let error = runtime.exec().unwrap_err();
+    assert!(matches!(error.cause.as_ref(), Cause::UnfilledHole(_)));
+    match error.stack.as_slice() {
+        [Frame::Match(Some(_)), Frame::Definition(f, Some(_)), Frame::Definition(main, None)] => {
+            assert_eq!((f.as_str(), main.as_str()), ("f", "main"));
+        },
+        stack => panic!("Unexpected stack {:?}", stack),
+    }
+    assert_eq!(runtime.render_error(&error), "\
+error: Evaluation reached the hole ?oops
+ --> stuck:2:18
+  |
+2 |     with zero => ?oops
+  |                  ^
+  = note: in the match at stuck:1:31
+  = note: in f, called at stuck:4:36
+  = note: in main");
+
+    // The runtime is still usable after an error.
+    assert!(runtime.call_stack.is_empty());
+    let term = parser::parse_term(None, "f (succ zero)").unwrap();
+    let value = runtime.eval(&term, Context::empty()).unwrap();
+    assert_eq!(format!("{:?}", runtime.force_deep(&value).unwrap()), "zero");
+
+    let error = runtime.apply(Value::Str("f".to_string()), vec![Value::Str("x".to_string())]).unwrap_err();
+    assert_eq!(error.to_string(), "Applied arguments to non-function \"f\"");
+
+    let str_length = runtime.builtin_ctx.lookup("str_length", 0).unwrap();
+    let error = runtime.apply(str_length, vec![Value::Ctor("zero".to_string(), vec![])]).unwrap_err();
+    assert_eq!(error.to_string(), "str_length: expected a Str, but found zero");
+}
+
+#[test]
+fn packed_nats() {
+    use crate::context::Context;
+    use crate::parser;
+
+    let mut runtime = Runtime::new();
+    let definition = parser::parse_def(None, "def pred : Nat -> Nat = fun n => match n with zero => zero with succ m => m").unwrap();
+    runtime.define(&definition).unwrap();
+
+    for (text, expected) in vec![
+        // Number literals are packed, but still match as zero or succ.
+        ("pred 1000000000000000000000", "999999999999999999999"),
+        ("pred 1", "0"),
+        ("pred 0", "zero"),
+        ("pred (succ 41)", "41"),
+        ("add 2 (succ (succ zero))", "4"),
+        ("mul 123456789 987654321", "121932631112635269"),
+        ("sub 2 5", "0"),
+        ("sub 5 2", "3"),
+        ("eq (mul 3 4) 12", "true"),
+        ("eq (succ 3) 3", "false"),
+        ("show (succ (succ 40))", "\"42\""),
+    ] {
+        let term = parser::parse_term(None, text).unwrap();
+        let value = runtime.eval(&term, Context::empty()).unwrap();
+        let value = runtime.force_deep(&value).unwrap();
+        assert_eq!(format!("{:?}", value), expected, "{}", text);
+    }
+}
+
+#[test]
+fn deep_recursion_is_stack_safe() {
+    use crate::context::Context;
+    use crate::parser;
+
+    // Neither building nor consuming the list is tail recursive.
+    let mut import_resolver = TextImportResolver(vec![("deep", "
+        def range : Nat -> List Nat = fun n =>
+            match n
+                with zero => nil
+                with succ m => cons m (range m)
+
+        def length : List Nat -> Nat = fun xs =>
+            match xs
+                with nil => zero
+                with cons x xs' => succ (length xs')
+    ")]);
+    let mut runtime = Runtime::new();
+    runtime.import("deep", &mut import_resolver, true).unwrap();
+
+    let term = parser::parse_term(None, "show (length (range 1000000))").unwrap();
+    let value = runtime.eval(&term, Context::empty()).unwrap();
+    assert_eq!(format!("{:?}", runtime.force_deep(&value).unwrap()), "\"1000000\"");
+
+    // Forcing the whole list, and then dropping it, mustn't recurse on the native stack either.
+    let term = parser::parse_term(None, "range 1000000").unwrap();
+    let value = runtime.eval(&term, Context::empty()).unwrap();
+    let list = runtime.force_deep(&value).unwrap();
+    drop(value);
+    drop(list);
+}
+
+#[test]
+fn showing_deep_values_is_stack_safe() {
+    use crate::context::Context;
+    use crate::parser;
+
+    let mut import_resolver = TextImportResolver(vec![("tower", "
+        inductive Tower
+            ground
+            floor Nat Tower
+
+        def build : Nat -> Tower = fun n =>
+            match n
+                with zero => ground
+                with succ m => floor 0 (build m)
+    ")]);
+    let mut runtime = Runtime::new();
+    runtime.import("tower", &mut import_resolver, true).unwrap();
+
+    let term = parser::parse_term(None, "build 200000").unwrap();
+    let value = runtime.eval(&term, Context::empty()).unwrap();
+    let shown = runtime.show(value).unwrap();
+    assert!(shown.starts_with("floor 0 (floor 0 ("));
+    assert!(shown.ends_with(&format!("(floor 0 ground){}", ")".repeat(199998))));
+    assert_eq!(shown.matches("floor").count(), 200000);
+}
+
+#[test]
+fn tail_calls_run_in_constant_space() {
+    use crate::runtime::Cause;
+
+    // The hole stops the loop at its last iteration, so that the stack can be looked at.
+    let mut import_resolver = TextImportResolver(vec![("tail", "
+        def count_down : Nat -> Nat -> Nat = fun n acc =>
+            match n
+                with zero => ?done
+                with succ m => count_down m (succ acc)
+
+        def main : IO Top = println (show (count_down 100000 0))
+    ")]);
+    let mut runtime = Runtime::new();
+    runtime.import("tail", &mut import_resolver, true).unwrap();
+
+    let error = runtime.exec().unwrap_err();
+    assert!(matches!(error.cause.as_ref(), Cause::UnfilledHole(_)));
+    assert!(error.stack.len() <= 4, "The stack grew to {} frames", error.stack.len());
+}
+
+#[test]
+fn evaluation_budget() {
+    use crate::context::Context;
+    use crate::parser;
+    use crate::runtime::Cause;
+    use crate::runtime::Resource;
+
+    let mut import_resolver = TextImportResolver(vec![("busy", "
+        def down : Nat -> Nat = fun n =>
+            match n
+                with zero => zero
+                with succ m => down m
+    ")]);
+    let mut runtime = Runtime::new();
+    runtime.import("busy", &mut import_resolver, true).unwrap();
+    let term = parser::parse_term(None, "down 100000").unwrap();
+
+    runtime.budget.steps = Some(1000);
+    let error = runtime.eval(&term, Context::empty()).unwrap_err();
+    assert!(matches!(error.cause.as_ref(), Cause::OutOfBudget(Resource::Steps, _)));
+    assert_eq!(error.to_string(), "Evaluation ran out of steps, having been limited to 1000 steps");
+    assert_eq!(error.code(), "out-of-budget");
+
+    // Each evaluation is given the whole budget again.
+    let small = parser::parse_term(None, "down 10").unwrap();
+    assert_eq!(format!("{:?}", runtime.eval(&small, Context::empty()).unwrap()), "zero");
+
+    runtime.budget.steps = None;
+    runtime.budget.values = Some(1000);
+    let error = runtime.eval(&term, Context::empty()).unwrap_err();
+    assert!(matches!(error.cause.as_ref(), Cause::OutOfBudget(Resource::Values, _)));
+
+    runtime.budget.values = None;
+    runtime.budget.set(Resource::Time, "0").unwrap();
+    let error = runtime.eval(&term, Context::empty()).unwrap_err();
+    assert!(matches!(error.cause.as_ref(), Cause::OutOfBudget(Resource::Time, _)));
+
+    runtime.budget.set(Resource::Time, "none").unwrap();
+    assert_eq!(format!("{:?}", runtime.eval(&term, Context::empty()).unwrap()), "zero");
+    assert!(runtime.budget.set(Resource::Steps, "lots").is_err());
+}
+
+#[test]
+fn big_numbers_are_charged_to_the_budget() {
+    use crate::context::Context;
+    use crate::parser;
+    use crate::runtime::Cause;
+    use crate::runtime::Resource;
+
+    // Each squaring doubles the size of the number, but takes only a few steps.
+    let mut import_resolver = TextImportResolver(vec![("squares", "
+        def sq : Nat -> Nat = fun n => mul n n
+
+        def squares : Nat -> Nat -> Nat = fun k n =>
+            match k
+                with zero => n
+                with succ j => squares j (sq n)
+    ")]);
+    let mut runtime = Runtime::new();
+    runtime.import("squares", &mut import_resolver, true).unwrap();
+    runtime.budget.values = Some(10000);
+
+    let term = parser::parse_term(None, "squares 40 3").unwrap();
+    let error = runtime.eval(&term, Context::empty()).unwrap_err();
+    assert!(matches!(error.cause.as_ref(), Cause::OutOfBudget(Resource::Values, _)));
+
+    // Small numbers still fit.
+    let term = parser::parse_term(None, "squares 3 3").unwrap();
+    assert_eq!(format!("{:?}", runtime.eval(&term, Context::empty()).unwrap()), "6561");
+}
+
+#[test]
+fn runtime_builder_prims() {
+    use crate::context::Context;
+    use crate::parser;
+    use crate::runtime::Value;
+
+    let mut runtime = Runtime::builder()
+        .prim("shout", "Str -> Str", |_runtime, args| match args.as_slice() {
+            [Value::Str(s)] => Ok(Value::Str(s.to_uppercase())),
+            _ => Err("shout: expected a string".to_string().into()),
+        })
+        .prim("singleton", "forall a. a -> List a", |_runtime, mut args| {
+            let nil = Value::Ctor("nil".to_string(), vec![]);
+            Ok(Value::Ctor("cons".to_string(), vec![args.remove(0), nil]))
+        })
+        .build()
+        .unwrap();
+
+    let mut import_resolver = TextImportResolver(vec![("host", "
+        def greeting : Str = shout (cat \"hello, \" \"world\")
+        def ones : List Nat = singleton 1
+        def main : IO Top = println greeting
+    "), ("mistyped", "def oops : List Str = singleton 1")]);
+    runtime.import("host", &mut import_resolver, true).unwrap();
+
+    let term = parser::parse_term(None, "greeting").unwrap();
+    let value = runtime.eval(&term, Context::empty()).unwrap();
+    assert_eq!(format!("{:?}", runtime.force_deep(&value).unwrap()), "\"HELLO, WORLD\"");
+
+    let term = parser::parse_term(None, "ones").unwrap();
+    let value = runtime.eval(&term, Context::empty()).unwrap();
+    assert_eq!(format!("{:?}", runtime.force_deep(&value).unwrap()), "cons (1) (nil)");
+
+    // Uses of a prim are typechecked like those of any other builtin.
+    let error = runtime.import("mistyped", &mut import_resolver, false).unwrap_err();
+    assert!(matches!(error.cause.as_ref(), crate::runtime::Cause::Type(_)));
+
+    let dummy = |_runtime: &mut Runtime, _args: Vec<Value>| Ok(Value::Str(String::new()));
+    let error = Runtime::builder().prim("show", "Nat -> Str", dummy).build().err().unwrap();
+    assert_eq!(error.to_string(), "There is already a builtin named show");
+    let error = Runtime::builder().prim("oops", "Nat -> Widget", dummy).build().err().unwrap();
+    assert_eq!(error.code(), "unknown-type");
+    let error = Runtime::builder().prim("oops", "Nat ->", dummy).build().err().unwrap();
+    assert!(matches!(error.cause.as_ref(), crate::runtime::Cause::Parse(_)));
+}
+
+#[test]
+fn call_definitions_from_rust() {
+    use crate::runtime::Arg;
+    use crate::runtime::Cause;
+
+    let mut import_resolver = TextImportResolver(vec![("lib", "
+        def double : Nat -> Nat = fun n => add n n
+        def length : forall a. List a -> Nat = fun xs =>
+            match xs
+                with nil => zero
+                with cons x xs' => succ (length xs')
+        def not : Bool -> Bool = fun b =>
+            match b
+                with true => false
+                with false => true
+        def is_even : Nat -> Bool = fun n =>
+            match n
+                with zero => true
+                with succ m => not (is_even m)
+        def evens : List Nat -> List Bool = fun ns =>
+            match ns
+                with nil => nil
+                with cons n ns' => cons (is_even n) (evens ns')
+        def greet : Str -> Str = fun name => cat \"hello, \" name
+        def unit : Top = top
+    ")]);
+    let mut runtime = Runtime::new();
+    runtime.import("lib", &mut import_resolver, true).unwrap();
+
+    assert_eq!(runtime.call::<u64>("double", vec![Arg::from(21u64)]).unwrap(), 42);
+    assert_eq!(runtime.call::<u64>("length", vec![Arg::from(vec![true, false, true])]).unwrap(), 3);
+    assert_eq!(runtime.call::<Vec<bool>>("evens", vec![Arg::from(vec![0u64, 1, 2])]).unwrap(), vec![true, false, true]);
+    assert_eq!(runtime.call::<String>("greet", vec![Arg::from("world")]).unwrap(), "hello, world");
+    runtime.call::<()>("unit", vec![]).unwrap();
+
+    // The arguments and the result are typechecked before anything is evaluated.
+    let error = runtime.call::<u64>("double", vec![Arg::from(true)]).unwrap_err();
+    assert!(matches!(error.cause.as_ref(), Cause::Type(_)));
+    let error = runtime.call::<bool>("double", vec![Arg::from(1u64)]).unwrap_err();
+    assert!(matches!(error.cause.as_ref(), Cause::Type(_)));
+    let error = runtime.call::<u64>("triple", vec![Arg::from(1u64)]).unwrap_err();
+    assert_eq!(error.to_string(), "Unbound variable triple");
+}
+
+#[test]
+fn strings() {
+    use crate::context::Context;
+    use crate::parser;
+
+    let mut runtime = Runtime::new();
+    let cases = [
+        (r#""tab\there""#, r#""tab\there""#),
+        (r#""say \"hi\"\n""#, r#""say \"hi\"\n""#),
+        (r#""back\\slash \u{3bb} \'""#, r#""back\\slash λ '""#),
+        (r#"str_length "héllo""#, "5"),
+        (r#"str_eq "abc" "abc""#, "true"),
+        (r#"str_eq "abc" "abd""#, "false"),
+        (r#"str_lt "abc" "abd""#, "true"),
+        (r#"str_lt "b" "abc""#, "false"),
+        (r#"substr "hello, world" 7 5"#, r#""world""#),
+        (r#"substr "hello" 3 100"#, r#""lo""#),
+        (r#"substr "hello" 100 1"#, r#""""#),
+        (r#"chars "aλ""#, r#"cons ("a") (cons ("λ") (nil))"#),
+        (r#"split "," "a,b,,c""#, r#"cons ("a") (cons ("b") (cons ("") (cons ("c") (nil))))"#),
+        (r#"parse_nat "1234""#, "some (1234)"),
+        (r#"parse_nat "12a""#, "none"),
+        (r#"parse_nat """#, "none"),
+        (r#"show_nat 1234"#, r#""1234""#),
+        (r#"show_nat (succ (succ zero))"#, r#""2""#),
+    ];
+    for (text, expected) in cases.iter() {
+        let term = parser::parse_term(None, text).unwrap();
+        let value = runtime.eval(&term, Context::empty()).unwrap();
+        let value = runtime.force_deep(&value).unwrap();
+        assert_eq!(format!("{:?}", value), *expected, "{}", text);
+    }
+
+    for text in [r#""\q""#, r#""\u{110000}""#, r#""\u3bb""#].iter() {
+        assert!(parser::parse_term(None, text).is_err(), "{}", text);
+    }
+}
+
+#[test]
+fn show_values() {
+    use crate::context::Context;
+    use crate::parser;
+
+    let mut import_resolver = TextImportResolver(vec![("shapes", "
+        inductive Tree a
+            leaf
+            node (Tree a) a (Tree a)
+
+        coinductive Stream a
+            scons a (Stream a)
+
+        def nats_from : Nat -> Stream Nat = fun n => scons n (nats_from (succ n))
+    ")]);
+    let mut runtime = Runtime::new();
+    runtime.import("shapes", &mut import_resolver, true).unwrap();
+    runtime.show_depth = 3;
+
+    let cases = [
+        ("succ (succ 40)", "42"),
+        ("cons 1 (cons (succ zero) nil)", "[1, 1]"),
+        ("cons (cons 1 nil) (cons nil nil)", "[[1], []]"),
+        ("cons \"a\\n\" nil", r#"["a\n"]"#),
+        ("some (some true)", "some (some true)"),
+        ("node leaf (some 3) (node leaf none leaf)", "node leaf (some 3) (node leaf none leaf)"),
+        ("nats_from 5", "scons 5 (scons 6 (scons 7 ...))"),
+        ("cons (nats_from 0) nil", "[scons 0 (scons 1 (scons 2 ...))]"),
+        ("fun x => x", "<function>"),
+        ("show (cons (some 1) nil)", r#""[some 1]""#),
+    ];
+    for (text, expected) in cases.iter() {
+        let term = parser::parse_term(None, text).unwrap();
+        let value = runtime.eval(&term, Context::empty()).unwrap();
+        assert_eq!(runtime.show(value).unwrap(), *expected, "{}", text);
+    }
+}
+
+#[test]
+fn partially_applied_prims() {
+    use crate::parser;
+    use crate::stg;
+
+    let text = "
+        def inc : Nat -> Nat = add 1
+        def greet : Str -> Str = cat \"hello, \"
+        def twice : (Nat -> Nat) -> Nat -> Nat = fun f n => f (f n)
+        def result : Str = cat (show (inc 2)) (cat (greet \"you\") (show (twice (mul 3) 2)))
+    ";
+    let expected = "3hello, you18";
+
+    let module = parser::parse_module(None, text).unwrap();
+    let mut runtime = Runtime::new();
+    for definition in &module.definitions {
+        runtime.define(definition).unwrap();
+    }
+    assert_eq!(runtime.call::<String>("result", vec![]).unwrap(), expected);
+
+    let program = stg::transform::transform(&module.definitions, &runtime.inductive_typedefs);
+    let mut machine = stg::StgMachine::new(&program, None);
+    machine.primops.extend(stg::builtins::primops(&runtime.inductive_typedefs, runtime.show_depth));
+    let result = machine.lookup_global_addr("result").unwrap();
+    match machine.whnf(stg::machine::Value::Addr(result)).unwrap() {
+        stg::machine::Whnf::Lit(stg::ast::Lit::Str(s)) => assert_eq!(&*s, expected),
+        whnf => panic!("Expected a Str, but found {:?}", whnf),
     }
 }
@@ -1,6 +1,8 @@
 use std::fmt;
 use std::collections::HashMap;
 
+use num_bigint::BigUint;
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Token {
     Ident(Loc, String),
@@ -19,11 +21,15 @@ pub enum Token {
     Match(Loc),
     With(Loc),
     Import(Loc),
+    Inductive(Loc),
+    Coinductive(Loc),
+    Forall(Loc),
     Colon(Loc),
+    Dot(Loc),
     Dollar(Loc),
     As(Loc),
     Str(Loc, String),
-    Nat(Loc, usize),
+    Nat(Loc, BigUint),
 }
 
 pub struct Tokenizer {
@@ -41,7 +47,18 @@ pub struct Loc {
     pub col: usize,
 }
 
-type TokenizeErr = String;
+/// A region of source code, from `start` up to (but not including) `end`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Span {
+    pub start: Loc,
+    pub end: Loc,
+}
+
+#[derive(Debug, Clone)]
+pub struct TokenizeErr {
+    pub loc: Loc,
+    pub message: String,
+}
 
 impl Token {
     pub fn name(&self) -> &'static str {
@@ -63,7 +80,11 @@ impl Token {
             Match(_loc) => "MATCH",
             With(_loc) => "WITH",
             Import(_loc) => "IMPORT",
+            Inductive(_loc) => "INDUCTIVE",
+            Coinductive(_loc) => "COINDUCTIVE",
+            Forall(_loc) => "FORALL",
             Colon(_loc) => "COLON",
+            Dot(_loc) => "DOT",
             Dollar(_loc) => "DOLLAR",
             As(_loc) => "AS",
             Str(_loc, _val) => "STR",
@@ -90,7 +111,11 @@ impl Token {
             Match(_loc) => format!("MATCH"),
             With(_loc) => format!("WITH"),
             Import(_loc) => format!("IMPORT"),
+            Inductive(_loc) => "INDUCTIVE".to_string(),
+            Coinductive(_loc) => "COINDUCTIVE".to_string(),
+            Forall(_loc) => "FORALL".to_string(),
             Colon(_loc) => format!("COLON"),
+            Dot(_loc) => "DOT".to_string(),
             Dollar(_loc) => format!("DOLLAR"),
             As(_loc) => format!("AS"),
             Str(_loc, val) => format!("STR({})", val),
@@ -117,7 +142,11 @@ impl Token {
             Match(loc) => loc,
             With(loc) => loc,
             Import(loc) => loc,
+            Inductive(loc) => loc,
+            Coinductive(loc) => loc,
+            Forall(loc) => loc,
             Colon(loc) => loc,
+            Dot(loc) => loc,
             Dollar(loc) => loc,
             As(loc) => loc,
             Str(loc, _val) => loc,
@@ -136,10 +165,17 @@ impl Tokenizer {
     }
 
     pub fn tokenize(&mut self) -> Result<Vec<Token>, TokenizeErr> {
+        let tokens = self.tokenize_spans()?;
+        Ok(tokens.into_iter().map(|(token, _span)| token).collect())
+    }
+
+    /// Tokenizes the input, pairing each token with the span of source code it was read from.
+    pub fn tokenize_spans(&mut self) -> Result<Vec<(Token, Span)>, TokenizeErr> {
         let mut tokens = Vec::new();
 
         while let Some(token) = self.token()? {
-            tokens.push(token);
+            let span = Span::new(token.loc().clone(), self.loc.clone());
+            tokens.push((token, span));
         }
         Ok(tokens)
     }
@@ -173,9 +209,10 @@ impl Tokenizer {
         macro_rules! double_char_token {
             ($characters:literal, $tok:ident) => {
                 if chars == $characters {
+                    let loc = self.loc.clone();
                     self.consume();
                     self.consume();
-                    return Some(Token::$tok(self.loc.clone()));
+                    return Some(Token::$tok(loc));
                 }
             }
         }
@@ -192,8 +229,9 @@ impl Tokenizer {
         macro_rules! single_char_token {
             ($character:literal, $tok:ident) => {
                 if head_char == $character {
+                    let loc = self.loc.clone();
                     self.consume();
-                    return Some(Token::$tok(self.loc.clone()));
+                    return Some(Token::$tok(loc));
                 }
             }
         }
@@ -203,6 +241,7 @@ impl Tokenizer {
         single_char_token!('{', LeftCurly);
         single_char_token!('}', RightCurly);
         single_char_token!(':', Colon);
+        single_char_token!('.', Dot);
         single_char_token!('$', Dollar);
         single_char_token!('=', Equals);
 
@@ -236,7 +275,7 @@ impl Tokenizer {
                 } else if head_char.is_ascii_digit() {
                     Ok(Some(self.tokenize_nat()?))
                 } else {
-                    Err(format!("Unexpected character while parsing: {}", head_char))
+                    Err(self.error(format!("Unexpected character while parsing: {}", head_char)))
                 }
             },
             None => Ok(None),
@@ -285,7 +324,7 @@ impl Tokenizer {
             }
 
             if level != 0 {
-                Err("Mismatch curly braces.".to_string())
+                Err(self.error("Mismatch curly braces.".to_string()))
             } else {
                 Ok(Token::Hole(loc, name, Some(contents)))
             }
@@ -303,10 +342,12 @@ impl Tokenizer {
 
         while let consume_char = self.consume() {
             match consume_char {
-                None => return Err("Expected \" but found end of file. Good luck!".to_string()),
+                None => return Err(self.error("Expected \" but found end of file. Good luck!".to_string())),
                 Some(chr) => {
                     if chr == '"' {
                         break;
+                    } else if chr == '\\' {
+                        buffer.push(self.tokenize_escape()?);
                     } else {
                         buffer.push(chr);
                     }
@@ -317,14 +358,49 @@ impl Tokenizer {
         Ok(Token::Str(loc, buffer))
     }
 
+    ///
+    /// Reads the rest of an escape sequence in a string, after the backslash. These are the
+    /// same as Rust's: `\n`, `\r`, `\t`, `\0`, `\\`, `\"`, `\'`, and `\u{...}` with the
+    /// character's code point in hex.
+    ///
+    fn tokenize_escape(&mut self) -> Result<char, TokenizeErr> {
+        match self.consume() {
+            Some('n') => Ok('\n'),
+            Some('r') => Ok('\r'),
+            Some('t') => Ok('\t'),
+            Some('0') => Ok('\0'),
+            Some('\\') => Ok('\\'),
+            Some('"') => Ok('"'),
+            Some('\'') => Ok('\''),
+            Some('u') => {
+                if self.consume() != Some('{') {
+                    return Err(self.error("Expected { after \\u in a string.".to_string()));
+                }
+                let mut digits = String::new();
+                loop {
+                    match self.consume() {
+                        Some('}') => break,
+                        Some(ch) if ch.is_ascii_hexdigit() && digits.len() < 6 => digits.push(ch),
+                        _ => return Err(self.error("Expected up to six hex digits and then } after \\u{ in a string.".to_string())),
+                    }
+                }
+                u32::from_str_radix(&digits, 16).ok()
+                    .and_then(std::char::from_u32)
+                    .ok_or_else(|| self.error(format!("\\u{{{}}} is not a character.", digits)))
+            },
+            Some(ch) => Err(self.error(format!("Unknown escape sequence \\{} in a string.", ch))),
+            None => Err(self.error("Expected \" but found end of file. Good luck!".to_string())),
+        }
+    }
+
     fn tokenize_nat(&mut self) -> Result<Token, TokenizeErr> {
         let loc = self.loc.clone();
         let mut buffer = String::new();
         match self.peek() {
-            None => return Err("Expected digit but found end of file. Good luck!".to_owned()),
+            None => return Err(self.error("Expected digit but found end of file. Good luck!".to_owned())),
             Some(ch) => {
                 if !ch.is_ascii_digit() {
-                    return Err(format!("Expected digit but found {}.", ch));
+                    return Err(self.error(format!("Expected digit but found {}.", ch)));
                 }
 
                 while let Some(ch) = self.peek() {
@@ -338,7 +414,7 @@ impl Tokenizer {
                 }
             }
         }
-        let n = buffer.parse::<usize>().unwrap();
+        let n = buffer.parse::<BigUint>().unwrap();
         Ok(Token::Nat(loc, n))
     }
     fn tokenize_identifier(&mut self) -> Result<Token, TokenizeErr> {
@@ -350,6 +426,9 @@ impl Tokenizer {
             ("match".to_string(), Token::Match(self.loc.clone())),
             ("with".to_string(), Token::With(self.loc.clone())),
             ("import".to_string(), Token::Import(self.loc.clone())),
+            ("inductive".to_string(), Token::Inductive(self.loc.clone())),
+            ("coinductive".to_string(), Token::Coinductive(self.loc.clone())),
+            ("forall".to_string(), Token::Forall(self.loc.clone())),
             ("as".to_string(), Token::As(self.loc.clone())),
         ].iter().cloned().collect();
 
@@ -431,6 +510,13 @@ impl Tokenizer {
         }
     }
 
+    fn error(&self, message: String) -> TokenizeErr {
+        TokenizeErr {
+            loc: self.loc.clone(),
+            message,
+        }
+    }
+
     fn consume_comment(&mut self) {
         while let Some(consume_char) = self.consume() {
             if consume_char == '\n' {
@@ -441,7 +527,7 @@ impl Tokenizer {
 }
 
 impl Loc {
-    fn new(source: Option<String>) -> Self {
+    pub fn new(source: Option<String>) -> Self {
         Loc {
             path: source,
             line: 0,
@@ -469,6 +555,29 @@ impl fmt::Display for Loc {
     }
 }
 
+impl Span {
+    pub fn new(start: Loc, end: Loc) -> Self {
+        Span { start, end }
+    }
+
+    /// The smallest span covering both this span and another which comes after it.
+    pub fn to(&self, other: &Span) -> Span {
+        Span::new(self.start.clone(), other.end.clone())
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.start)
+    }
+}
+
+impl fmt::Display for TokenizeErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
 pub fn tokenize(source: Option<String>, input: &str) -> Result<Vec<Token>, TokenizeErr> {
     let mut tokenizer = Tokenizer::new(source, input);
     tokenizer.tokenize()
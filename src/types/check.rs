@@ -1,218 +1,1146 @@
 use std::collections::HashSet;
 use std::collections::HashMap;
+use std::fmt;
 
+use crate::ast::Def;
+use crate::ast::Term;
 use crate::ast::TermNode;
+use crate::ast::Variable;
 use crate::ast::Tag;
+use crate::ast::HoleId;
 use crate::ast::MatchArm;
+use crate::ast::MetaId;
+use crate::ast::TypeDecl;
+use crate::ast::CtorDecl;
 use crate::runtime::TypeDef;
+use crate::ast::Flavor;
 use crate::ast::Type;
 use crate::ast::TypeNode;
 use crate::context::Context;
+use crate::tokenizer::Span;
+use crate::diagnostic::Diagnostic;
 
-pub type TypeErr = String;
+#[derive(Debug, Clone)]
+pub enum TypeErr {
+    UnboundVariable { span: Option<Span>, variable: Variable },
+    /// A term of the actual type was applied to arguments.
+    NotAFunction { span: Option<Span>, actual: Type },
+    /// A function was found where a term of the expected type was needed.
+    UnexpectedFunction { span: Option<Span>, expected: Type },
+    Mismatch { span: Option<Span>, expected: Type, actual: Type },
+    /// Unifying the two types would require a type to contain itself.
+    InfiniteType { span: Option<Span>, expected: Type, actual: Type },
+    UnknownCtor { span: Option<Span>, tag: Tag },
+    /// The arms of a match don't cover each constructor of the type exactly once.
+    Coverage { span: Option<Span>, missing: Vec<Tag>, unexpected: Vec<Tag>, duplicate: Vec<Tag> },
+    PatternArity { span: Option<Span>, tag: Tag, expected: usize, actual: usize },
+    UnknownType { span: Option<Span>, name: String },
+    TypeArity { span: Option<Span>, name: String, expected: usize, actual: usize },
+    TypeAlreadyDefined { span: Option<Span>, name: String },
+    DuplicateTypeParam { span: Option<Span>, name: String, param: String },
+    DuplicateCtor { span: Option<Span>, name: String, tag: Tag },
+    CtorAlreadyDefined { span: Option<Span>, tag: Tag, defined_by: String },
+    UnboundTypeVariable { span: Option<Span>, name: String, tag: Tag },
+    /// An inductive type occurs to the left of an arrow in one of its own constructors.
+    NegativeOccurrence { span: Option<Span>, name: String, tag: Tag },
+    /// A recursive call which isn't on a structurally smaller argument.
+    NonTerminating { span: Option<Span>, name: String, call: Term, consumes_codata: bool },
+    /// A corecursive call which isn't guarded by a coinductive constructor.
+    NonProductive { span: Option<Span>, name: String, call: Term },
+}
 
-pub fn infer_type(t: &TermNode, ctx: Context<Type>, inductive_typedefs: &HashMap<String, TypeDef>) -> Result<Type, TypeErr> {
-    match t {
-        TermNode::Var(v) => {
-            let x = &v.name;
-            let k = v.layer;
+impl TypeErr {
+    pub fn span(&self) -> Option<&Span> {
+        use TypeErr::*;
+        match self {
+            UnboundVariable { span, .. } => span.as_ref(),
+            NotAFunction { span, .. } => span.as_ref(),
+            UnexpectedFunction { span, .. } => span.as_ref(),
+            Mismatch { span, .. } => span.as_ref(),
+            InfiniteType { span, .. } => span.as_ref(),
+            UnknownCtor { span, .. } => span.as_ref(),
+            Coverage { span, .. } => span.as_ref(),
+            PatternArity { span, .. } => span.as_ref(),
+            UnknownType { span, .. } => span.as_ref(),
+            TypeArity { span, .. } => span.as_ref(),
+            TypeAlreadyDefined { span, .. } => span.as_ref(),
+            DuplicateTypeParam { span, .. } => span.as_ref(),
+            DuplicateCtor { span, .. } => span.as_ref(),
+            CtorAlreadyDefined { span, .. } => span.as_ref(),
+            UnboundTypeVariable { span, .. } => span.as_ref(),
+            NegativeOccurrence { span, .. } => span.as_ref(),
+            NonTerminating { span, .. } => span.as_ref(),
+            NonProductive { span, .. } => span.as_ref(),
+        }
+    }
+
+    /// Gives the error a location, unless it already has one.
+    fn located(mut self: Box<Self>, location: Option<&Span>) -> Box<TypeErr> {
+        use TypeErr::*;
+        let span = match self.as_mut() {
+            UnboundVariable { span, .. } => span,
+            NotAFunction { span, .. } => span,
+            UnexpectedFunction { span, .. } => span,
+            Mismatch { span, .. } => span,
+            InfiniteType { span, .. } => span,
+            UnknownCtor { span, .. } => span,
+            Coverage { span, .. } => span,
+            PatternArity { span, .. } => span,
+            UnknownType { span, .. } => span,
+            TypeArity { span, .. } => span,
+            TypeAlreadyDefined { span, .. } => span,
+            DuplicateTypeParam { span, .. } => span,
+            DuplicateCtor { span, .. } => span,
+            CtorAlreadyDefined { span, .. } => span,
+            UnboundTypeVariable { span, .. } => span,
+            NegativeOccurrence { span, .. } => span,
+            NonTerminating { span, .. } => span,
+            NonProductive { span, .. } => span,
+        };
+        if span.is_none() {
+            *span = location.cloned();
+        }
+        self
+    }
 
-            match ctx.lookup(x, k) {
-                None => Err(format!("Variable {} not found in context", x)),
-                Some(typ) => Ok(typ),
+    /// Secondary information about the error, such as which tags a match is missing.
+    pub fn notes(&self) -> Vec<String> {
+        let mut notes = vec![];
+        if let TypeErr::Coverage { missing, unexpected, duplicate, .. } = self {
+            if !missing.is_empty() {
+                notes.push(format!("missing tags: {}", missing.join(", ")));
             }
-        },
-        TermNode::Lam(_y, _body) => Err("Can't infer type of functions.".to_string()),
-        TermNode::App(f, vs) => {
-            let mut result = infer_type(&f, ctx.clone(), inductive_typedefs)?;
+            if !unexpected.is_empty() {
+                notes.push(format!("unexpected tags: {}", unexpected.join(", ")));
+            }
+            if !duplicate.is_empty() {
+                notes.push(format!("duplicate tags: {}", duplicate.join(", ")));
+            }
+        }
+        notes
+    }
 
-            for v in vs.iter() {
-                match result.as_ref() {
-                    TypeNode::Atom(_) => return Err("Expected function type.".to_string()),
-                    TypeNode::Arrow(dom, cod) => {
-                        check_type(&v, ctx.clone(), inductive_typedefs, dom.clone())?;
-                        result = cod.clone();
-                    },
-                    TypeNode::Forall(_, _) => return Err("Can't apply to a forall.".to_string()),
+    /// A short name for the kind of error, used to identify it in machine-readable output.
+    pub fn code(&self) -> &'static str {
+        use TypeErr::*;
+        match self {
+            UnboundVariable { .. } => "unbound-variable",
+            NotAFunction { .. } => "not-a-function",
+            UnexpectedFunction { .. } => "unexpected-function",
+            Mismatch { .. } => "mismatch",
+            InfiniteType { .. } => "infinite-type",
+            UnknownCtor { .. } => "unknown-ctor",
+            Coverage { .. } => "coverage",
+            PatternArity { .. } => "pattern-arity",
+            UnknownType { .. } => "unknown-type",
+            TypeArity { .. } => "type-arity",
+            TypeAlreadyDefined { .. } => "type-already-defined",
+            DuplicateTypeParam { .. } => "duplicate-type-param",
+            DuplicateCtor { .. } => "duplicate-ctor",
+            CtorAlreadyDefined { .. } => "ctor-already-defined",
+            UnboundTypeVariable { .. } => "unbound-type-variable",
+            NegativeOccurrence { .. } => "negative-occurrence",
+            NonTerminating { .. } => "non-terminating",
+            NonProductive { .. } => "non-productive",
+        }
+    }
+
+    pub fn diagnostic(&self) -> Diagnostic {
+        Diagnostic::new(self.code(), self.to_string(), self.span().cloned()).with_notes(self.notes())
+    }
+}
+
+impl fmt::Display for TypeErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use TypeErr::*;
+        match self {
+            UnboundVariable { span: _, variable } => write!(f, "Variable {} not found in context", variable),
+            NotAFunction { span: _, actual } => write!(f, "Expected a function, but found a term of type {}", **actual),
+            UnexpectedFunction { span: _, expected } => write!(f, "Expected {} but found a function", **expected),
+            Mismatch { span: _, expected, actual } => write!(f, "Expected {} but found {}", **expected, **actual),
+            InfiniteType { span: _, expected, actual } => write!(f, "Cannot construct the infinite type {} = {}", **expected, **actual),
+            UnknownCtor { span: _, tag } => write!(f, "Unknown constructor {}", tag),
+            Coverage { .. } => write!(f, "Match statement must have exactly one arm for each constructor"),
+            PatternArity { span: _, tag, expected, actual } => {
+                write!(f, "Constructor {} takes {} arguments, but the pattern binds {}", tag, expected, actual)
+            },
+            UnknownType { span: _, name } => write!(f, "Unknown type {}", name),
+            TypeArity { span: _, name, expected, actual } => {
+                write!(f, "Type {} expects {} type arguments but was given {}", name, expected, actual)
+            },
+            TypeAlreadyDefined { span: _, name } => write!(f, "Type {} is already defined", name),
+            DuplicateTypeParam { span: _, name, param } => write!(f, "Duplicate type parameter {} in type {}", param, name),
+            DuplicateCtor { span: _, name, tag } => write!(f, "Duplicate constructor {} in type {}", tag, name),
+            CtorAlreadyDefined { span: _, tag, defined_by } => write!(f, "Constructor {} is already defined by type {}", tag, defined_by),
+            UnboundTypeVariable { span: _, name, tag } => write!(f, "Unbound type variable {} in the constructor {}", name, tag),
+            NegativeOccurrence { span: _, name, tag } => write!(f, "Type {} occurs negatively in the constructor {}", name, tag),
+            NonTerminating { span: _, name, call, consumes_codata } => {
+                if *consumes_codata {
+                    write!(f, "Definition {} might not terminate: the recursive call `{}` consumes coinductive data, which may be infinite", name, **call)
+                } else {
+                    write!(f, "Definition {} might not terminate: the recursive call `{}` is not made on a structurally smaller argument", name, **call)
                 }
-            }
-            Ok(result)
-        },
-        TermNode::Let(x, v, body) => {
-            let x_typ = infer_type(&v, ctx.clone(), inductive_typedefs)?;
-            infer_type(&body, ctx.extend(x, x_typ), inductive_typedefs)
-        },
-        TermNode::Match(_t, _match_arms) => {
-            Err("Can't infer type of match statements. (Yet?)".to_string())
-        },
-        TermNode::Hole(_hole_info) => Err("Can't infer type of a hole.".to_string()),
-        TermNode::StrLit(_contents) => { Ok(TypeNode::Atom("Str".to_string()).into())},
-        TermNode::As(term, typ) => {
-            check_type(&term, ctx, inductive_typedefs, typ.clone())?;
-            Ok(typ.clone())
-        },
+            },
+            NonProductive { span: _, name, call } => {
+                write!(f, "Definition {} might not be productive: the corecursive call `{}` is not guarded by a coinductive constructor", name, **call)
+            },
+        }
     }
 }
 
-pub fn check_type(t: &TermNode, ctx: Context<Type>, inductive_typedefs: &HashMap<String, TypeDef>, typ: Type) -> Result<(), TypeErr> {
-    match t {
-        TermNode::Var(v) => {
-            let x = &v.name;
-            let k = v.layer;
-
-            match ctx.lookup(x, k) {
-                Some(x_typ) => {
-                    if x_typ == typ {
-                        Ok(())
-                    } else {
-                        Err(format!("Term {} does not have type {:?} in context: {:?}", x, &typ, &ctx))
+/// The type a hole is expected to have, together with the typing context in scope
+/// at the point where the hole appears.
+#[derive(Debug, Clone)]
+pub struct HoleTyping {
+    pub typ: Type,
+    pub ctx: Context<Type>,
+}
+
+pub type HoleTypings = HashMap<HoleId, HoleTyping>;
+
+///
+/// The TypeChecker holds the state needed while checking a single term.
+///
+/// Polymorphic types are instantiated with fresh metavariables wherever a variable is used.
+/// Metavariables are solved by unification as the term is checked, and the solutions are
+/// recorded in `metas`.
+///
+struct TypeChecker<'a> {
+    inductive_typedefs: &'a HashMap<String, TypeDef>,
+    metas: Vec<Option<Type>>,
+    holes: Vec<(HoleId, HoleTyping)>,
+}
+
+pub fn infer_type(
+    t: &Term,
+    ctx: Context<Type>,
+    inductive_typedefs: &HashMap<String, TypeDef>,
+    hole_typings: &mut HoleTypings,
+) -> Result<Type, Box<TypeErr>> {
+    let mut checker = TypeChecker::new(inductive_typedefs);
+    let typ = checker.infer_type(t, ctx)?;
+    let typ = checker.generalize(&typ, &Context::empty());
+    checker.finish(hole_typings);
+    Ok(typ)
+}
+
+pub fn check_type(
+    t: &Term,
+    ctx: Context<Type>,
+    inductive_typedefs: &HashMap<String, TypeDef>,
+    hole_typings: &mut HoleTypings,
+    typ: Type,
+) -> Result<(), Box<TypeErr>> {
+    let mut checker = TypeChecker::new(inductive_typedefs);
+    checker.check_type(t, ctx, typ)?;
+    checker.finish(hole_typings);
+    Ok(())
+}
+
+impl<'a> TypeChecker<'a> {
+    fn new(inductive_typedefs: &'a HashMap<String, TypeDef>) -> Self {
+        TypeChecker {
+            inductive_typedefs,
+            metas: Vec::new(),
+            holes: Vec::new(),
+        }
+    }
+
+    /// Records the types of the holes seen while checking, with all solved metavariables filled in.
+    fn finish(&self, hole_typings: &mut HoleTypings) {
+        for (hole_id, hole_typing) in self.holes.iter() {
+            let zonked_bindings: Vec<(String, Type)> = hole_typing.ctx
+                .bindings()
+                .into_iter()
+                .map(|(name, typ)| (name, self.zonk(&typ)))
+                .collect();
+
+            hole_typings.insert(*hole_id, HoleTyping {
+                typ: self.zonk(&hole_typing.typ),
+                ctx: Context::empty().extend_many(&zonked_bindings),
+            });
+        }
+    }
+
+    fn infer_type(&mut self, t: &Term, ctx: Context<Type>) -> Result<Type, Box<TypeErr>> {
+        match t.as_ref() {
+            TermNode::Var(v) => {
+                let x = &v.name;
+                let k = v.layer;
+
+                match ctx.lookup(x, k) {
+                    None => Err(Box::new(TypeErr::UnboundVariable { span: t.span().cloned(), variable: v.clone() })),
+                    Some(typ) => Ok(self.instantiate(&typ)),
+                }
+            },
+            TermNode::Lam(x, body) => {
+                let dom = self.fresh_meta();
+                let cod = self.infer_type(body, ctx.extend(x, dom.clone()))?;
+                Ok(TypeNode::Arrow(dom, cod).into())
+            },
+            TermNode::App(f, vs) => {
+                let mut result = self.infer_type(f, ctx.clone())?;
+
+                for v in vs.iter() {
+                    match self.resolve(&result).as_ref() {
+                        TypeNode::Arrow(dom, cod) => {
+                            self.check_type(v, ctx.clone(), dom.clone())?;
+                            result = cod.clone();
+                        },
+                        TypeNode::Meta(_) => {
+                            let dom = self.fresh_meta();
+                            let cod = self.fresh_meta();
+                            self.unify(&result, &TypeNode::Arrow(dom.clone(), cod.clone()).into())
+                                .map_err(|e| e.located(t.span()))?;
+                            self.check_type(v, ctx.clone(), dom)?;
+                            result = cod;
+                        },
+                        _ => return Err(Box::new(TypeErr::NotAFunction { span: f.span().cloned(), actual: self.zonk(&result) })),
                     }
+                }
+                Ok(result)
+            },
+            TermNode::Let(x, v, body) => {
+                let x_typ = self.infer_type(v, ctx.clone())?;
+                let x_typ = self.generalize(&x_typ, &ctx);
+                self.infer_type(body, ctx.extend(x, x_typ))
+            },
+            TermNode::Match(discriminee, match_arms) => {
+                let typ = self.fresh_meta();
+                self.check_type_match(t, discriminee, match_arms, ctx, typ.clone())?;
+                Ok(typ)
+            },
+            TermNode::Hole(hole_info) => {
+                let typ = self.fresh_meta();
+                self.holes.push((hole_info.hole_id, HoleTyping { typ: typ.clone(), ctx }));
+                Ok(typ)
+            },
+            TermNode::StrLit(_contents) => { Ok(TypeNode::Atom("Str".to_string()).into())},
+            TermNode::NatLit(_n) => { Ok(TypeNode::Atom("Nat".to_string()).into())},
+            TermNode::As(term, typ) => {
+                check_type_wellformed(typ, self.inductive_typedefs)?;
+                self.check_type(term, ctx, typ.clone())?;
+                Ok(typ.clone())
+            },
+        }
+    }
+
+    fn check_type(&mut self, t: &Term, ctx: Context<Type>, typ: Type) -> Result<(), Box<TypeErr>> {
+        // A term has a polymorphic type when it has that type for any choice of the
+        // type variables. So we check it with the type variables left abstract.
+        if let TypeNode::Forall(_name, body) = typ.as_ref() {
+            return self.check_type(t, ctx, body.clone());
+        }
+
+        match t.as_ref() {
+            TermNode::Var(v) => {
+                let x = &v.name;
+                let k = v.layer;
+
+                match ctx.lookup(x, k) {
+                    Some(x_typ) => {
+                        let x_typ = self.instantiate(&x_typ);
+                        self.unify(&typ, &x_typ).map_err(|e| e.located(t.span()))
+                    },
+                    None => Err(Box::new(TypeErr::UnboundVariable { span: t.span().cloned(), variable: v.clone() })),
+                }
+            },
+            TermNode::Lam(x, body) => {
+                match self.resolve(&typ).as_ref() {
+                    TypeNode::Arrow(dom, cod) => self.check_type(body, ctx.extend(x, dom.clone()), cod.clone()),
+                    TypeNode::Meta(_) => {
+                        let dom = self.fresh_meta();
+                        let cod = self.fresh_meta();
+                        self.unify(&typ, &TypeNode::Arrow(dom.clone(), cod.clone()).into())
+                            .map_err(|e| e.located(t.span()))?;
+                        self.check_type(body, ctx.extend(x, dom), cod)
+                    },
+                    _ => Err(Box::new(TypeErr::UnexpectedFunction { span: t.span().cloned(), expected: self.zonk(&typ) })),
+                }
+            },
+            TermNode::App(_f, _vs) => {
+                let inferred_typ = self.infer_type(t, ctx)?;
+                self.unify(&typ, &inferred_typ).map_err(|e| e.located(t.span()))
+            },
+            TermNode::StrLit(_contents) => {
+                let str_typ = TypeNode::Atom("Str".to_string()).into();
+                self.unify(&typ, &str_typ).map_err(|e| e.located(t.span()))
+            },
+            TermNode::NatLit(_n) => {
+                let nat_typ = TypeNode::Atom("Nat".to_string()).into();
+                self.unify(&typ, &nat_typ).map_err(|e| e.located(t.span()))
+            },
+            TermNode::Let(x, v, body) => {
+                let x_typ = self.infer_type(v, ctx.clone())?;
+                let x_typ = self.generalize(&x_typ, &ctx);
+                self.check_type(body, ctx.extend(x, x_typ), typ)
+            },
+            TermNode::Match(discriminee, match_arms) => self.check_type_match(t, discriminee, match_arms, ctx, typ),
+            TermNode::Hole(hole_info) => {
+                self.holes.push((hole_info.hole_id, HoleTyping { typ, ctx }));
+                Ok(())
+            },
+            TermNode::As(term, as_typ) => {
+                check_type_wellformed(as_typ, self.inductive_typedefs)?;
+                self.unify(&typ, as_typ).map_err(|e| e.located(t.span()))?;
+                self.check_type(term, ctx, typ)
+            },
+        }
+    }
+
+    fn check_type_match(
+        &mut self,
+        t: &Term,
+        discriminee: &Term,
+        match_arms: &[MatchArm],
+        ctx: Context<Type>,
+        typ: Type,
+    ) -> Result<(), Box<TypeErr>> {
+        let match_tags: Vec<Tag> = match_arms.iter().map(|MatchArm(pat, _arm_term, _span)| pat[0].to_string()).collect();
+        // TODO: handle bottom type
+        if let Some(MatchArm(first_pat, _first_arm_term, first_span)) = match_arms.first() {
+            match lookup_typedef_by_ctor_tag(&first_pat[0], self.inductive_typedefs) {
+                None => Err(Box::new(TypeErr::UnknownCtor { span: Some(first_span.clone()), tag: first_pat[0].to_string() })),
+                Some(inductive_typedef) => {
+                    let typedef_tags = inductive_typedef.ctor_tags();
+                    analyze_coverage(&typedef_tags, &match_tags).map_err(|e| e.located(t.span()))?;
+
+                    let type_args: Vec<Type> = inductive_typedef.params.iter().map(|_| self.fresh_meta()).collect();
+                    self.check_type(
+                        discriminee,
+                        ctx.clone(),
+                        inductive_typedef.applied_to(type_args.clone()),
+                    )?;
+                    for match_arm in match_arms {
+                        self.check_type_match_arm(match_arm, inductive_typedef, &type_args, &ctx, &typ)?;
+                    }
+                    Ok(())
                 },
-                None => Err(format!("{} does not appear in context.", x)),
-            }
-        },
-        TermNode::Lam(x, body) => {
-            match typ.as_ref() {
-                TypeNode::Atom(atom) => Err(format!("Functions need function types, but we got {}", atom)),
-                TypeNode::Arrow(dom, cod) => check_type(&body, ctx.extend(x, dom.clone()), inductive_typedefs, cod.clone()),
-                TypeNode::Forall(_name, _cod) => Err(format!("Functions need function types, but we got a forall")),
             }
-        },
-        TermNode::App(_f, _vs) => {
-            let inferred_typ = infer_type(&t, ctx, inductive_typedefs)?;
-            if &inferred_typ == &typ {
-                Ok(())
-            } else {
-                Err(format!("Type mismatch during application: {:?} vs {:?}", &inferred_typ, &typ))
+        } else {
+            // NOTE: There is an assumption here that Bot is the only empty type!
+            self.check_type(
+                discriminee,
+                ctx,
+                TypeNode::Atom("Bot".to_string()).into(),
+            )
+        }
+    }
+
+    fn check_type_match_arm(
+        &mut self,
+        match_arm: &MatchArm,
+        inductive_typedef: &TypeDef,
+        type_args: &[Type],
+        ctx: &Context<Type>,
+        typ: &Type,
+    ) -> Result<(), Box<TypeErr>> {
+        let MatchArm(pat, body, span) = match_arm;
+        let ctor_tag = pat[0].to_string();
+        let mut ctor_typ = instantiate_with(&inductive_typedef.ctor_types[&ctor_tag], type_args);
+
+        let pattern_names: Vec<String> = pat[1..].to_vec();
+        let mut pattern_types: Vec<Type> = Vec::new();
+
+        while let TypeNode::Arrow(dom, cod) = ctor_typ.clone().as_ref() {
+            pattern_types.push(dom.clone());
+            ctor_typ = cod.clone();
+        }
+
+        if pattern_names.len() != pattern_types.len() {
+            Err(Box::new(TypeErr::PatternArity {
+                span: Some(span.clone()),
+                tag: ctor_tag,
+                expected: pattern_types.len(),
+                actual: pattern_names.len(),
+            }))
+        } else {
+            let zipped: Vec<(String, Type)> = pattern_names.into_iter().zip(pattern_types).collect();
+            let extended_ctx = ctx.extend_many(&zipped);
+            self.check_type(body, extended_ctx, typ.clone())
+        }
+    }
+
+    fn fresh_meta(&mut self) -> Type {
+        let meta_id: MetaId = self.metas.len();
+        self.metas.push(None);
+        TypeNode::Meta(meta_id).into()
+    }
+
+    /// Replaces the variables bound by the outermost foralls of a type with fresh metavariables.
+    fn instantiate(&mut self, typ: &Type) -> Type {
+        let mut typ = typ.clone();
+        while let TypeNode::Forall(name, body) = typ.clone().as_ref() {
+            let meta = self.fresh_meta();
+            typ = body.subst(name, &meta);
+        }
+        typ
+    }
+
+    ///
+    /// Abstracts over the unsolved metavariables of a type which do not appear in the context.
+    /// Each such metavariable is solved with a fresh type variable, which is then bound by a forall.
+    /// This is how let-bound variables get polymorphic types, and how the REPL finds principal types.
+    ///
+    fn generalize(&mut self, typ: &Type, ctx: &Context<Type>) -> Type {
+        let typ = self.zonk(typ);
+
+        let mut ctx_metas: Vec<MetaId> = Vec::new();
+        for (_name, ctx_typ) in ctx.bindings() {
+            self.unsolved_metas(&ctx_typ, &mut ctx_metas);
+        }
+
+        let mut typ_metas: Vec<MetaId> = Vec::new();
+        self.unsolved_metas(&typ, &mut typ_metas);
+        typ_metas.retain(|meta_id| !ctx_metas.contains(meta_id));
+
+        let taken_names = typ.free_type_vars();
+        let mut fresh_names = (0..)
+            .map(type_variable_name)
+            .filter(|name| !taken_names.contains(name));
+
+        let mut names: Vec<String> = Vec::new();
+        for meta_id in typ_metas {
+            let name = fresh_names.next().unwrap();
+            self.metas[meta_id] = Some(TypeNode::Var(name.clone()).into());
+            names.push(name);
+        }
+
+        let mut result = self.zonk(&typ);
+        for name in names.into_iter().rev() {
+            result = TypeNode::Forall(name, result).into();
+        }
+        result
+    }
+
+    /// Collects the unsolved metavariables of a type, in the order they first appear.
+    fn unsolved_metas(&self, typ: &Type, metas: &mut Vec<MetaId>) {
+        match self.resolve(typ).as_ref() {
+            TypeNode::Atom(_) | TypeNode::Var(_) => (),
+            TypeNode::Meta(meta_id) => {
+                if !metas.contains(meta_id) {
+                    metas.push(*meta_id);
+                }
+            },
+            TypeNode::App(_name, args) => {
+                for arg in args {
+                    self.unsolved_metas(arg, metas);
+                }
+            },
+            TypeNode::Arrow(dom, cod) => {
+                self.unsolved_metas(dom, metas);
+                self.unsolved_metas(cod, metas);
+            },
+            TypeNode::Forall(_name, body) => self.unsolved_metas(body, metas),
+        }
+    }
+
+    /// Follows solved metavariables until reaching a type which is not a solved metavariable.
+    fn resolve(&self, typ: &Type) -> Type {
+        let mut typ = typ.clone();
+        while let TypeNode::Meta(meta_id) = typ.as_ref() {
+            match &self.metas[*meta_id] {
+                Some(solution) => typ = solution.clone(),
+                None => break,
             }
-        },
-        TermNode::StrLit(_contents) => {
-            if typ == TypeNode::Atom("Str".to_string()).into() {
+        }
+        typ
+    }
+
+    /// Replaces all of the solved metavariables in a type with their solutions.
+    fn zonk(&self, typ: &Type) -> Type {
+        let typ = self.resolve(typ);
+        match typ.as_ref() {
+            TypeNode::Atom(_) | TypeNode::Var(_) | TypeNode::Meta(_) => typ.clone(),
+            TypeNode::App(name, args) => TypeNode::App(name.clone(), args.iter().map(|arg| self.zonk(arg)).collect()).into(),
+            TypeNode::Arrow(dom, cod) => TypeNode::Arrow(self.zonk(dom), self.zonk(cod)).into(),
+            TypeNode::Forall(name, body) => TypeNode::Forall(name.clone(), self.zonk(body)).into(),
+        }
+    }
+
+    fn occurs(&self, meta_id: MetaId, typ: &Type) -> bool {
+        match self.resolve(typ).as_ref() {
+            TypeNode::Atom(_) | TypeNode::Var(_) => false,
+            TypeNode::Meta(other_meta_id) => meta_id == *other_meta_id,
+            TypeNode::App(_name, args) => args.iter().any(|arg| self.occurs(meta_id, arg)),
+            TypeNode::Arrow(dom, cod) => self.occurs(meta_id, dom) || self.occurs(meta_id, cod),
+            TypeNode::Forall(_name, body) => self.occurs(meta_id, body),
+        }
+    }
+
+    /// Makes two types equal by solving metavariables, or fails if they can't be made equal.
+    /// The error message treats `expected` as the type which was expected.
+    fn unify(&mut self, expected: &Type, actual: &Type) -> Result<(), Box<TypeErr>> {
+        let expected = self.resolve(expected);
+        let actual = self.resolve(actual);
+
+        match (expected.as_ref(), actual.as_ref()) {
+            (TypeNode::Meta(m), TypeNode::Meta(n)) if m == n => Ok(()),
+            (TypeNode::Meta(m), _) => self.solve(*m, &actual),
+            (_, TypeNode::Meta(n)) => self.solve(*n, &expected),
+            (TypeNode::Atom(a), TypeNode::Atom(b)) if a == b => Ok(()),
+            (TypeNode::Var(a), TypeNode::Var(b)) if a == b => Ok(()),
+            (TypeNode::App(f, xs), TypeNode::App(g, ys)) if f == g && xs.len() == ys.len() => {
+                for (x, y) in xs.iter().zip(ys.iter()) {
+                    self.unify(x, y).map_err(|_e| self.mismatch(&expected, &actual))?;
+                }
                 Ok(())
-            } else {
-                Err(format!("Type of string literal can't be {:?}", &typ))
-            }
-        },
-        TermNode::Let(x, v, body) => {
-            let x_typ = infer_type(&v, ctx.clone(), inductive_typedefs)?;
-            check_type(&body, ctx.extend(&x, x_typ), inductive_typedefs, typ)
-        },
-        TermNode::Match(t, match_arms) => check_type_match(&t, &match_arms, ctx, inductive_typedefs, typ),
-        TermNode::Hole(_hole_info) => Ok(()),
-        TermNode::As(term, as_typ) => {
-            if &typ == as_typ {
-                check_type(&term, ctx, inductive_typedefs, typ)
-            } else {
-                Err(format!("Type mismatch during ascription: {:?} vs {:?}", &as_typ, &typ))
-            }
-        },
+            },
+            (TypeNode::Arrow(dom1, cod1), TypeNode::Arrow(dom2, cod2)) => {
+                self.unify(dom1, dom2).map_err(|_e| self.mismatch(&expected, &actual))?;
+                self.unify(cod1, cod2).map_err(|_e| self.mismatch(&expected, &actual))
+            },
+            (TypeNode::Forall(a, body1), TypeNode::Forall(b, body2)) => {
+                let body2 = body2.subst(b, &TypeNode::Var(a.clone()).into());
+                self.unify(body1, &body2).map_err(|_e| self.mismatch(&expected, &actual))
+            },
+            _ => Err(self.mismatch(&expected, &actual)),
+        }
+    }
+
+    fn solve(&mut self, meta_id: MetaId, typ: &Type) -> Result<(), Box<TypeErr>> {
+        if self.occurs(meta_id, typ) {
+            Err(Box::new(TypeErr::InfiniteType {
+                span: None,
+                expected: TypeNode::Meta(meta_id).into(),
+                actual: self.zonk(typ),
+            }))
+        } else {
+            self.metas[meta_id] = Some(typ.clone());
+            Ok(())
+        }
+    }
+
+    fn mismatch(&self, expected: &Type, actual: &Type) -> Box<TypeErr> {
+        Box::new(TypeErr::Mismatch {
+            span: None,
+            expected: self.zonk(expected),
+            actual: self.zonk(actual),
+        })
     }
 }
 
-pub fn check_type_match(
-    discriminee: &TermNode,
-    match_arms: &[MatchArm],
-    ctx: Context<Type>,
+/// The names given to generalized type variables: a, b, ..., z, a1, b1, ...
+fn type_variable_name(i: usize) -> String {
+    let letter = (b'a' + (i % 26) as u8) as char;
+    if i < 26 {
+        letter.to_string()
+    } else {
+        format!("{}{}", letter, i / 26)
+    }
+}
+
+/// Replaces the variables bound by the outermost foralls of a type with the given types.
+fn instantiate_with(typ: &Type, args: &[Type]) -> Type {
+    let mut typ = typ.clone();
+    for arg in args {
+        match typ.clone().as_ref() {
+            TypeNode::Forall(name, body) => typ = body.subst(name, arg),
+            _ => panic!("Type {} has fewer type parameters than expected", *typ),
+        }
+    }
+    typ
+}
+
+///
+/// Checks that each type mentioned is either Str or an inductive type, and that
+/// inductive types are given exactly as many type arguments as they have parameters.
+///
+pub fn check_type_wellformed(typ: &Type, inductive_typedefs: &HashMap<String, TypeDef>) -> Result<(), Box<TypeErr>> {
+    check_type_wellformed_with(typ, inductive_typedefs, None)
+}
+
+/// Like check_type_wellformed, but also allows a single type which is in the process
+/// of being declared, given by its name and number of parameters.
+fn check_type_wellformed_with(
+    typ: &Type,
     inductive_typedefs: &HashMap<String, TypeDef>,
-    typ: Type,
-) -> Result<(), TypeErr> {
-    let match_tags: Vec<Tag> = match_arms.iter().map(|MatchArm(pat, _arm_term)| pat[0].to_string()).collect();
-    // TODO: handle bottom type
-    if let Some(first_ctor_tag) = &match_tags.iter().cloned().collect::<Vec<Tag>>().get(0) {
-        match lookup_typedef_by_ctor_tag(first_ctor_tag, inductive_typedefs) {
-            None => Err(format!("Unknown ctor {:?}", first_ctor_tag)),
-            Some(inductive_typedef) => {
-                let typedef_tags = inductive_typedef.ctor_tags();
-                analyze_coverage(&typedef_tags, &match_tags)?;
-                check_type(
-                    discriminee,
-                    ctx.clone(),
-                    inductive_typedefs,
-                    TypeNode::Atom(inductive_typedef.name.to_string()).into(),
-                )?;
-                for match_arm in match_arms {
-                    check_type_match_arm(match_arm, inductive_typedef, &ctx, inductive_typedefs, &typ)?;
+    declaring: Option<(&str, usize)>,
+) -> Result<(), Box<TypeErr>> {
+    let arity = |name: &str| -> Result<usize, Box<TypeErr>> {
+        let span = typ.span().cloned();
+        match declaring {
+            Some((declared_name, declared_arity)) if declared_name == name => Ok(declared_arity),
+            _ => {
+                match primitive_type_arity(name) {
+                    Some(arity) => Ok(arity),
+                    None => match inductive_typedefs.get(name) {
+                        Some(typedef) => Ok(typedef.params.len()),
+                        None => Err(Box::new(TypeErr::UnknownType { span, name: name.to_string() })),
+                    },
                 }
-                Ok(())
             },
         }
-    } else {
-        // NOTE: There is an assumption here that Bot is the only empty type!
-        check_type(
-            discriminee,
-            ctx.clone(),
-            inductive_typedefs,
-            TypeNode::Atom("Bot".to_string()).into(),
-        )
+    };
+
+    match typ.as_ref() {
+        TypeNode::Atom(name) => {
+            let expected_arity = arity(name)?;
+            if expected_arity == 0 {
+                Ok(())
+            } else {
+                Err(Box::new(TypeErr::TypeArity { span: typ.span().cloned(), name: name.to_string(), expected: expected_arity, actual: 0 }))
+            }
+        },
+        TypeNode::App(name, args) => {
+            let expected_arity = arity(name)?;
+            if expected_arity != args.len() {
+                return Err(Box::new(TypeErr::TypeArity {
+                    span: typ.span().cloned(),
+                    name: name.to_string(),
+                    expected: expected_arity,
+                    actual: args.len(),
+                }));
+            }
+
+            for arg in args {
+                check_type_wellformed_with(arg, inductive_typedefs, declaring)?;
+            }
+            Ok(())
+        },
+        TypeNode::Var(_) | TypeNode::Meta(_) => Ok(()),
+        TypeNode::Arrow(dom, cod) => {
+            check_type_wellformed_with(dom, inductive_typedefs, declaring)?;
+            check_type_wellformed_with(cod, inductive_typedefs, declaring)
+        },
+        TypeNode::Forall(_name, body) => check_type_wellformed_with(body, inductive_typedefs, declaring),
     }
 }
 
-fn analyze_coverage(typedef_tags: &Vec<Tag>, match_tags: &Vec<Tag>) -> Result<(), TypeErr> {
+fn analyze_coverage(typedef_tags: &[Tag], match_tags: &[Tag]) -> Result<(), Box<TypeErr>> {
     let match_tags_set: HashSet<_> = match_tags.iter().cloned().collect();
     let typedef_tags_set: HashSet<_> = typedef_tags.iter().cloned().collect();
 
-    let unexpected_tags: HashSet<_> = match_tags_set.difference(&typedef_tags_set).collect();
-    let missing_tags: HashSet<_> = typedef_tags_set.difference(&match_tags_set).collect();
+    let mut unexpected_tags: Vec<Tag> = match_tags_set.difference(&typedef_tags_set).cloned().collect();
+    let mut missing_tags: Vec<Tag> = typedef_tags_set.difference(&match_tags_set).cloned().collect();
+    unexpected_tags.sort();
+    missing_tags.sort();
 
-    let mut sorted_match_tags = match_tags.clone();
+    let mut sorted_match_tags = match_tags.to_vec();
     sorted_match_tags.sort();
-    let mut duplicate_tags: HashSet<Tag> = HashSet::new();
+    let mut duplicate_tags: Vec<Tag> = Vec::new();
     let match_tag_with_next: Vec<_> = sorted_match_tags
         .iter()
         .zip(sorted_match_tags[1..].iter())
         .collect();
 
     for (cur, next) in match_tag_with_next.into_iter() {
-        if cur == next {
-            duplicate_tags.insert(cur.to_string());
+        if cur == next && !duplicate_tags.contains(cur) {
+            duplicate_tags.push(cur.to_string());
         }
     }
 
-    if !unexpected_tags.is_empty() {
-        Err(format!("Found unexpected tags: {:?}", unexpected_tags))
-    } else if !missing_tags.is_empty() {
-        Err(format!("Expected missing tags: {:?}", missing_tags))
-    } else if !duplicate_tags.is_empty() {
-        Err(format!("Duplicate tags: {:?}", duplicate_tags))
-    } else {
+    if unexpected_tags.is_empty() && missing_tags.is_empty() && duplicate_tags.is_empty() {
         Ok(())
+    } else {
+        Err(Box::new(TypeErr::Coverage {
+            span: None,
+            missing: missing_tags,
+            unexpected: unexpected_tags,
+            duplicate: duplicate_tags,
+        }))
     }
 }
 
-fn check_type_match_arm(
-    match_arm: &MatchArm,
-    inductive_typedef: &TypeDef,
-    ctx: &Context<Type>,
-    inductive_typedefs: &HashMap<String, TypeDef>,
-    typ: &Type,
-) -> Result<(), TypeErr> {
-    let MatchArm(pat, body) = match_arm;
-    let ctor_tag = pat[0].to_string();
-    let mut ctor_typ = inductive_typedef.ctor_types.get(&ctor_tag).unwrap();
+fn lookup_typedef_by_ctor_tag<'a>(ctor_tag: &Tag, inductive_typedefs: &'a HashMap<String, TypeDef>) -> Option<&'a TypeDef> {
+    for (_typename, inductive_typedef) in inductive_typedefs.iter() {
+        let ctor_tags: Vec<Tag> = inductive_typedef.ctor_types.keys().cloned().collect();
+        if ctor_tags.contains(ctor_tag) {
+            return Some(inductive_typedef);
+        }
+    }
+    None
+}
+
+/// Types which are built into the runtime rather than declared, such as `Str` and `IO`,
+/// along with the number of type parameters they take.
+fn primitive_type_arity(name: &str) -> Option<usize> {
+    match name {
+        "Str" => Some(0),
+        "IO" => Some(1),
+        _ => None,
+    }
+}
 
-    let pattern_names: Vec<String> = (&pat[1..]).iter().cloned().collect();
-    let mut pattern_types: Vec<Type> = Vec::new();
+/// Checks that a type declaration may be added alongside the existing typedefs.
+/// The type and its constructors must not clash with anything already declared,
+/// the argument types of each constructor must be well-formed, and (for inductive types)
+/// the type may only occur strictly positively in its constructors' arguments.
+pub fn check_type_decl(type_decl: &TypeDecl, inductive_typedefs: &HashMap<String, TypeDef>) -> Result<(), Box<TypeErr>> {
+    let TypeDecl(flavor, name, params, ctor_decls, span) = type_decl;
 
-    while let TypeNode::Arrow(dom, cod) = ctor_typ.as_ref() {
-        pattern_types.push(dom.clone());
-        ctor_typ = &cod;
+    if inductive_typedefs.contains_key(name) || primitive_type_arity(name).is_some() {
+        return Err(Box::new(TypeErr::TypeAlreadyDefined { span: Some(span.clone()), name: name.to_string() }));
     }
 
-    if pattern_names.len() != pattern_types.len() {
-        Err(format!("Pattern has the wrong number of variables: {:?} is more than {}", pattern_names, pattern_types.len()))
+    for (i, param) in params.iter().enumerate() {
+        if params[..i].contains(param) {
+            return Err(Box::new(TypeErr::DuplicateTypeParam { span: Some(span.clone()), name: name.to_string(), param: param.to_string() }));
+        }
+    }
+
+    let mut seen_tags: HashSet<Tag> = HashSet::new();
+    for CtorDecl(tag, arg_types, ctor_span) in ctor_decls {
+        if !seen_tags.insert(tag.to_string()) {
+            return Err(Box::new(TypeErr::DuplicateCtor { span: Some(ctor_span.clone()), name: name.to_string(), tag: tag.to_string() }));
+        }
+
+        if let Some(typedef) = lookup_typedef_by_ctor_tag(tag, inductive_typedefs) {
+            return Err(Box::new(TypeErr::CtorAlreadyDefined {
+                span: Some(ctor_span.clone()),
+                tag: tag.to_string(),
+                defined_by: typedef.name.to_string(),
+            }));
+        }
+
+        for arg_type in arg_types {
+            check_type_wellformed_with(arg_type, inductive_typedefs, Some((name, params.len())))?;
+
+            for type_var in arg_type.free_type_vars() {
+                if !params.contains(&type_var) {
+                    return Err(Box::new(TypeErr::UnboundTypeVariable { span: arg_type.span().cloned(), name: type_var, tag: tag.to_string() }));
+                }
+            }
+
+            if *flavor == Flavor::Inductive && occurs_negatively(name, arg_type) {
+                return Err(Box::new(TypeErr::NegativeOccurrence { span: arg_type.span().cloned(), name: name.to_string(), tag: tag.to_string() }));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn occurs_negatively(name: &str, typ: &Type) -> bool {
+    match typ.as_ref() {
+        TypeNode::Atom(_) | TypeNode::Var(_) | TypeNode::Meta(_) => false,
+        TypeNode::App(_head, args) => args.iter().any(|arg| occurs_negatively(name, arg)),
+        TypeNode::Arrow(dom, cod) => occurs(name, dom) || occurs_negatively(name, cod),
+        TypeNode::Forall(_name, typ) => occurs_negatively(name, typ),
+    }
+}
+
+fn occurs(name: &str, typ: &Type) -> bool {
+    match typ.as_ref() {
+        TypeNode::Atom(atom) => atom == name,
+        TypeNode::App(head, args) => head == name || args.iter().any(|arg| occurs(name, arg)),
+        TypeNode::Var(_) | TypeNode::Meta(_) => false,
+        TypeNode::Arrow(dom, cod) => occurs(name, dom) || occurs(name, cod),
+        TypeNode::Forall(_name, typ) => occurs(name, typ),
+    }
+}
+
+/// What the totality checker knows about a local variable.
+#[derive(Debug, Clone)]
+enum Size {
+    /// The variable is the i-th parameter of the definition being checked.
+    Param(usize),
+    /// The variable was bound by a pattern and is structurally smaller than each of these parameters.
+    Smaller(Vec<usize>),
+    /// The variable was bound by a pattern on coinductive data, and so it might be no smaller at all.
+    Observed,
+    Unknown,
+}
+
+///
+/// Checks that a definition is total: that it is either terminating or productive.
+///
+/// Definitions whose result is coinductive data are corecursive, and must be productive.
+/// Every other definition is recursive, and must terminate.
+///
+pub fn check_totality(definition: &Def, inductive_typedefs: &HashMap<String, TypeDef>) -> Result<(), Box<TypeErr>> {
+    let Def(_name, typ, _body, _span) = definition;
+
+    let mut result_typ = typ.clone();
+    loop {
+        match result_typ.clone().as_ref() {
+            TypeNode::Forall(_name, body) => result_typ = body.clone(),
+            TypeNode::Arrow(_dom, cod) => result_typ = cod.clone(),
+            _ => break,
+        }
+    }
+
+    let result_flavor = match result_typ.as_ref() {
+        TypeNode::Atom(name) | TypeNode::App(name, _) => inductive_typedefs.get(name).map(|typedef| &typedef.flavor),
+        _ => None,
+    };
+
+    if result_flavor == Some(&Flavor::Coinductive) {
+        check_productivity(definition, inductive_typedefs)
     } else {
-        let zipped: Vec<(String, Type)> = pattern_names.into_iter().zip(pattern_types).collect();
-        let extended_ctx = ctx.extend_many(&zipped);
-        check_type(&body, extended_ctx.clone(), inductive_typedefs, typ.clone())
+        check_termination(definition, inductive_typedefs)
     }
 }
 
-fn lookup_typedef_by_ctor_tag<'a>(ctor_tag: &Tag, inductive_typedefs: &'a HashMap<String, TypeDef>) -> Option<&'a TypeDef> {
-    for (_typename, inductive_typedef) in inductive_typedefs.iter() {
-        let ctor_tags: Vec<Tag> = inductive_typedef.ctor_types.keys().cloned().collect();
-        if ctor_tags.contains(&ctor_tag) {
-            return Some(inductive_typedef);
+///
+/// Checks that a definition only calls itself on structurally smaller arguments.
+///
+/// A definition passes if there is some parameter such that every recursive call passes,
+/// in that parameter's position, a variable bound by a match pattern on that parameter
+/// (or on something already smaller than it). Definitions which don't refer to themselves
+/// are always accepted.
+///
+/// Matching on coinductive data doesn't make anything smaller, since it might go on forever.
+///
+pub fn check_termination(definition: &Def, inductive_typedefs: &HashMap<String, TypeDef>) -> Result<(), Box<TypeErr>> {
+    let Def(name, _typ, body, _span) = definition;
+    let (params, body) = split_params(body);
+
+    let locals: Vec<(String, Size)> = params
+        .iter()
+        .enumerate()
+        .map(|(i, param)| (param.to_string(), Size::Param(i)))
+        .collect();
+
+    let mut calls: Vec<(Term, Vec<Size>)> = Vec::new();
+    collect_recursive_calls(name, body, &locals, inductive_typedefs, &mut calls);
+
+    let decreases_on = |i: usize, arg_sizes: &[Size]| -> bool {
+        match arg_sizes.get(i) {
+            Some(Size::Smaller(params)) => params.contains(&i),
+            _ => false,
         }
+    };
+
+    if calls.is_empty() || (0..params.len()).any(|i| calls.iter().all(|(_call, arg_sizes)| decreases_on(i, arg_sizes))) {
+        return Ok(());
+    }
+
+    // Blame the first call which isn't decreasing on any argument. If there is no such call,
+    // then the calls disagree about which argument decreases, so blame the first call which
+    // doesn't decrease on the same argument as the first call does.
+    let first_decreasing = calls
+        .first()
+        .and_then(|(_call, arg_sizes)| (0..params.len()).find(|i| decreases_on(*i, arg_sizes)));
+
+    let (offending_call, offending_arg_sizes) = match first_decreasing {
+        None => &calls[0],
+        Some(i) => {
+            match calls.iter().find(|(_call, arg_sizes)| !(0..params.len()).any(|j| decreases_on(j, arg_sizes))) {
+                Some(call) => call,
+                None => calls.iter().find(|(_call, arg_sizes)| !decreases_on(i, arg_sizes)).unwrap(),
+            }
+        },
+    };
+
+    Err(Box::new(TypeErr::NonTerminating {
+        span: offending_call.span().cloned(),
+        name: name.to_string(),
+        call: offending_call.clone(),
+        consumes_codata: offending_arg_sizes.iter().any(|size| matches!(size, Size::Observed)),
+    }))
+}
+
+///
+/// Checks that a corecursive definition is productive.
+///
+/// Every recursive call must be guarded: it must appear directly as an argument to a
+/// coinductive constructor, so that each step of the corecursion produces at least one
+/// constructor before recursing. The only things which may come between the constructor
+/// and the call are other constructor applications, the bodies of lets, and the arms of matches.
+///
+pub fn check_productivity(definition: &Def, inductive_typedefs: &HashMap<String, TypeDef>) -> Result<(), Box<TypeErr>> {
+    let Def(name, _typ, body, _span) = definition;
+    let (params, body) = split_params(body);
+
+    let locals: Vec<(String, Size)> = params.iter().map(|param| (param.to_string(), Size::Unknown)).collect();
+
+    match find_unguarded_call(name, body, false, &locals, inductive_typedefs) {
+        None => Ok(()),
+        Some(call) => Err(Box::new(TypeErr::NonProductive {
+            span: call.span().cloned(),
+            name: name.to_string(),
+            call,
+        })),
+    }
+}
+
+/// Separates the parameters of a definition from the rest of its body.
+fn split_params(body: &Term) -> (Vec<String>, &Term) {
+    let mut params: Vec<String> = Vec::new();
+    let mut body = body;
+    while let TermNode::Lam(x, lam_body) = body.as_ref() {
+        params.push(x.to_string());
+        body = lam_body;
+    }
+    (params, body)
+}
+
+/// Finds every reference to the definition `name` in `t`, together with the sizes of the arguments it is applied to.
+fn collect_recursive_calls(
+    name: &str,
+    t: &Term,
+    locals: &[(String, Size)],
+    inductive_typedefs: &HashMap<String, TypeDef>,
+    calls: &mut Vec<(Term, Vec<Size>)>,
+) {
+    match t.as_ref() {
+        TermNode::Var(v) => {
+            if is_recursive_reference(name, v, locals) {
+                calls.push((t.clone(), Vec::new()));
+            }
+        },
+        TermNode::Lam(x, body) => {
+            let mut new_locals = locals.to_vec();
+            new_locals.push((x.to_string(), Size::Unknown));
+            collect_recursive_calls(name, body, &new_locals, inductive_typedefs, calls);
+        },
+        TermNode::App(func, args) => {
+            match func.as_ref() {
+                TermNode::Var(v) if is_recursive_reference(name, v, locals) => {
+                    let arg_sizes = args.iter().map(|arg| size_of(arg, locals)).collect();
+                    calls.push((t.clone(), arg_sizes));
+                },
+                _ => collect_recursive_calls(name, func, locals, inductive_typedefs, calls),
+            }
+
+            for arg in args {
+                collect_recursive_calls(name, arg, locals, inductive_typedefs, calls);
+            }
+        },
+        TermNode::Let(x, v, body) => {
+            collect_recursive_calls(name, v, locals, inductive_typedefs, calls);
+
+            let mut new_locals = locals.to_vec();
+            new_locals.push((x.to_string(), size_of(v, locals)));
+            collect_recursive_calls(name, body, &new_locals, inductive_typedefs, calls);
+        },
+        TermNode::Match(discriminee, match_arms) => {
+            collect_recursive_calls(name, discriminee, locals, inductive_typedefs, calls);
+
+            let pattern_size = if matches_on_codata(match_arms, inductive_typedefs) {
+                Size::Observed
+            } else {
+                match size_of(discriminee, locals) {
+                    Size::Param(i) => Size::Smaller(vec![i]),
+                    Size::Smaller(params) => Size::Smaller(params),
+                    Size::Observed | Size::Unknown => Size::Unknown,
+                }
+            };
+
+            for MatchArm(pat, body, _span) in match_arms {
+                let mut new_locals = locals.to_vec();
+                for x in pat[1..].iter() {
+                    new_locals.push((x.to_string(), pattern_size.clone()));
+                }
+                collect_recursive_calls(name, body, &new_locals, inductive_typedefs, calls);
+            }
+        },
+        TermNode::Hole(_hole_info) => (),
+        TermNode::As(term, _typ) => collect_recursive_calls(name, term, locals, inductive_typedefs, calls),
+        TermNode::StrLit(_contents) => (),
+        TermNode::NatLit(_n) => (),
+    }
+}
+
+/// Finds the first reference to the definition `name` in `t` which isn't guarded by a coinductive constructor.
+fn find_unguarded_call(
+    name: &str,
+    t: &Term,
+    guarded: bool,
+    locals: &[(String, Size)],
+    inductive_typedefs: &HashMap<String, TypeDef>,
+) -> Option<Term> {
+    match t.as_ref() {
+        TermNode::Var(v) => {
+            if !guarded && is_recursive_reference(name, v, locals) {
+                Some(t.clone())
+            } else {
+                None
+            }
+        },
+        TermNode::Lam(x, body) => {
+            let mut new_locals = locals.to_vec();
+            new_locals.push((x.to_string(), Size::Unknown));
+            find_unguarded_call(name, body, false, &new_locals, inductive_typedefs)
+        },
+        TermNode::App(func, args) => {
+            let (func_unguarded_call, args_guarded) = match func.as_ref() {
+                TermNode::Var(v) if is_recursive_reference(name, v, locals) => {
+                    (if guarded { None } else { Some(t.clone()) }, false)
+                },
+                TermNode::Var(v) if is_coinductive_ctor(v, locals, inductive_typedefs) => (None, true),
+                _ => (find_unguarded_call(name, func, false, locals, inductive_typedefs), false),
+            };
+
+            func_unguarded_call.or_else(|| {
+                args.iter().find_map(|arg| find_unguarded_call(name, arg, args_guarded, locals, inductive_typedefs))
+            })
+        },
+        TermNode::Let(x, v, body) => {
+            let mut new_locals = locals.to_vec();
+            new_locals.push((x.to_string(), Size::Unknown));
+
+            find_unguarded_call(name, v, false, locals, inductive_typedefs)
+                .or_else(|| find_unguarded_call(name, body, guarded, &new_locals, inductive_typedefs))
+        },
+        TermNode::Match(discriminee, match_arms) => {
+            find_unguarded_call(name, discriminee, false, locals, inductive_typedefs).or_else(|| {
+                match_arms.iter().find_map(|MatchArm(pat, body, _span)| {
+                    let mut new_locals = locals.to_vec();
+                    for x in pat[1..].iter() {
+                        new_locals.push((x.to_string(), Size::Unknown));
+                    }
+                    find_unguarded_call(name, body, guarded, &new_locals, inductive_typedefs)
+                })
+            })
+        },
+        TermNode::Hole(_hole_info) => None,
+        TermNode::As(term, _typ) => find_unguarded_call(name, term, guarded, locals, inductive_typedefs),
+        TermNode::StrLit(_contents) => None,
+        TermNode::NatLit(_n) => None,
+    }
+}
+
+fn matches_on_codata(match_arms: &[MatchArm], inductive_typedefs: &HashMap<String, TypeDef>) -> bool {
+    match match_arms.first() {
+        Some(MatchArm(pat, _body, _span)) => {
+            match lookup_typedef_by_ctor_tag(&pat[0], inductive_typedefs) {
+                Some(typedef) => typedef.flavor == Flavor::Coinductive,
+                None => false,
+            }
+        },
+        None => false,
+    }
+}
+
+fn is_coinductive_ctor(v: &Variable, locals: &[(String, Size)], inductive_typedefs: &HashMap<String, TypeDef>) -> bool {
+    if !matches!(lookup_local(v, locals), Err(0)) {
+        return false;
+    }
+
+    match lookup_typedef_by_ctor_tag(&v.name, inductive_typedefs) {
+        Some(typedef) => typedef.flavor == Flavor::Coinductive,
+        None => false,
+    }
+}
+
+/// Looks up a variable amongst the locals, taking shadowing into account.
+/// Returns the number of layers left over if the variable isn't a local.
+fn lookup_local<'a>(v: &Variable, locals: &'a [(String, Size)]) -> Result<&'a Size, usize> {
+    let mut layers_left = v.layer;
+    for (x, size) in locals.iter().rev() {
+        if *x == v.name {
+            if layers_left == 0 {
+                return Ok(size);
+            } else {
+                layers_left -= 1;
+            }
+        }
+    }
+    Err(layers_left)
+}
+
+fn is_recursive_reference(name: &str, v: &Variable, locals: &[(String, Size)]) -> bool {
+    v.name == name && matches!(lookup_local(v, locals), Err(0))
+}
+
+fn size_of(t: &Term, locals: &[(String, Size)]) -> Size {
+    match t.as_ref() {
+        TermNode::Var(v) => match lookup_local(v, locals) {
+            Ok(size) => size.clone(),
+            Err(_layers_left) => Size::Unknown,
+        },
+        TermNode::As(term, _typ) => size_of(term, locals),
+        _ => Size::Unknown,
     }
-    None
 }
@@ -0,0 +1,35 @@
+use std::fs;
+use std::process::Command;
+
+/// Runs an example with the given backend, and returns what it printed.
+fn run_example(backend: &str, path: &str) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_quail"))
+        .arg(format!("--backend={}", backend))
+        .arg(path)
+        .output()
+        .expect("Could not run quail");
+    assert!(output.status.success(), "{} failed on the {} backend: {}", path, backend, String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn examples_match_across_backends() {
+    let paths = fs::read_dir("examples").expect("Could not open examples/ directory");
+    for path in paths {
+        let path = path.expect("Couldn't open file").path();
+        let path = path.to_str().unwrap();
+        println!("{}", path);
+        assert_eq!(run_example("stg", path), run_example("tree", path), "The backends disagree on {}", path);
+    }
+}
+
+#[test]
+fn println_writes_strings_as_they_are() {
+    let path = std::env::temp_dir().join(format!("quail-println-{}.ql", std::process::id()));
+    fs::write(&path, r#"def main : IO Top = then (println "line\none") (println "say \"hi\"")"#).unwrap();
+    let path = path.to_str().unwrap();
+    for backend in ["tree", "stg"].iter() {
+        assert_eq!(run_example(backend, path), "line\none\nsay \"hi\"\n", "on the {} backend", backend);
+    }
+    fs::remove_file(path).unwrap();
+}